@@ -1,13 +1,113 @@
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::io::{self, Read, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::collections::HashMap;
-use libc::{epoll_create1, epoll_ctl, epoll_wait, epoll_event, EPOLLIN, EPOLLERR, EPOLLHUP, EPOLL_CTL_ADD, EPOLL_CTL_DEL};
+use std::collections::{HashMap, VecDeque};
+use libc::{epoll_create1, epoll_ctl, epoll_wait, epoll_event, EPOLLIN, EPOLLOUT, EPOLLERR, EPOLLHUP, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD};
 // Import Serde
 use serde_derive::Deserialize;
 use std::fs;
 use std::process::{Command, Stdio};
 use std::env;
+use std::cell::{RefCell, RefMut};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Header fields of a request or response. Names compare case-insensitively
+/// but keep the casing they were added with, fields stay in insertion order,
+/// and a name may appear several times (e.g. Set-Cookie).
+#[derive(Debug, Clone, Default)]
+struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    fn new() -> Self {
+        HeaderMap { entries: Vec::new() }
+    }
+
+    /// First value of a field
+    fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of a field, in the order they were added
+    fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of a list-based field combined into one, as RFC 9110
+    /// section 5.3 allows
+    fn get_joined(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.get_all(name).collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut String> {
+        self.entries
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set a field, replacing any values it already had. The field keeps the
+    /// position of its first occurrence.
+    fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let value = value.into();
+        match self.entries.iter().position(|(key, _)| key.eq_ignore_ascii_case(&name)) {
+            Some(first) => {
+                // Later duplicates go; entries before `first` keep their indexes
+                let mut index = 0;
+                self.entries.retain(|(key, _)| {
+                    index += 1;
+                    index - 1 == first || !key.eq_ignore_ascii_case(&name)
+                });
+                self.entries[first] = (name, value);
+            }
+            None => self.entries.push((name, value)),
+        }
+    }
+
+    /// Add a value without touching existing ones
+    fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Remove every value of a field
+    fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&str, &str) -> bool) {
+        self.entries.retain(|(key, value)| keep(key, value));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
 
 // Form data structures
 #[derive(Debug, Clone)]
@@ -25,7 +125,7 @@ struct HttpRequest {
     #[allow(dead_code)]
     query_string: Option<String>,
     version: String,
-    headers: HashMap<String, String>,
+    headers: HeaderMap,
     #[allow(dead_code)]
     cookies: HashMap<String, String>,
     #[allow(dead_code)]
@@ -36,91 +136,240 @@ struct HttpRequest {
     form_files: HashMap<String, FormFile>,
     #[allow(dead_code)]
     body: Vec<u8>,
+    /// Trailer fields sent after a chunked body
+    trailers: HeaderMap,
+    /// Client address, filled in by the server once the request is parsed
+    remote_addr: Option<SocketAddr>,
+    /// Address the request was received on
+    local_addr: Option<SocketAddr>,
+    /// Keys for signed and private cookies, filled in by the server
+    cookie_keys: Option<Rc<CookieKeys>>,
+    /// Server-side session, loaded from the session cookie before routing
+    session: Rc<RefCell<Session>>,
+    /// Parameters captured by the matched route pattern
+    params: HashMap<String, String>,
 }
 
 #[derive(Debug)]
 struct HttpResponse {
     status: u16,
     status_text: String,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
-    is_chunked: bool,
+    headers: HeaderMap,
+    body: Body,
+}
+
+/// Response payload
+#[derive(Debug)]
+enum Body {
+    Bytes(Vec<u8>),
+    /// `len` bytes of `file` starting at `offset`, sent with sendfile(2)
+    /// straight from the page cache instead of being read into memory
+    File {
+        file: Rc<fs::File>,
+        offset: u64,
+        len: u64,
+    },
+    /// Bodies sent back to back, e.g. the parts of a multipart/byteranges reply
+    Parts(Vec<Body>),
+    /// Data produced on demand, of unknown length
+    Stream(BodyStream),
+}
+
+/// Producer of body data, pulled by the event loop one chunk at a time as
+/// the socket becomes writable
+struct BodyStream {
+    chunks: Box<dyn Iterator<Item = Vec<u8>>>,
+    /// Frame each chunk with chunked transfer coding. Off when the body is
+    /// instead delimited by closing the connection (HTTP/1.0 clients).
+    chunked: bool,
+}
+
+impl std::fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BodyStream").field("chunked", &self.chunked).finish_non_exhaustive()
+    }
+}
+
+impl Body {
+    /// Length in bytes, or None for a stream whose length is not known up front
+    fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Parts(parts) => parts.iter().map(Body::len).sum(),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// The inclusive byte range `first..=last` of this body, without copying file data
+    fn slice(&self, first: u64, last: u64) -> Option<Body> {
+        match self {
+            Body::Bytes(bytes) => Some(Body::Bytes(bytes[first as usize..=last as usize].to_vec())),
+            Body::File { file, offset, .. } => Some(Body::File {
+                file: Rc::clone(file),
+                offset: offset + first,
+                len: last - first + 1,
+            }),
+            Body::Parts(_) | Body::Stream(_) => None,
+        }
+    }
+}
+
+impl HttpRequest {
+    /// Case-insensitive header lookup
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// A parameter captured by the route, such as `id` for "/users/:id"
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    /// The session this request belongs to. Changes are saved, and a
+    /// session cookie issued if needed, once the handler returns.
+    fn session(&self) -> RefMut<'_, Session> {
+        self.session.borrow_mut()
+    }
+
+    /// The request's cookies, including signed and private ones
+    fn cookie_jar(&self) -> CookieJar<'_> {
+        CookieJar {
+            cookies: &self.cookies,
+            keys: self.cookie_keys.as_deref(),
+        }
+    }
+
+    /// Whether the client asked for the connection to stay open after this
+    /// request. HTTP/1.1 is persistent unless "Connection: close" is sent;
+    /// HTTP/1.0 only when "Connection: keep-alive" is sent.
+    fn wants_keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers
+                .get_all("Connection")
+                .any(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        };
+        match self.version.as_str() {
+            "HTTP/1.1" => !has_token("close"),
+            _ => has_token("keep-alive"),
+        }
+    }
 }
 
 impl HttpResponse {
     fn new(status: u16, status_text: &str, body: &str) -> Self {
-        let mut headers = HashMap::new();
-        headers.insert("Content-Type".to_string(), "text/html".to_string());
-        headers.insert("Content-Length".to_string(), body.len().to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "text/html");
+        headers.insert("Content-Length", body.len().to_string());
         
         HttpResponse {
             status,
             status_text: status_text.to_string(),
             headers,
-            body: body.as_bytes().to_vec(),
-            is_chunked: false,
+            body: Body::Bytes(body.as_bytes().to_vec()),
         }
     }
     
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status, self.status_text);
-        for (key, value) in &self.headers {
-            response.push_str(&format!("{}: {}\r\n", key, value));
+    /// Drop chunked framing for clients that do not understand it (HTTP/1.0).
+    /// A streamed body then ends when the connection closes, so this returns
+    /// true when the connection must not be kept alive.
+    fn unchunk(&mut self) -> bool {
+        let Body::Stream(stream) = &mut self.body else {
+            return false;
+        };
+        stream.chunked = false;
+        self.headers.remove("Transfer-Encoding");
+        true
+    }
+    
+    /// Serialize the response into the pieces written to the socket in
+    /// order. In-memory data is coalesced; file regions and streams are
+    /// left for the write loop to pull from.
+    fn into_segments(self) -> Vec<Body> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.status_text);
+        for (key, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", key, value));
         }
-        response.push_str("\r\n");
+        head.push_str("\r\n");
         
-        let mut bytes = response.into_bytes();
+        let mut segments = vec![Body::Bytes(head.into_bytes())];
+        Self::push_segment(&mut segments, self.body);
         
-        if self.is_chunked {
-            // Encode body in chunks
-            let chunk_size = 1024;
-            for chunk in self.body.chunks(chunk_size) {
-                let chunk_header = format!("{:x}\r\n", chunk.len());
-                bytes.extend_from_slice(chunk_header.as_bytes());
-                bytes.extend_from_slice(chunk);
-                bytes.extend_from_slice(b"\r\n");
-            }
-            // Final chunk
-            bytes.extend_from_slice(b"0\r\n\r\n");
-        } else {
-            bytes.extend_from_slice(&self.body);
+        segments
+    }
+
+    /// Append a body to a segment list, flattening parts and merging
+    /// adjacent in-memory pieces so they go out in one write
+    fn push_segment(segments: &mut Vec<Body>, body: Body) {
+        match body {
+            Body::Parts(parts) => {
+                for part in parts {
+                    Self::push_segment(segments, part);
+                }
+            }
+            Body::Bytes(bytes) => match segments.last_mut() {
+                Some(Body::Bytes(previous)) => previous.extend_from_slice(&bytes),
+                _ => segments.push(Body::Bytes(bytes)),
+            },
+            Body::File { len: 0, .. } => {}
+            other => segments.push(other),
         }
-        
-        bytes
     }
 }
 
+/// A request resolved to a CGI script
+struct CgiScript {
+    /// Script location on disk
+    path: String,
+    /// Program the script is run with; None executes the script itself
+    interpreter: Option<String>,
+    /// URL path of the script (SCRIPT_NAME)
+    script_name: String,
+    /// URL path following the script name (PATH_INFO)
+    path_info: String,
+}
+
 /// CGI Executor - Handles Common Gateway Interface script execution
 struct CGIExecutor;
 
 impl CGIExecutor {
     /// Execute a CGI script and return the HTTP response
     fn execute(
-        script_path: &str,
+        script: &CgiScript,
         request: &HttpRequest,
-        client_ip: &str,
     ) -> io::Result<HttpResponse> {
+        let script_path = script.path.as_str();
         // Verify script exists
-        if !std::path::Path::new(script_path).exists() {
-            return Ok(HttpResponse::new(404, "Not Found", "CGI script not found"));
+        if !std::path::Path::new(script_path).is_file() {
+            return Ok(HttpResponse::new(404, "Not Found", &ErrorPages::not_found()));
         }
 
-        // Make script executable
-        std::process::Command::new("chmod")
-            .arg("+x")
-            .arg(script_path)
-            .output()
-            .ok();
+        let mut command = match &script.interpreter {
+            Some(interpreter) => {
+                let mut command = Command::new(interpreter);
+                command.arg(script_path);
+                command
+            }
+            None => {
+                // Make script executable
+                std::process::Command::new("chmod")
+                    .arg("+x")
+                    .arg(script_path)
+                    .output()
+                    .ok();
+                Command::new(script_path)
+            }
+        };
 
         // Build environment variables for CGI
-        let env_vars = Self::build_cgi_env(request, client_ip);
+        let env_vars = Self::build_cgi_env(script, request);
 
         // Determine request method for stdin handling
         let use_stdin = request.method == "POST" || request.method == "PUT";
         let stdin_data: &[u8] = if use_stdin { &request.body } else { &[] };
 
         // Execute the script
-        let mut child = Command::new(script_path)
+        let mut child = command
             .env_clear()
             .envs(&env_vars)
             .stdin(Stdio::piped())
@@ -144,45 +393,62 @@ impl CGIExecutor {
         }
 
         // Parse CGI response from bytes
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        Self::parse_cgi_response(&output_str)
+        Self::parse_cgi_response(&output.stdout)
     }
 
     /// Build CGI environment variables based on HTTP request
-    fn build_cgi_env(request: &HttpRequest, client_ip: &str) -> HashMap<String, String> {
+    fn build_cgi_env(script: &CgiScript, request: &HttpRequest) -> HashMap<String, String> {
         let mut env = HashMap::new();
 
         // CGI Standard Variables
         env.insert("REQUEST_METHOD".to_string(), request.method.clone());
-        env.insert("SCRIPT_NAME".to_string(), request.path.clone());
-        env.insert("PATH_INFO".to_string(), request.path.clone());
+        env.insert("SCRIPT_NAME".to_string(), script.script_name.clone());
+        env.insert("SCRIPT_FILENAME".to_string(), script.path.clone());
+        env.insert("PATH_INFO".to_string(), script.path_info.clone());
         env.insert("QUERY_STRING".to_string(), 
                    request.query_string.clone().unwrap_or_default());
         env.insert("CONTENT_LENGTH".to_string(), 
                    request.body.len().to_string());
         
         // HTTP Headers as environment variables
-        if let Some(content_type) = request.headers.get("Content-Type") {
-            env.insert("CONTENT_TYPE".to_string(), content_type.clone());
+        if let Some(content_type) = request.header("Content-Type") {
+            env.insert("CONTENT_TYPE".to_string(), content_type.to_string());
         } else {
             env.insert("CONTENT_TYPE".to_string(), "text/plain".to_string());
         }
 
         // Server information
-        env.insert("SERVER_NAME".to_string(), "localhost".to_string());
-        env.insert("SERVER_PORT".to_string(), "8000".to_string());
+        let server_name = request
+            .header("Host")
+            .map(|host| host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host).to_string())
+            .or_else(|| request.local_addr.map(|addr| addr.ip().to_string()))
+            .unwrap_or_else(|| "localhost".to_string());
+        let server_port = request.local_addr.map(|addr| addr.port()).unwrap_or(80);
+        env.insert("SERVER_NAME".to_string(), server_name);
+        env.insert("SERVER_PORT".to_string(), server_port.to_string());
         env.insert("SERVER_PROTOCOL".to_string(), request.version.clone());
         env.insert("SERVER_SOFTWARE".to_string(), "localhost-http-server/1.0".to_string());
 
         // Client information
-        env.insert("REMOTE_ADDR".to_string(), client_ip.to_string());
-        env.insert("REMOTE_HOST".to_string(), client_ip.to_string());
+        let client_ip = request
+            .remote_addr
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
+        env.insert("REMOTE_ADDR".to_string(), client_ip.clone());
+        env.insert("REMOTE_HOST".to_string(), client_ip);
 
-        // HTTP Request Headers (converted to CGI format)
-        for (key, value) in &request.headers {
+        // HTTP Request Headers (converted to CGI format); repeated fields
+        // are combined into one variable, cookies with "; " as the client would
+        for (key, value) in request.headers.iter() {
             let cgi_key = format!("HTTP_{}", 
                 key.to_uppercase().replace("-", "_"));
-            env.insert(cgi_key, value.clone());
+            let separator = if cgi_key == "HTTP_COOKIE" { "; " } else { ", " };
+            env.entry(cgi_key)
+                .and_modify(|existing: &mut String| {
+                    existing.push_str(separator);
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
         }
 
         // Additional CGI variables
@@ -199,26 +465,23 @@ impl CGIExecutor {
     }
 
     /// Parse CGI response (headers + body)
-    fn parse_cgi_response(output: &str) -> io::Result<HttpResponse> {
-        // Split headers and body by double newline
-        let parts: Vec<&str> = output.splitn(2, "\r\n\r\n").collect();
-        
-        let (headers_str, body_str) = if parts.len() == 2 {
-            (parts[0], parts[1])
-        } else {
+    fn parse_cgi_response(output: &[u8]) -> io::Result<HttpResponse> {
+        // Split headers and body by double newline; only the header block is
+        // treated as text so scripts can emit binary bodies
+        let (headers_bytes, body) = if let Some(pos) = HttpParser::find_bytes(output, b"\r\n\r\n", 0) {
+            (&output[..pos], &output[pos + 4..])
+        } else if let Some(pos) = HttpParser::find_bytes(output, b"\n\n", 0) {
             // Try with just \n\n
-            let parts: Vec<&str> = output.splitn(2, "\n\n").collect();
-            if parts.len() == 2 {
-                (parts[0], parts[1])
-            } else {
-                // No headers, entire output is body
-                ("Status: 200 OK", output)
-            }
+            (&output[..pos], &output[pos + 2..])
+        } else {
+            // No headers, entire output is body
+            (&b"Status: 200 OK"[..], output)
         };
+        let headers_str = String::from_utf8_lossy(headers_bytes);
 
         let mut status_code = 200u16;
         let mut status_text = "OK".to_string();
-        let mut response_headers = HashMap::new();
+        let mut response_headers = HeaderMap::new();
 
         // Parse CGI headers
         for line in headers_str.lines() {
@@ -229,7 +492,7 @@ impl CGIExecutor {
             if line.starts_with("Status:") {
                 let status_line = line.trim_start_matches("Status:").trim();
                 let parts: Vec<&str> = status_line.splitn(2, ' ').collect();
-                if parts.len() >= 1 {
+                if !parts.is_empty() {
                     if let Ok(code) = parts[0].parse::<u16>() {
                         status_code = code;
                         if parts.len() > 1 {
@@ -240,21 +503,23 @@ impl CGIExecutor {
             } else if let Some(colon_pos) = line.find(':') {
                 let key = line[..colon_pos].trim();
                 let value = line[colon_pos + 1..].trim();
-                response_headers.insert(key.to_string(), value.to_string());
+                response_headers.append(key, value);
             }
         }
 
         // If no Content-Type was set, default to text/html
-        if !response_headers.contains_key("Content-Type") {
-            response_headers.insert("Content-Type".to_string(), "text/html".to_string());
+        if !response_headers.contains("Content-Type") {
+            response_headers.insert("Content-Type", "text/html");
         }
 
+        // Frame the body by its exact byte length
+        response_headers.insert("Content-Length", body.len().to_string());
+
         Ok(HttpResponse {
             status: status_code,
             status_text,
             headers: response_headers,
-            body: body_str.as_bytes().to_vec(),
-            is_chunked: false,
+            body: Body::Bytes(body.to_vec()),
         })
     }
 }
@@ -263,10 +528,8 @@ impl CGIExecutor {
 struct ResponseBuilder {
     status: u16,
     status_text: String,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
-    cookies: Vec<(String, String)>, // (name, value) pairs
-    is_chunked: bool,
+    headers: HeaderMap,
+    body: Body,
 }
 
 impl ResponseBuilder {
@@ -275,10 +538,8 @@ impl ResponseBuilder {
         ResponseBuilder {
             status: 200,
             status_text: "OK".to_string(),
-            headers: HashMap::new(),
-            body: Vec::new(),
-            cookies: Vec::new(),
-            is_chunked: false,
+            headers: HeaderMap::new(),
+            body: Body::Bytes(Vec::new()),
         }
     }
     
@@ -291,73 +552,116 @@ impl ResponseBuilder {
     
     /// Add a response header
     fn header(mut self, key: &str, value: &str) -> Self {
-        self.headers.insert(key.to_string(), value.to_string());
+        self.headers.insert(key, value);
         self
     }
     
     /// Set Content-Type header
     fn content_type(mut self, content_type: &str) -> Self {
-        self.headers.insert("Content-Type".to_string(), content_type.to_string());
+        self.headers.insert("Content-Type", content_type);
         self
     }
     
     /// Set the response body as string
     fn body_text(mut self, body: &str) -> Self {
-        self.body = body.as_bytes().to_vec();
+        self.body = Body::Bytes(body.as_bytes().to_vec());
         self
     }
     
     /// Set the response body as bytes
     #[allow(dead_code)]
     fn body_bytes(mut self, body: Vec<u8>) -> Self {
-        self.body = body;
+        self.body = Body::Bytes(body);
         self
     }
     
     /// Add a Set-Cookie header
     #[allow(dead_code)]
-    fn cookie(mut self, name: &str, value: &str) -> Self {
-        self.cookies.push((name.to_string(), value.to_string()));
-        self
+    fn cookie(self, name: &str, value: &str) -> Self {
+        self.set_cookie(&Cookie::new(name, value))
     }
     
     /// Add a Set-Cookie with additional options
-    fn cookie_with_options(mut self, name: &str, value: &str, max_age: Option<u32>, path: &str, http_only: bool) -> Self {
-        let mut cookie_str = format!("{}={}", name, value);
+    #[allow(dead_code)]
+    fn cookie_with_options(self, name: &str, value: &str, max_age: Option<u32>, path: &str, http_only: bool) -> Self {
+        let mut cookie = Cookie::new(name, value).path(path).http_only(http_only);
         if let Some(age) = max_age {
-            cookie_str.push_str(&format!("; Max-Age={}", age));
+            cookie = cookie.max_age(age as i64);
         }
-        cookie_str.push_str(&format!("; Path={}", path));
-        if http_only {
-            cookie_str.push_str("; HttpOnly");
-        }
-        self.headers.insert(
-            "Set-Cookie".to_string(),
-            cookie_str,
-        );
+        self.set_cookie(&cookie)
+    }
+    
+    /// Add a Set-Cookie header; each cookie gets its own header line
+    fn set_cookie(mut self, cookie: &Cookie) -> Self {
+        self.headers.append("Set-Cookie", cookie.to_header_value());
         self
     }
     
-    /// Enable chunked transfer encoding
-    fn chunked(mut self, enable: bool) -> Self {
-        self.is_chunked = enable;
-        if enable {
-            self.headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
-            // Remove Content-Length for chunked encoding
-            self.headers.remove("Content-Length");
-        }
+    /// Tell the client to drop a cookie. `path` (and the domain, if one was
+    /// used) must match the ones the cookie was set with.
+    #[allow(dead_code)]
+    fn delete_cookie(self, name: &str, path: &str) -> Self {
+        self.set_cookie(&Cookie::new(name, "").path(path).expired())
+    }
+    
+    /// Stream the body from an iterator of chunks with chunked transfer
+    /// encoding. Chunks are produced only as the client is ready for them.
+    fn stream(mut self, chunks: impl Iterator<Item = Vec<u8>> + 'static) -> Self {
+        self.body = Body::Stream(BodyStream {
+            chunks: Box::new(chunks),
+            chunked: true,
+        });
+        self.headers.insert("Transfer-Encoding", "chunked");
+        self.headers.remove("Content-Length");
         self
     }
     
-    /// Serve a static file
+    /// Serve a static file. The file is opened here but its contents are
+    /// only read by the kernel as the socket drains.
     fn file(mut self, path: &str) -> Result<Self, std::io::Error> {
-        let file_data = std::fs::read(path)?;
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "not a regular file"));
+        }
         let content_type = Self::get_content_type(path);
         
-        self.body = file_data;
-        self.headers.insert("Content-Type".to_string(), content_type);
+        self.body = Body::File {
+            file: Rc::new(file),
+            offset: 0,
+            len: metadata.len(),
+        };
+        self.headers.insert("Content-Type", content_type);
+        self.headers.insert("Accept-Ranges", "bytes");
+        self.headers.insert("ETag", Preconditions::file_etag(&metadata));
+        if let Ok(modified) = metadata.modified() {
+            self = self.last_modified(modified);
+        }
         Ok(self)
     }
+
+    /// Set a strong ETag; `tag` is the opaque value without quotes
+    #[allow(dead_code)]
+    fn etag(mut self, tag: &str) -> Self {
+        self.headers.insert("ETag", format!("\"{}\"", tag));
+        self
+    }
+
+    /// Set a strong ETag derived from the in-memory body set so far, so
+    /// dynamic handlers get 304 and 412 handling like static files
+    fn etag_from_body(mut self) -> Self {
+        if let Body::Bytes(body) = &self.body {
+            let etag = Preconditions::body_etag(body);
+            self.headers.insert("ETag", etag);
+        }
+        self
+    }
+
+    /// Set the Last-Modified header
+    fn last_modified(mut self, time: SystemTime) -> Self {
+        self.headers.insert("Last-Modified", format_http_date(time));
+        self
+    }
     
     /// Get content type based on file extension
     fn get_content_type(path: &str) -> String {
@@ -395,17 +699,12 @@ impl ResponseBuilder {
     
     /// Build the final HttpResponse
     fn build(mut self) -> HttpResponse {
-        // Set Content-Length if not chunked and not already set
-        if !self.is_chunked && !self.headers.contains_key("Content-Length") {
-            self.headers.insert("Content-Length".to_string(), self.body.len().to_string());
-        }
-        
-        // Add Set-Cookie headers for cookies added via cookie()
-        for (name, value) in &self.cookies {
-            self.headers.insert(
-                "Set-Cookie".to_string(),
-                format!("{}={}", name, value),
-            );
+        // Set Content-Length if the length is known and not already set;
+        // a 204 never carries one (RFC 9110 section 8.6)
+        if let Some(len) = self.body.len().filter(|_| self.status != 204) {
+            if !self.headers.contains("Content-Length") {
+                self.headers.insert("Content-Length", len.to_string());
+            }
         }
         
         HttpResponse {
@@ -413,1478 +712,8065 @@ impl ResponseBuilder {
             status_text: self.status_text,
             headers: self.headers,
             body: self.body,
-            is_chunked: self.is_chunked,
         }
     }
 }
 
-struct HttpParser;
+/// SameSite cookie attribute
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+enum SameSite {
+    Strict,
+    Lax,
+    /// Sent on cross-site requests too; browsers require Secure with it
+    None,
+}
 
-impl HttpParser {
-    fn parse(data: &[u8]) -> Option<HttpRequest> {
-        let request_str = String::from_utf8_lossy(data);
-        let lines: Vec<&str> = request_str.lines().collect();
-        
-        if lines.is_empty() {
-            return None;
+/// A cookie to send in a Set-Cookie header (RFC 6265)
+#[derive(Debug, Clone)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: Option<String>,
+    path: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<i64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    /// CHIPS: store the cookie per top-level site; implies Secure
+    partitioned: bool,
+}
+
+#[allow(dead_code)]
+impl Cookie {
+    /// A session cookie; `name` must be a token, `value` may be any text
+    /// and is percent-encoded where RFC 6265 does not allow it as is
+    fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: None,
+            path: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+            partitioned: false,
         }
-        
-        // Parse request line: "GET /path?query=value HTTP/1.1"
-        let request_line_parts: Vec<&str> = lines[0].split_whitespace().collect();
-        if request_line_parts.len() < 3 {
-            return None;
+    }
+
+    fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.trim_start_matches('.').to_string());
+        self
+    }
+
+    fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    fn expires(mut self, time: SystemTime) -> Self {
+        self.expires = Some(time);
+        self
+    }
+
+    /// Lifetime in seconds; zero or negative deletes the cookie
+    fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    /// Turn this into a cookie that makes the client discard any stored
+    /// cookie with the same name, domain and path
+    fn expired(mut self) -> Self {
+        self.value.clear();
+        self.max_age = Some(0);
+        self.expires = Some(UNIX_EPOCH);
+        self
+    }
+
+    /// Serialize as a Set-Cookie field value
+    fn to_header_value(&self) -> String {
+        let mut header = format!("{}={}", self.name, Self::encode_value(&self.value));
+        if let Some(domain) = &self.domain {
+            header.push_str(&format!("; Domain={}", Self::attribute_value(domain)));
         }
-        
-        let method = request_line_parts[0].to_string();
-        let full_path = request_line_parts[1];
-        let version = request_line_parts[2].to_string();
-        
-        // Split path and query string
-        let (path, query_string) = if let Some(pos) = full_path.find('?') {
-            (
-                full_path[..pos].to_string(),
-                Some(full_path[pos + 1..].to_string()),
-            )
-        } else {
-            (full_path.to_string(), None)
-        };
-        
-        // Parse headers
-        let mut headers = HashMap::new();
-        let mut cookies = HashMap::new();
-        let mut body_start = 0;
-        let mut is_chunked = false;
-        let mut content_type = String::new();
-        
-        for (i, line) in lines.iter().enumerate().skip(1) {
-            if line.is_empty() {
-                body_start = i + 1;
-                break;
-            }
-            
-            if let Some(colon_pos) = line.find(':') {
-                let key = line[..colon_pos].trim().to_string();
-                let value = line[colon_pos + 1..].trim().to_string();
-                
-                // Special handling for Cookie header
-                if key.to_lowercase() == "cookie" {
-                    Self::parse_cookies(&value, &mut cookies);
-                }
-                
-                // Check for chunked encoding
-                if key.to_lowercase() == "transfer-encoding" {
-                    is_chunked = value.to_lowercase().contains("chunked");
-                }
-                
-                // Store content type for multipart parsing
-                if key.to_lowercase() == "content-type" {
-                    content_type = value.clone();
-                }
-                
-                headers.insert(key, value);
-            }
+        if let Some(path) = &self.path {
+            header.push_str(&format!("; Path={}", Self::attribute_value(path)));
         }
-        
-        // Parse query parameters
-        let query_params = if let Some(ref qs) = query_string {
-            Self::parse_query_string(qs)
-        } else {
-            HashMap::new()
-        };
-        
-        // Parse body
-        let mut body = if body_start < lines.len() {
-            lines[body_start..].join("\n").into_bytes()
-        } else {
-            Vec::new()
-        };
-        
-        // Handle chunked encoding
-        if is_chunked {
-            body = Self::decode_chunked(&body);
+        if let Some(expires) = self.expires {
+            header.push_str(&format!("; Expires={}", format_http_date(expires)));
         }
-        
-        // Parse form data (multipart or urlencoded)
-        let (form_fields, form_files) = Self::parse_form_data(&content_type, &body);
-        
-        Some(HttpRequest {
-            method,
-            path,
-            query_string,
-            version,
-            headers,
-            cookies,
-            query_params,
-            form_fields,
-            form_files,
-            body,
-        })
-    }
-    
-    fn parse_cookies(cookie_header: &str, cookies: &mut HashMap<String, String>) {
-        for cookie in cookie_header.split(';') {
-            let cookie = cookie.trim();
-            if let Some(pos) = cookie.find('=') {
-                let name = cookie[..pos].trim().to_string();
-                let value = cookie[pos + 1..].trim().to_string();
-                cookies.insert(name, value);
-            }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age.max(0)));
         }
+        // SameSite=None and Partitioned cookies are rejected without Secure
+        if self.secure || self.partitioned || self.same_site == Some(SameSite::None) {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => header.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => header.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => header.push_str("; SameSite=None"),
+            None => {}
+        }
+        if self.partitioned {
+            header.push_str("; Partitioned");
+        }
+        header
     }
-    
-    fn parse_query_string(query_string: &str) -> HashMap<String, String> {
-        let mut params = HashMap::new();
-        for param in query_string.split('&') {
-            if let Some(pos) = param.find('=') {
-                let key = Self::url_decode(&param[..pos]);
-                let value = Self::url_decode(&param[pos + 1..]);
-                params.insert(key, value);
+
+    /// Percent-encode everything outside RFC 6265 cookie-octet, plus "%"
+    /// itself so that decoding is unambiguous
+    fn encode_value(value: &str) -> String {
+        let mut encoded = String::with_capacity(value.len());
+        for byte in value.bytes() {
+            let allowed = matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E) && byte != b'%';
+            if allowed {
+                encoded.push(byte as char);
             } else {
-                params.insert(Self::url_decode(param), String::new());
+                encoded.push_str(&format!("%{:02X}", byte));
             }
         }
-        params
+        encoded
     }
-    
-    fn url_decode(encoded: &str) -> String {
-        let mut result = String::new();
-        let mut bytes = encoded.bytes().peekable();
-        
-        while let Some(byte) = bytes.next() {
-            match byte {
-                b'%' => {
-                    if let (Some(h1), Some(h2)) = (bytes.next(), bytes.next()) {
-                        if let Ok(hex_str) = std::str::from_utf8(&[h1, h2]) {
-                            if let Ok(byte_val) = u8::from_str_radix(hex_str, 16) {
-                                result.push(byte_val as char);
-                            }
-                        }
-                    }
-                }
-                b'+' => result.push(' '),
-                b => result.push(b as char),
+
+    /// Reverse of `encode_value`, for cookies read from a request. Invalid
+    /// escapes are kept literally.
+    fn decode_value(value: &str) -> String {
+        // A value may be wrapped in double quotes, which are not part of it
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1..i + 3].iter().all(u8::is_ascii_hexdigit) {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("00");
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+                i += 3;
+                continue;
             }
+            decoded.push(bytes[i]);
+            i += 1;
         }
-        
-        result
+        String::from_utf8_lossy(&decoded).into_owned()
     }
-    
-    fn decode_chunked(data: &[u8]) -> Vec<u8> {
-        let mut result = Vec::new();
-        let data_str = String::from_utf8_lossy(data);
-        let lines: Vec<&str> = data_str.lines().collect();
-        
-        let mut i = 0;
-        while i < lines.len() {
-            let chunk_size_line = lines[i].trim();
-            
-            // Parse chunk size (hex number)
-            if let Ok(chunk_size) = usize::from_str_radix(chunk_size_line, 16) {
-                if chunk_size == 0 {
-                    // Last chunk
-                    break;
-                }
-                
-                i += 1;
-                if i < lines.len() {
-                    let chunk_data = lines[i].as_bytes();
-                    let data_to_add = std::cmp::min(chunk_size, chunk_data.len());
-                    result.extend_from_slice(&chunk_data[..data_to_add]);
-                }
+
+    /// Attribute values end at ";" and may not contain control characters
+    fn attribute_value(value: &str) -> String {
+        value.chars().filter(|c| *c != ';' && !c.is_control()).collect()
+    }
+}
+
+/// Secrets protecting signed and private cookies. New cookies use the
+/// first key; the rest are only accepted on read, so a key can be rotated
+/// out without logging everyone out at once.
+struct CookieKeys {
+    /// (signing key, encryption key) pairs derived from each configured secret
+    keys: Vec<([u8; 32], [u8; 32])>,
+}
+
+impl std::fmt::Debug for CookieKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CookieKeys({} keys)", self.keys.len())
+    }
+}
+
+impl CookieKeys {
+    /// Secrets shorter than this are rejected when the config is validated
+    const MIN_SECRET_LEN: usize = 32;
+    /// Example secret from the sample config, refused so it cannot ship
+    const PLACEHOLDER_SECRET: &'static str = "change-me-to-a-long-random-secret-value";
+    /// Bytes of nonce in front of a private cookie's ciphertext
+    const NONCE_LEN: usize = 12;
+    /// Bytes of Poly1305 tag after a private cookie's ciphertext
+    const TAG_LEN: usize = 16;
+
+    /// Derive keys from the configured secrets, signing key first. Without
+    /// a configured key a random one is used, so protected cookies do not
+    /// survive a restart.
+    fn from_config(config: &CookieConfig) -> io::Result<Self> {
+        let mut secrets: Vec<Vec<u8>> = config.signing_key.iter().map(|key| key.as_bytes().to_vec()).collect();
+        if secrets.is_empty() {
+            eprintln!("Warning: no [cookies] signing_key configured; using a random key");
+            secrets.push(random_bytes(32)?);
+        }
+        secrets.extend(config.verify_keys.iter().map(|key| key.as_bytes().to_vec()));
+        let keys = secrets
+            .iter()
+            .map(|secret| {
+                (
+                    hmac_sha256(secret, b"localhost cookie signing"),
+                    hmac_sha256(secret, b"localhost cookie encryption"),
+                )
+            })
+            .collect();
+        Ok(CookieKeys { keys })
+    }
+
+    /// Replace the cookie's value with `value.signature`, readable by the
+    /// client but not forgeable. The signature covers the name too, so a
+    /// value cannot be moved to another cookie.
+    fn sign(&self, mut cookie: Cookie) -> Cookie {
+        let value = base64url_encode(cookie.value.as_bytes());
+        let tag = hmac_sha256(&self.keys[0].0, format!("{}={}", cookie.name, value).as_bytes());
+        cookie.value = format!("{}.{}", value, base64url_encode(&tag));
+        cookie
+    }
+
+    /// Replace the cookie's value with a ChaCha20-Poly1305 sealed form that
+    /// the client can neither read nor alter. The cookie name is bound in as
+    /// associated data, so a value cannot be moved to another cookie.
+    fn encrypt(&self, mut cookie: Cookie) -> io::Result<Cookie> {
+        let cipher = ChaCha20Poly1305::new(&self.keys[0].1.into());
+        let mut sealed = random_bytes(Self::NONCE_LEN)?;
+        let payload = Payload {
+            msg: cookie.value.as_bytes(),
+            aad: cookie.name.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(sealed[..Self::NONCE_LEN].into(), payload)
+            .map_err(|_| io::Error::other("cookie encryption failed"))?;
+        sealed.extend_from_slice(&ciphertext);
+        cookie.value = base64url_encode(&sealed);
+        Ok(cookie)
+    }
+
+    /// Value of a signed cookie if any known key produced its signature
+    fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let signature = base64url_decode(signature)?;
+        let message = format!("{}={}", name, value);
+        self.keys
+            .iter()
+            .any(|(mac_key, _)| {
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(mac_key).expect("HMAC accepts 32-byte keys");
+                mac.update(message.as_bytes());
+                mac.verify_slice(&signature).is_ok()
+            })
+            .then(|| base64url_decode(value))
+            .flatten()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+    }
+
+    /// Plaintext of a private cookie if it opens under a known key
+    fn decrypt(&self, name: &str, sealed: &str) -> Option<String> {
+        let sealed = base64url_decode(sealed)?;
+        if sealed.len() < Self::NONCE_LEN + Self::TAG_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(Self::NONCE_LEN);
+        let plaintext = self.keys.iter().find_map(|(_, enc_key)| {
+            let payload = Payload {
+                msg: ciphertext,
+                aad: name.as_bytes(),
+            };
+            ChaCha20Poly1305::new(&(*enc_key).into()).decrypt(nonce.into(), payload).ok()
+        })?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+/// Read access to a request's cookies, verifying protected ones
+struct CookieJar<'a> {
+    cookies: &'a HashMap<String, String>,
+    keys: Option<&'a CookieKeys>,
+}
+
+#[allow(dead_code)]
+impl CookieJar<'_> {
+    /// A plain cookie, with the percent-escapes `Cookie::to_header_value`
+    /// adds decoded
+    fn get(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(|value| value.as_str())
+    }
+
+    /// A cookie created with `signed`, or None if it is missing or was tampered with
+    fn get_signed(&self, name: &str) -> Option<String> {
+        self.keys?.verify(name, self.get(name)?)
+    }
+
+    /// A cookie created with `private`, or None if it is missing or was tampered with
+    fn get_private(&self, name: &str) -> Option<String> {
+        self.keys?.decrypt(name, self.get(name)?)
+    }
+
+    /// Sign a cookie before handing it to `ResponseBuilder::set_cookie`
+    fn signed(&self, cookie: Cookie) -> Option<Cookie> {
+        Some(self.keys?.sign(cookie))
+    }
+
+    /// Encrypt a cookie before handing it to `ResponseBuilder::set_cookie`
+    fn private(&self, cookie: Cookie) -> Option<Cookie> {
+        self.keys?.encrypt(cookie).ok()
+    }
+}
+
+/// Data kept for one session and the times used to expire it
+#[derive(Debug, Clone, Default)]
+struct SessionRecord {
+    data: HashMap<String, String>,
+    /// Unix seconds when the session was first stored
+    created: u64,
+    /// Unix seconds of the last request that used the session
+    last_access: u64,
+}
+
+/// Where session records live between requests. Ids reaching a store are
+/// always ones the server generated.
+trait SessionStore {
+    fn load(&mut self, id: &str) -> io::Result<Option<SessionRecord>>;
+    fn save(&mut self, id: &str, record: &SessionRecord) -> io::Result<()>;
+    fn remove(&mut self, id: &str) -> io::Result<()>;
+    /// Drop every record `keep` rejects and return how many went
+    fn retain(&mut self, keep: &dyn Fn(&SessionRecord) -> bool) -> io::Result<usize>;
+}
+
+/// Sessions held in process memory, lost on restart
+#[derive(Default)]
+struct MemorySessionStore {
+    sessions: HashMap<String, SessionRecord>,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&mut self, id: &str) -> io::Result<Option<SessionRecord>> {
+        Ok(self.sessions.get(id).cloned())
+    }
+
+    fn save(&mut self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        self.sessions.insert(id.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &str) -> io::Result<()> {
+        self.sessions.remove(id);
+        Ok(())
+    }
+
+    fn retain(&mut self, keep: &dyn Fn(&SessionRecord) -> bool) -> io::Result<usize> {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, record| keep(record));
+        Ok(before - self.sessions.len())
+    }
+}
+
+/// Sessions stored one file per id, surviving restarts. A file holds
+/// "created last_access" on its first line, then one "key<TAB>value" line
+/// per entry with both sides percent-encoded.
+struct FileSessionStore {
+    dir: std::path::PathBuf,
+}
+
+impl FileSessionStore {
+    fn new(dir: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(FileSessionStore { dir: std::path::PathBuf::from(dir) })
+    }
+
+    fn path(&self, id: &str) -> io::Result<std::path::PathBuf> {
+        // Ids are hex, so they can never name a file outside the directory
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid session id"));
+        }
+        Ok(self.dir.join(id))
+    }
+
+    fn parse(contents: &str) -> Option<SessionRecord> {
+        let mut lines = contents.lines();
+        let (created, last_access) = lines.next()?.split_once(' ')?;
+        let mut record = SessionRecord {
+            data: HashMap::new(),
+            created: created.parse().ok()?,
+            last_access: last_access.parse().ok()?,
+        };
+        for line in lines {
+            let (key, value) = line.split_once('\t')?;
+            record.data.insert(Cookie::decode_value(key), Cookie::decode_value(value));
+        }
+        Some(record)
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&mut self, id: &str) -> io::Result<Option<SessionRecord>> {
+        match fs::read_to_string(self.path(id)?) {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&mut self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let path = self.path(id)?;
+        let mut contents = format!("{} {}\n", record.created, record.last_access);
+        for (key, value) in &record.data {
+            contents.push_str(&format!("{}\t{}\n", Cookie::encode_value(key), Cookie::encode_value(value)));
+        }
+        // Write then rename, so a crash never leaves a half-written session
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, contents)?;
+        fs::rename(temp, path)
+    }
+
+    fn remove(&mut self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn retain(&mut self, keep: &dyn Fn(&SessionRecord) -> bool) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let record = fs::read_to_string(&path).ok().and_then(|contents| Self::parse(&contents));
+            // Unreadable files are left for the operator rather than deleted
+            if record.is_some_and(|record| !keep(&record)) && fs::remove_file(&path).is_ok() {
+                removed += 1;
             }
-            
-            i += 1;
         }
-        
-        result
+        Ok(removed)
     }
-    
-    fn parse_form_data(content_type: &str, body: &[u8]) -> (HashMap<String, String>, HashMap<String, FormFile>) {
-        let mut fields = HashMap::new();
-        let mut files = HashMap::new();
-        
-        if content_type.contains("application/x-www-form-urlencoded") {
-            // Parse URL-encoded form data
-            let body_str = String::from_utf8_lossy(body);
-            for param in body_str.split('&') {
-                if let Some(pos) = param.find('=') {
-                    let key = Self::url_decode(&param[..pos]);
-                    let value = Self::url_decode(&param[pos + 1..]);
-                    fields.insert(key, value);
-                }
+}
+
+/// A request's view of its session
+#[derive(Debug, Default)]
+struct Session {
+    /// None until the session is first stored
+    id: Option<String>,
+    record: SessionRecord,
+    changed: bool,
+    /// Move the data to a fresh id when saving
+    renew: bool,
+    destroyed: bool,
+}
+
+#[allow(dead_code)]
+impl Session {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.record.data.get(key).map(|value| value.as_str())
+    }
+
+    fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.record.data.insert(key.into(), value.into());
+        self.changed = true;
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        let value = self.record.data.remove(key);
+        self.changed |= value.is_some();
+        value
+    }
+
+    /// Issue a new id for the same data. Call this when privileges change,
+    /// such as on login, so an id planted before then becomes useless.
+    fn renew(&mut self) {
+        self.renew = true;
+    }
+
+    /// Delete the session and its cookie
+    fn destroy(&mut self) {
+        self.destroyed = true;
+        self.record.data.clear();
+    }
+
+    /// True until the session has been stored and its cookie sent
+    fn is_new(&self) -> bool {
+        self.id.is_none()
+    }
+
+    fn created(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.record.created)
+    }
+}
+
+/// Loads sessions before routing, saves them afterwards and sweeps out
+/// expired ones
+struct Sessions {
+    store: Box<dyn SessionStore>,
+    config: SessionConfig,
+    last_sweep: Instant,
+}
+
+impl Sessions {
+    /// Unchanged sessions are touched once per idle timeout / this
+    const TOUCH_DIVISOR: u64 = 4;
+
+    fn from_config(config: &SessionConfig) -> io::Result<Self> {
+        let store: Box<dyn SessionStore> = match config.store {
+            SessionBackend::Memory => Box::new(MemorySessionStore::default()),
+            SessionBackend::File => Box::new(FileSessionStore::new(&config.dir)?),
+        };
+        Ok(Sessions {
+            store,
+            config: config.clone(),
+            last_sweep: Instant::now(),
+        })
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+
+    /// Takes the config rather than `self` so `sweep` can use it while the
+    /// store is borrowed
+    fn is_live(config: &SessionConfig, record: &SessionRecord, now: u64) -> bool {
+        now.saturating_sub(record.last_access) < config.idle_timeout_secs
+            && now.saturating_sub(record.created) < config.absolute_timeout_secs
+    }
+
+    /// The session named by the request's cookie, or a new empty one if the
+    /// cookie is missing, forged or points at an expired session
+    fn load(&mut self, request: &HttpRequest) -> Session {
+        let Some(id) = request.cookie_jar().get_signed(&self.config.cookie_name) else {
+            return Session::default();
+        };
+        let now = Self::now();
+        match self.store.load(&id) {
+            Ok(Some(record)) if Self::is_live(&self.config, &record, now) => Session {
+                id: Some(id),
+                record,
+                ..Session::default()
+            },
+            Ok(Some(_)) => {
+                let _ = self.store.remove(&id);
+                Session::default()
             }
-        } else if content_type.contains("multipart/form-data") {
-            // Extract boundary from content type
-            if let Some(boundary_start) = content_type.find("boundary=") {
-                let boundary = &content_type[boundary_start + 9..];
-                let boundary = if let Some(semicolon) = boundary.find(';') {
-                    &boundary[..semicolon]
-                } else {
-                    boundary
-                };
-                
-                Self::parse_multipart(body, boundary, &mut fields, &mut files);
+            Ok(None) => Session::default(),
+            Err(e) => {
+                eprintln!("Error loading session: {}", e);
+                Session::default()
             }
         }
-        
-        (fields, files)
     }
-    
-    fn parse_multipart(
-        body: &[u8],
-        boundary: &str,
-        fields: &mut HashMap<String, String>,
-        files: &mut HashMap<String, FormFile>,
-    ) {
-        let body_str = String::from_utf8_lossy(body);
-        let boundary_marker = format!("--{}", boundary);
-        let parts: Vec<&str> = body_str.split(&boundary_marker).collect();
-        
-        for part in parts.iter().skip(1) {
-            if part.contains("--") {
-                // End boundary
-                break;
-            }
-            
-            let part = part.trim();
-            if let Some(blank_line_pos) = part.find("\r\n\r\n") {
-                let headers_str = &part[..blank_line_pos];
-                let content = &part[blank_line_pos + 4..];
-                let content = content.trim_end_matches("\r\n");
-                
-                // Parse part headers
-                let mut field_name = String::new();
-                let mut filename = Option::<String>::None;
-                let mut content_type_part = String::from("text/plain");
-                
-                for header_line in headers_str.lines() {
-                    if let Some(colon_pos) = header_line.find(':') {
-                        let header_name = header_line[..colon_pos].trim().to_lowercase();
-                        let header_value = header_line[colon_pos + 1..].trim();
-                        
-                        if header_name == "content-disposition" {
-                            // Parse: form-data; name="field_name"; filename="file.txt"
-                            if let Some(name_start) = header_value.find("name=\"") {
-                                let name_start = name_start + 6;
-                                if let Some(name_end) = header_value[name_start..].find('"') {
-                                    field_name = header_value[name_start..name_start + name_end].to_string();
-                                }
-                            }
-                            
-                            if let Some(file_start) = header_value.find("filename=\"") {
-                                let file_start = file_start + 10;
-                                if let Some(file_end) = header_value[file_start..].find('"') {
-                                    filename = Some(header_value[file_start..file_start + file_end].to_string());
-                                }
-                            }
-                        } else if header_name == "content-type" {
-                            content_type_part = header_value.to_string();
-                        }
-                    }
+
+    /// Write the session back and set or clear its cookie on the response.
+    /// Sessions only read from are rewritten just to refresh their idle
+    /// timer, and at most once per fraction of the idle timeout.
+    fn save(&mut self, mut session: Session, response: &mut HttpResponse, keys: &CookieKeys) {
+        let cookie = |value: &str| {
+            Cookie::new(&self.config.cookie_name, value)
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+        };
+
+        if session.destroyed {
+            if let Some(id) = &session.id {
+                if let Err(e) = self.store.remove(id) {
+                    eprintln!("Error removing session: {}", e);
                 }
-                
-                // Store field or file
-                if let Some(filename) = filename {
-                    files.insert(
-                        field_name,
-                        FormFile {
-                            filename,
-                            content_type: content_type_part,
-                            data: content.as_bytes().to_vec(),
-                        },
-                    );
-                } else {
-                    fields.insert(field_name, content.to_string());
+                response.headers.append("Set-Cookie", cookie("").expired().to_header_value());
+            }
+            return;
+        }
+        // Nothing worth storing in a session nobody wrote to
+        if session.is_new() && !session.changed {
+            return;
+        }
+
+        let now = Self::now();
+        let stale = now.saturating_sub(session.record.last_access) >= self.config.idle_timeout_secs / Self::TOUCH_DIVISOR;
+        if !session.is_new() && !session.changed && !session.renew && !stale {
+            return;
+        }
+        if session.is_new() || session.renew {
+            if let Some(old) = &session.id {
+                let _ = self.store.remove(old);
+            }
+            let id = match random_hex(16) {
+                Ok(id) => id,
+                Err(e) => {
+                    eprintln!("Error generating session id: {}", e);
+                    return;
                 }
+            };
+            response.headers.append("Set-Cookie", keys.sign(cookie(&id)).to_header_value());
+            session.id = Some(id);
+        }
+        if session.record.created == 0 {
+            session.record.created = now;
+        }
+        session.record.last_access = now;
+        if let Some(id) = &session.id {
+            if let Err(e) = self.store.save(id, &session.record) {
+                eprintln!("Error saving session: {}", e);
             }
         }
     }
-}
 
-type RouteHandler = fn(&HttpRequest) -> HttpResponse;
+    /// Drop expired sessions, at most once per sweep interval
+    fn sweep(&mut self) {
+        if self.last_sweep.elapsed() < Duration::from_secs(self.config.sweep_interval_secs) {
+            return;
+        }
+        self.last_sweep = Instant::now();
+        let now = Self::now();
+        let config = &self.config;
+        let keep = |record: &SessionRecord| Self::is_live(config, record, now);
+        match self.store.retain(&keep) {
+            Ok(0) => {}
+            Ok(removed) => println!("Expired {} sessions", removed),
+            Err(e) => eprintln!("Error sweeping sessions: {}", e),
+        }
+    }
+}
 
-struct Route {
-    method: String,
-    path: String,
-    handler: RouteHandler,
+/// Bytes from the kernel CSPRNG
+fn random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
 }
 
-struct Router {
-    routes: Vec<Route>,
+/// `len` random bytes as lowercase hex, for ids and tokens
+fn random_hex(len: usize) -> io::Result<String> {
+    Ok(random_bytes(len)?.iter().map(|b| format!("{:02x}", b)).collect())
 }
 
-impl Router {
-    fn new() -> Self {
-        Router {
-            routes: Vec::new(),
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Unpadded base64url (RFC 4648 section 5), safe in cookie values and URLs
+fn base64url_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..=chunk.len() {
+            encoded.push(BASE64URL[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
         }
     }
-    
-    fn register(&mut self, method: &str, path: &str, handler: RouteHandler) {
-        self.routes.push(Route {
-            method: method.to_string(),
-            path: path.to_string(),
-            handler,
-        });
+    encoded
+}
+
+fn base64url_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = BASE64URL.iter().position(|&b| b == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        decoded.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
     }
-    
-    fn handle(&self, request: &HttpRequest) -> HttpResponse {
-        // Check for CGI paths first (/cgi-bin/*)
-        if request.path.starts_with("/cgi-bin/") {
-            return handle_cgi(request, "127.0.0.1");
+    Some(decoded)
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 (RFC 2104)
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Phases an in-flight request moves through while it is read off the socket
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParseState {
+    RequestLine,
+    Headers,
+    Body { remaining: usize },
+    ChunkSize,
+    ChunkData { remaining: usize },
+    ChunkDataEnd,
+    ChunkTrailer,
+    Complete,
+}
+
+/// Reasons a request could not be parsed
+#[derive(Debug)]
+enum ParseError {
+    BadRequest(String),
+    /// Request line longer than `max_header_bytes`
+    UriTooLong,
+    /// Header section larger than `max_header_bytes` or with more than `max_headers` fields
+    HeaderFieldsTooLarge,
+    /// Body larger than `client_max_body_size`
+    PayloadTooLarge,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ParseError::UriTooLong => write!(f, "request line too long"),
+            ParseError::HeaderFieldsTooLarge => write!(f, "header section too large"),
+            ParseError::PayloadTooLarge => write!(f, "request body too large"),
         }
+    }
+}
 
-        // Try to find an exact match first
-        for route in &self.routes {
-            if route.method == request.method && route.path == request.path {
-                return (route.handler)(request);
+impl ParseError {
+    /// Build the error response sent back before the connection is closed
+    fn to_response(&self) -> HttpResponse {
+        match self {
+            ParseError::BadRequest(_) => {
+                HttpResponse::new(400, "Bad Request", &ErrorPages::bad_request())
             }
-        }
-        
-        // Try path prefix matching (for routes like /api/*)
-        // But exclude root path "/" from prefix matching
-        for route in &self.routes {
-            if route.method == request.method && route.path != "/" && request.path.starts_with(&route.path) {
-                return (route.handler)(request);
+            ParseError::UriTooLong => {
+                HttpResponse::new(414, "URI Too Long", &ErrorPages::uri_too_long())
+            }
+            ParseError::HeaderFieldsTooLarge => HttpResponse::new(
+                431,
+                "Request Header Fields Too Large",
+                &ErrorPages::header_fields_too_large(),
+            ),
+            ParseError::PayloadTooLarge => {
+                HttpResponse::new(413, "Payload Too Large", &ErrorPages::payload_too_large())
             }
         }
-        
-        // Default 404 response
-        HttpResponse::new(404, "Not Found", &ErrorPages::not_found())
     }
 }
 
-// Error page builder
-#[allow(dead_code)]
-struct ErrorPages;
+/// Size limits enforced while a request is being read
+#[derive(Debug, Clone, Copy)]
+struct ParserLimits {
+    /// Request line plus header section, in bytes
+    max_header_bytes: usize,
+    /// Number of header fields
+    max_headers: usize,
+    /// Decoded body size, in bytes
+    max_body_size: usize,
+}
 
-impl ErrorPages {
-    #[allow(dead_code)]
-    fn not_found() -> String {
-        format!(
-            r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>404 Not Found</title>
-    <style>
-        body {{
-            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
-            margin: 0;
-            padding: 0;
-            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
-            height: 100vh;
-            display: flex;
-            justify-content: center;
-            align-items: center;
-        }}
-        .container {{
-            text-align: center;
-            background: white;
-            padding: 50px;
-            border-radius: 10px;
-            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
-            max-width: 600px;
-        }}
-        h1 {{
-            color: #e74c3c;
-            font-size: 72px;
-            margin: 0;
-            font-weight: 700;
-        }}
-        p {{
-            color: #666;
-            font-size: 18px;
-            margin: 20px 0;
-        }}
-        a {{
-            display: inline-block;
-            margin-top: 20px;
-            padding: 12px 30px;
-            background: #667eea;
-            color: white;
-            text-decoration: none;
-            border-radius: 5px;
-            transition: background 0.3s;
-        }}
-        a:hover {{
-            background: #764ba2;
-        }}
-        .error-details {{
-            text-align: left;
-            background: #f5f5f5;
-            padding: 20px;
-            border-radius: 5px;
-            margin-top: 30px;
-            font-size: 14px;
-            color: #333;
-        }}
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>404</h1>
-        <p>The page you're looking for doesn't exist.</p>
-        <p>It might have been removed or the URL might be incorrect.</p>
-        <a href="/">Go Home</a>
-        <div class="error-details">
-            <strong>Error Details:</strong><br>
-            Resource not found on this server.
-        </div>
-    </div>
-</body>
-</html>"#
-        )
+impl ParserLimits {
+    fn from_config(config: &ServerConfig) -> Self {
+        ParserLimits {
+            max_header_bytes: config.max_header_bytes,
+            max_headers: config.max_headers,
+            max_body_size: config.client_max_body_size,
+        }
     }
+}
 
-    #[allow(dead_code)]
-    fn bad_request() -> String {
-        format!(
-            r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>400 Bad Request</title>
-    <style>
-        body {{
-            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
-            margin: 0;
-            padding: 0;
-            background: linear-gradient(135deg, #f093fb 0%, #f5576c 100%);
-            height: 100vh;
-            display: flex;
-            justify-content: center;
-            align-items: center;
-        }}
-        .container {{
-            text-align: center;
-            background: white;
-            padding: 50px;
-            border-radius: 10px;
-            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
-            max-width: 600px;
-        }}
-        h1 {{
-            color: #f5576c;
-            font-size: 72px;
-            margin: 0;
-            font-weight: 700;
-        }}
-        p {{
-            color: #666;
-            font-size: 18px;
-            margin: 20px 0;
-        }}
-        a {{
-            display: inline-block;
-            margin-top: 20px;
-            padding: 12px 30px;
-            background: #f5576c;
-            color: white;
-            text-decoration: none;
-            border-radius: 5px;
-            transition: background 0.3s;
-        }}
-        a:hover {{
-            background: #f093fb;
-        }}
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>400</h1>
-        <p>Bad Request</p>
-        <p>The server cannot process your request due to a client error.</p>
-        <a href="/">Go Home</a>
-    </div>
-</body>
-</html>"#
-        )
+/// Resumable HTTP/1.x request parser.
+///
+/// Each connection owns one parser. Bytes are fed in as they arrive and the
+/// parser only yields an `HttpRequest` once the request line, headers and the
+/// whole body (by Content-Length or chunked framing) have been received.
+struct HttpParser {
+    state: ParseState,
+    limits: ParserLimits,
+    /// Bytes of request line and headers consumed so far
+    header_bytes: usize,
+    /// Header (or trailer) fields seen so far, repeats included
+    field_count: usize,
+    /// Bytes of the current unfinished line already searched for a newline,
+    /// so a line trickling in is not rescanned from its start on every read
+    scanned: usize,
+    /// Set when the client sent "Expect: 100-continue" and is waiting for
+    /// the go-ahead before sending the body
+    continue_pending: bool,
+    method: String,
+    full_path: String,
+    version: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    trailers: HeaderMap,
+}
+
+/// Longest chunk size line (size plus extensions) accepted before giving up
+const MAX_CHUNK_LINE: usize = 4096;
+
+impl HttpParser {
+    fn new(limits: ParserLimits) -> Self {
+        HttpParser {
+            state: ParseState::RequestLine,
+            limits,
+            header_bytes: 0,
+            field_count: 0,
+            scanned: 0,
+            continue_pending: false,
+            method: String::new(),
+            full_path: String::new(),
+            version: String::new(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            trailers: HeaderMap::new(),
+        }
     }
 
-    #[allow(dead_code)]
-    fn internal_error() -> String {
-        format!(
-            r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>500 Internal Server Error</title>
-    <style>
-        body {{
-            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
-            margin: 0;
-            padding: 0;
-            background: linear-gradient(135deg, #eb3349 0%, #f45c43 100%);
-            height: 100vh;
-            display: flex;
-            justify-content: center;
-            align-items: center;
-        }}
-        .container {{
-            text-align: center;
-            background: white;
-            padding: 50px;
-            border-radius: 10px;
-            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
-            max-width: 600px;
-        }}
-        h1 {{
-            color: #eb3349;
-            font-size: 72px;
-            margin: 0;
-            font-weight: 700;
-        }}
-        p {{
-            color: #666;
-            font-size: 18px;
-            margin: 20px 0;
-        }}
-        a {{
-            display: inline-block;
-            margin-top: 20px;
-            padding: 12px 30px;
-            background: #eb3349;
-            color: white;
-            text-decoration: none;
-            border-radius: 5px;
-            transition: background 0.3s;
-        }}
-        a:hover {{
-            background: #f45c43;
-        }}
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>500</h1>
-        <p>Internal Server Error</p>
-        <p>Something went wrong on our end. Please try again later.</p>
-        <a href="/">Go Home</a>
-    </div>
-</body>
-</html>"#
-        )
+    /// Whether the parser is between requests (nothing partially read)
+    fn is_idle(&self) -> bool {
+        self.state == ParseState::RequestLine
     }
 
-    #[allow(dead_code)]
-    fn method_not_allowed() -> String {
-        format!(
-            r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>405 Method Not Allowed</title>
-    <style>
-        body {{
-            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
-            margin: 0;
-            padding: 0;
-            background: linear-gradient(135deg, #fa709a 0%, #fee140 100%);
-            height: 100vh;
-            display: flex;
-            justify-content: center;
-            align-items: center;
-        }}
-        .container {{
-            text-align: center;
-            background: white;
-            padding: 50px;
-            border-radius: 10px;
-            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
-            max-width: 600px;
-        }}
-        h1 {{
-            color: #fa709a;
-            font-size: 72px;
-            margin: 0;
-            font-weight: 700;
-        }}
-        p {{
-            color: #666;
-            font-size: 18px;
-            margin: 20px 0;
-        }}
-        a {{
-            display: inline-block;
-            margin-top: 20px;
-            padding: 12px 30px;
-            background: #fa709a;
-            color: white;
-            text-decoration: none;
-            border-radius: 5px;
-            transition: background 0.3s;
-        }}
-        a:hover {{
-            background: #fee140;
-            color: #333;
-        }}
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>405</h1>
-        <p>Method Not Allowed</p>
-        <p>The HTTP method used is not supported for this resource.</p>
-        <a href="/">Go Home</a>
-    </div>
-</body>
-</html>"#
+    /// Whether the headers are in and the body is still being received
+    fn in_body(&self) -> bool {
+        !matches!(
+            self.state,
+            ParseState::RequestLine | ParseState::Headers | ParseState::Complete
         )
     }
-}
 
-// Route handlers
-fn handle_root(_req: &HttpRequest) -> HttpResponse {
-    let html = r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Localhost Server</title>
-    <style>
-        body { font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; margin: 0; padding: 20px; background: linear-gradient(135deg, #667eea 0%, #764ba2 100%); min-height: 100vh; }
-        .container { max-width: 900px; margin: 0 auto; background: white; padding: 40px; border-radius: 10px; box-shadow: 0 10px 40px rgba(0,0,0,0.2); }
-        h1 { color: #667eea; margin-top: 0; }
-        h2 { color: #764ba2; margin-top: 30px; }
-        .endpoint { background: #f5f5f5; padding: 15px; margin: 10px 0; border-left: 4px solid #667eea; border-radius: 4px; }
-        .endpoint code { background: #e8e8ff; padding: 2px 6px; border-radius: 3px; font-weight: bold; }
-        .endpoint p { margin: 5px 0; color: #555; }
-        a { color: #667eea; text-decoration: none; font-weight: bold; }
-        a:hover { text-decoration: underline; }
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>🚀 Welcome to Localhost Server</h1>
-        <p>A high-performance HTTP server built in Rust with epoll event loop and advanced HTTP parsing.</p>
-        
-        <h2>📍 Available Endpoints</h2>
+    /// Whether a "100 Continue" interim response should be sent now. Only
+    /// returns true once per request.
+    fn take_continue(&mut self) -> bool {
+        std::mem::take(&mut self.continue_pending)
+    }
+
+    /// Consume as much of `buffer` as possible.
+    ///
+    /// Returns `Ok(Some(request))` once a full request has been read. Consumed
+    /// bytes are drained from the front of `buffer`; anything belonging to a
+    /// following request is left in place for the next call.
+    fn advance(&mut self, buffer: &mut Vec<u8>) -> Result<Option<HttpRequest>, ParseError> {
+        let mut pos = 0;
+        let result = self.advance_from(buffer, &mut pos);
+        buffer.drain(..pos);
+        result
+    }
+
+    fn advance_from(&mut self, data: &[u8], pos: &mut usize) -> Result<Option<HttpRequest>, ParseError> {
+        loop {
+            match self.state {
+                ParseState::RequestLine => {
+                    let Some((line, next)) = self.next_header_line(data, *pos, ParseError::UriTooLong)? else {
+                        return Ok(None);
+                    };
+                    *pos = next;
+                    // Tolerate empty lines before the request line (RFC 9112 section 2.2)
+                    if line.is_empty() {
+                        self.header_bytes = 0;
+                        continue;
+                    }
+                    self.parse_request_line(line)?;
+                    self.state = ParseState::Headers;
+                }
+                ParseState::Headers => {
+                    let Some((line, next)) = self.next_header_line(data, *pos, ParseError::HeaderFieldsTooLarge)? else {
+                        return Ok(None);
+                    };
+                    *pos = next;
+                    if line.is_empty() {
+                        self.state = self.body_state()?;
+                        self.continue_pending = self.state != ParseState::Complete
+                            && self.headers.get("Expect")
+                                .map(|value| value.eq_ignore_ascii_case("100-continue"))
+                                .unwrap_or(false);
+                    } else {
+                        self.field_count += 1;
+                        if self.field_count > self.limits.max_headers {
+                            return Err(ParseError::HeaderFieldsTooLarge);
+                        }
+                        self.parse_header_line(line)?;
+                    }
+                }
+                ParseState::Body { remaining } => {
+                    let available = std::cmp::min(remaining, data.len() - *pos);
+                    self.body.extend_from_slice(&data[*pos..*pos + available]);
+                    *pos += available;
+                    if available < remaining {
+                        self.state = ParseState::Body { remaining: remaining - available };
+                        return Ok(None);
+                    }
+                    self.state = ParseState::Complete;
+                }
+                ParseState::ChunkSize => {
+                    let Some((line, next)) = self.next_line(data, *pos) else {
+                        if data.len() - *pos > MAX_CHUNK_LINE {
+                            return Err(ParseError::BadRequest("chunk size line too long".into()));
+                        }
+                        return Ok(None);
+                    };
+                    *pos = next;
+                    let size = Self::parse_chunk_size(line)?;
+                    if size > self.limits.max_body_size.saturating_sub(self.body.len()) {
+                        return Err(ParseError::PayloadTooLarge);
+                    }
+                    self.state = if size == 0 {
+                        self.header_bytes = 0;
+                        self.field_count = 0;
+                        ParseState::ChunkTrailer
+                    } else {
+                        ParseState::ChunkData { remaining: size }
+                    };
+                }
+                ParseState::ChunkData { remaining } => {
+                    let available = std::cmp::min(remaining, data.len() - *pos);
+                    self.body.extend_from_slice(&data[*pos..*pos + available]);
+                    *pos += available;
+                    if available < remaining {
+                        self.state = ParseState::ChunkData { remaining: remaining - available };
+                        return Ok(None);
+                    }
+                    self.state = ParseState::ChunkDataEnd;
+                }
+                ParseState::ChunkDataEnd => {
+                    // Chunk data must be followed by exactly CRLF (a bare LF is tolerated)
+                    match &data[*pos..] {
+                        [] | [b'\r'] => return Ok(None),
+                        [b'\r', b'\n', ..] => *pos += 2,
+                        [b'\n', ..] => *pos += 1,
+                        _ => return Err(ParseError::BadRequest("missing CRLF after chunk data".into())),
+                    }
+                    self.state = ParseState::ChunkSize;
+                }
+                ParseState::ChunkTrailer => {
+                    // Trailers count against the header limits as well
+                    let Some((line, next)) = self.next_header_line(data, *pos, ParseError::HeaderFieldsTooLarge)? else {
+                        return Ok(None);
+                    };
+                    *pos = next;
+                    if line.is_empty() {
+                        self.state = ParseState::Complete;
+                    } else {
+                        self.field_count += 1;
+                        if self.field_count > self.limits.max_headers {
+                            return Err(ParseError::HeaderFieldsTooLarge);
+                        }
+                        let (key, value) = Self::parse_field_line(line)?;
+                        self.trailers.append(key, value);
+                    }
+                }
+                ParseState::Complete => {
+                    let request = self.finish();
+                    return Ok(Some(request));
+                }
+            }
+        }
+    }
+
+    /// Like `next_line`, but counts the bytes against `max_header_bytes` and
+    /// fails with `too_large` as soon as the limit is exceeded, even while
+    /// the line is still incomplete
+    fn next_header_line<'a>(
+        &mut self,
+        data: &'a [u8],
+        start: usize,
+        too_large: ParseError,
+    ) -> Result<Option<(&'a [u8], usize)>, ParseError> {
+        match self.next_line(data, start) {
+            Some((line, next)) => {
+                self.header_bytes += next - start;
+                if self.header_bytes > self.limits.max_header_bytes {
+                    return Err(too_large);
+                }
+                Ok(Some((line, next)))
+            }
+            None => {
+                if self.header_bytes + (data.len() - start) > self.limits.max_header_bytes {
+                    return Err(too_large);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Find the next line starting at `start`, without its line terminator.
+    /// Returns the line and the offset just past it, or None if incomplete.
+    fn next_line<'a>(&mut self, data: &'a [u8], start: usize) -> Option<(&'a [u8], usize)> {
+        let from = start + self.scanned.min(data.len() - start);
+        let Some(found) = data[from..].iter().position(|&b| b == b'\n') else {
+            self.scanned = data.len() - start;
+            return None;
+        };
+        self.scanned = 0;
+        let newline = from - start + found;
+        let mut line = &data[start..start + newline];
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        Some((line, start + newline + 1))
+    }
+
+    fn parse_request_line(&mut self, line: &[u8]) -> Result<(), ParseError> {
+        // Parse request line: "GET /path?query=value HTTP/1.1"
+        let parts: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+        if parts.len() != 3
+            || parts[0].is_empty()
+            || !parts[0].iter().all(|&b| Self::is_token_byte(b))
+            || parts[1].is_empty()
+            || !parts[1].iter().all(|&b| b.is_ascii_graphic())
+            || !parts[2].starts_with(b"HTTP/")
+        {
+            return Err(ParseError::BadRequest(format!(
+                "malformed request line: {}",
+                String::from_utf8_lossy(line)
+            )));
+        }
+
+        if Self::has_dot_segment(parts[1]) {
+            return Err(ParseError::BadRequest("dot segment in request target".into()));
+        }
+
+        // All three parts are plain ASCII at this point
+        self.method = String::from_utf8_lossy(parts[0]).into_owned();
+        self.full_path = String::from_utf8_lossy(parts[1]).into_owned();
+        self.version = String::from_utf8_lossy(parts[2]).into_owned();
+        Ok(())
+    }
+
+    /// Whether the path of a request target has a "." or ".." segment,
+    /// percent-encoded or not. Such paths are refused before routing so they
+    /// cannot climb out of the route or location they matched.
+    fn has_dot_segment(target: &[u8]) -> bool {
+        let path = String::from_utf8_lossy(target);
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        path.split('/')
+            .filter_map(StaticFiles::percent_decode)
+            .any(|segment| is_dot_segment(&segment))
+    }
+
+    fn parse_header_line(&mut self, line: &[u8]) -> Result<(), ParseError> {
+        let (key, value) = Self::parse_field_line(line)?;
+        self.headers.append(key, value);
+        Ok(())
+    }
+
+    /// Parse a "name: value" line, shared by the header and trailer sections
+    fn parse_field_line(line: &[u8]) -> Result<(String, String), ParseError> {
+        // Obsolete line folding is not supported (RFC 9112 section 5.2)
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(ParseError::BadRequest("obsolete header line folding".into()));
+        }
+
+        let Some(colon_pos) = line.iter().position(|&b| b == b':') else {
+            return Err(ParseError::BadRequest(format!(
+                "malformed header: {}",
+                String::from_utf8_lossy(line)
+            )));
+        };
+        let key = &line[..colon_pos];
+        if key.is_empty() || !key.iter().all(|&b| Self::is_token_byte(b)) {
+            return Err(ParseError::BadRequest(format!(
+                "malformed header name: {}",
+                String::from_utf8_lossy(key)
+            )));
+        }
+
+        let value = Self::trim_ows(&line[colon_pos + 1..]);
+        if value.iter().any(|&b| b == b'\r' || b == 0) {
+            return Err(ParseError::BadRequest("invalid character in header value".into()));
+        }
+
+        // Header names are ASCII tokens; values may carry obs-text, which is
+        // decoded leniently since handlers treat headers as text
+        Ok((
+            String::from_utf8_lossy(key).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ))
+    }
+
+    /// Parse a chunk size line: `1*HEXDIG *( BWS ";" BWS ext-name [ BWS "=" BWS ext-val ] )`.
+    /// Extensions are validated and then ignored.
+    fn parse_chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+        let invalid = || {
+            ParseError::BadRequest(format!(
+                "invalid chunk size line: {}",
+                String::from_utf8_lossy(line)
+            ))
+        };
+
+        let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+        if digits == 0 {
+            return Err(invalid());
+        }
+        let mut size: usize = 0;
+        for &b in &line[..digits] {
+            let digit = (b as char).to_digit(16).unwrap_or(0) as usize;
+            size = size
+                .checked_mul(16)
+                .and_then(|s| s.checked_add(digit))
+                .ok_or_else(invalid)?;
+        }
+
+        let mut rest = Self::trim_ows(&line[digits..]);
+        while !rest.is_empty() {
+            let [b';', tail @ ..] = rest else {
+                return Err(invalid());
+            };
+            rest = Self::trim_ows(tail);
+
+            let name_len = rest.iter().take_while(|&&b| Self::is_token_byte(b)).count();
+            if name_len == 0 {
+                return Err(invalid());
+            }
+            rest = Self::trim_ows(&rest[name_len..]);
+
+            if let [b'=', tail @ ..] = rest {
+                rest = Self::trim_ows(tail);
+                let value_len = if rest.first() == Some(&b'"') {
+                    Self::quoted_string_len(rest).ok_or_else(invalid)?
+                } else {
+                    rest.iter().take_while(|&&b| Self::is_token_byte(b)).count()
+                };
+                if value_len == 0 {
+                    return Err(invalid());
+                }
+                rest = Self::trim_ows(&rest[value_len..]);
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Length of the quoted-string at the start of `data`, including both quotes
+    fn quoted_string_len(data: &[u8]) -> Option<usize> {
+        let mut i = 1;
+        while i < data.len() {
+            match data[i] {
+                b'"' => return Some(i + 1),
+                b'\\' => i += 2,
+                _ => i += 1,
+            }
+        }
+        None
+    }
+
+    /// tchar from RFC 9110 section 5.6.2
+    fn is_token_byte(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    }
+
+    /// Strip optional whitespace (spaces and tabs) from both ends
+    fn trim_ows(mut value: &[u8]) -> &[u8] {
+        while let [b' ' | b'\t', rest @ ..] = value {
+            value = rest;
+        }
+        while let [rest @ .., b' ' | b'\t'] = value {
+            value = rest;
+        }
+        value
+    }
+
+    /// Position of `needle` within `haystack`, starting the search at `from`
+    fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+        if needle.is_empty() || from > haystack.len() {
+            return None;
+        }
+        haystack[from..]
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|pos| pos + from)
+    }
+
+    /// Decide how the body is framed once all headers are in
+    fn body_state(&mut self) -> Result<ParseState, ParseError> {
+        if let Some(encoding) = self.headers.get_joined("Transfer-Encoding") {
+            // Transfer-Encoding overrides Content-Length (RFC 9112 section 6.3)
+            let last_coding = encoding.rsplit(',').next().unwrap_or("").trim().to_lowercase();
+            if last_coding != "chunked" {
+                return Err(ParseError::BadRequest(format!("unsupported transfer coding: {}", encoding)));
+            }
+            self.headers.remove("Content-Length");
+            return Ok(ParseState::ChunkSize);
+        }
+
+        if let Some(length) = self.headers.get_joined("Content-Length") {
+            // Repeated identical values ("5, 5", or the field sent twice) are
+            // allowed, anything else is rejected
+            let mut values = length.split(',').map(|v| v.trim());
+            let first = values.next().unwrap_or("");
+            if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) || values.any(|v| v != first) {
+                return Err(ParseError::BadRequest(format!("invalid Content-Length: {}", length)));
+            }
+            let length: usize = first
+                .parse()
+                .map_err(|_| ParseError::BadRequest(format!("invalid Content-Length: {}", first)))?;
+            if length > self.limits.max_body_size {
+                // Rejected before any of the body is buffered
+                return Err(ParseError::PayloadTooLarge);
+            }
+            if length > 0 {
+                return Ok(ParseState::Body { remaining: length });
+            }
+        }
+
+        Ok(ParseState::Complete)
+    }
+
+    /// Turn the accumulated parts into a request and reset for the next one
+    fn finish(&mut self) -> HttpRequest {
+        let method = std::mem::take(&mut self.method);
+        let full_path = std::mem::take(&mut self.full_path);
+        let version = std::mem::take(&mut self.version);
+        let headers = std::mem::take(&mut self.headers);
+        let body = std::mem::take(&mut self.body);
+        let trailers = std::mem::take(&mut self.trailers);
+        self.state = ParseState::RequestLine;
+        self.header_bytes = 0;
+        self.field_count = 0;
+        self.continue_pending = false;
+
+        // Split path and query string
+        let (path, query_string) = if let Some(pos) = full_path.find('?') {
+            (
+                full_path[..pos].to_string(),
+                Some(full_path[pos + 1..].to_string()),
+            )
+        } else {
+            (full_path, None)
+        };
+
+        let mut cookies = HashMap::new();
+        for cookie_header in headers.get_all("Cookie") {
+            Self::parse_cookies(cookie_header, &mut cookies);
+        }
+
+        // Parse query parameters
+        let query_params = if let Some(ref qs) = query_string {
+            Self::parse_query_string(qs)
+        } else {
+            HashMap::new()
+        };
+
+        // Parse form data (multipart or urlencoded)
+        let content_type = headers.get("Content-Type").unwrap_or_default().to_string();
+        let (form_fields, form_files) = Self::parse_form_data(&content_type, &body);
+
+        HttpRequest {
+            method,
+            path,
+            query_string,
+            version,
+            headers,
+            cookies,
+            query_params,
+            form_fields,
+            form_files,
+            body,
+            trailers,
+            remote_addr: None,
+            local_addr: None,
+            cookie_keys: None,
+            session: Rc::default(),
+            params: HashMap::new(),
+        }
+    }
+    
+    fn parse_cookies(cookie_header: &str, cookies: &mut HashMap<String, String>) {
+        for cookie in cookie_header.split(';') {
+            let cookie = cookie.trim();
+            if let Some(pos) = cookie.find('=') {
+                let name = cookie[..pos].trim().to_string();
+                let value = Cookie::decode_value(cookie[pos + 1..].trim());
+                cookies.insert(name, value);
+            }
+        }
+    }
+    
+    fn parse_query_string(query_string: &str) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        for param in query_string.split('&') {
+            if let Some(pos) = param.find('=') {
+                let key = Self::url_decode(&param[..pos]);
+                let value = Self::url_decode(&param[pos + 1..]);
+                params.insert(key, value);
+            } else {
+                params.insert(Self::url_decode(param), String::new());
+            }
+        }
+        params
+    }
+    
+    fn url_decode(encoded: &str) -> String {
+        // Decode into raw bytes first so multi-byte UTF-8 sequences such as
+        // "%C3%A9" come back as one character rather than two
+        let mut result = Vec::with_capacity(encoded.len());
+        let mut bytes = encoded.bytes();
         
-        <div class="endpoint">
-            <code>GET /health</code>
-            <p>Check server health status in JSON format</p>
-            <p><a href="/health">Visit /health</a></p>
-        </div>
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'%' => {
+                    if let (Some(h1), Some(h2)) = (bytes.next(), bytes.next()) {
+                        if let Ok(hex_str) = std::str::from_utf8(&[h1, h2]) {
+                            if let Ok(byte_val) = u8::from_str_radix(hex_str, 16) {
+                                result.push(byte_val);
+                            }
+                        }
+                    }
+                }
+                b'+' => result.push(b' '),
+                b => result.push(b),
+            }
+        }
         
-        <div class="endpoint">
-            <code>GET /api/users</code>
-            <p>Get user information with session management demonstration</p>
-            <p><a href="/api/users">Visit /api/users</a></p>
-        </div>
+        String::from_utf8_lossy(&result).into_owned()
+    }
+    
+    fn parse_form_data(content_type: &str, body: &[u8]) -> (HashMap<String, String>, HashMap<String, FormFile>) {
+        let mut fields = HashMap::new();
+        let mut files = HashMap::new();
         
-        <div class="endpoint">
-            <code>GET /inspect</code>
-            <p>Inspect HTTP request details (headers, cookies, query params, etc.)</p>
-            <p><a href="/inspect">Visit /inspect</a></p>
-        </div>
+        if content_type.contains("application/x-www-form-urlencoded") {
+            // Parse URL-encoded form data; the encoding keeps it ASCII-only
+            let body_str = String::from_utf8_lossy(body);
+            for param in body_str.split('&') {
+                if let Some(pos) = param.find('=') {
+                    let key = Self::url_decode(&param[..pos]);
+                    let value = Self::url_decode(&param[pos + 1..]);
+                    fields.insert(key, value);
+                }
+            }
+        } else if content_type.contains("multipart/form-data") {
+            // Extract boundary from content type
+            if let Some(boundary_start) = content_type.find("boundary=") {
+                let boundary = &content_type[boundary_start + 9..];
+                let boundary = if let Some(semicolon) = boundary.find(';') {
+                    &boundary[..semicolon]
+                } else {
+                    boundary
+                };
+                let boundary = boundary.trim().trim_matches('"');
+                
+                Self::parse_multipart(body, boundary, &mut fields, &mut files);
+            }
+        }
         
-        <div class="endpoint">
-            <code>GET/POST /form-test</code>
-            <p>Test form parsing with URL-encoded and multipart/form-data support</p>
-            <p><a href="/form-test">Visit /form-test</a></p>
-        </div>
+        (fields, files)
+    }
+    
+    /// Split a multipart body on its boundary without any text conversion,
+    /// so file parts keep the exact bytes the client sent
+    fn parse_multipart(
+        body: &[u8],
+        boundary: &str,
+        fields: &mut HashMap<String, String>,
+        files: &mut HashMap<String, FormFile>,
+    ) {
+        let first_marker = format!("--{}", boundary);
+        // Every boundary after the first is preceded by the CRLF that ends the previous part
+        let marker = format!("\r\n--{}", boundary);
         
-        <div class="endpoint">
-            <code>GET /download</code>
-            <p>Chunked transfer encoding demonstration for streaming responses</p>
-            <p><a href="/download">Visit /download</a></p>
-        </div>
+        let Some(first) = Self::find_bytes(body, first_marker.as_bytes(), 0) else {
+            return;
+        };
+        let mut pos = first + first_marker.len();
         
-        <div class="endpoint">
-            <code>GET /login</code>
-            <p>Session management demonstration with cookie options</p>
-            <p><a href="/login">Visit /login</a></p>
-        </div>
+        loop {
+            // "--" straight after a boundary marks the end of the body
+            if body[pos..].starts_with(b"--") {
+                break;
+            }
+            // Skip the rest of the boundary line
+            let Some(line_end) = Self::find_bytes(body, b"\r\n", pos) else {
+                break;
+            };
+            let part_start = line_end + 2;
+            let Some(part_end) = Self::find_bytes(body, marker.as_bytes(), part_start) else {
+                break;
+            };
+            Self::parse_part(&body[part_start..part_end], fields, files);
+            pos = part_end + marker.len();
+        }
+    }
+    
+    fn parse_part(
+        part: &[u8],
+        fields: &mut HashMap<String, String>,
+        files: &mut HashMap<String, FormFile>,
+    ) {
+        let Some(blank_line_pos) = Self::find_bytes(part, b"\r\n\r\n", 0) else {
+            return;
+        };
+        let headers_str = String::from_utf8_lossy(&part[..blank_line_pos]);
+        let content = &part[blank_line_pos + 4..];
         
-        <div class="endpoint">
-            <code>GET /static</code>
-            <p>Static file serving with automatic MIME type detection</p>
-            <p><a href="/static">Visit /static</a></p>
-        </div>
+        // Parse part headers
+        let mut field_name = String::new();
+        let mut filename = Option::<String>::None;
+        let mut content_type_part = String::from("text/plain");
         
-        <div class="endpoint">
-            <code>GET /api/*</code>
-            <p>Catch-all API endpoint for other paths</p>
-            <p><a href="/api/posts">Example: /api/posts</a></p>
-        </div>
+        for header_line in headers_str.lines() {
+            if let Some(colon_pos) = header_line.find(':') {
+                let header_name = header_line[..colon_pos].trim().to_lowercase();
+                let header_value = header_line[colon_pos + 1..].trim();
+                
+                if header_name == "content-disposition" {
+                    // Parse: form-data; name="field_name"; filename="file.txt"
+                    for param in header_value.split(';').skip(1) {
+                        let param = param.trim();
+                        if let Some(value) = param.strip_prefix("name=") {
+                            field_name = value.trim_matches('"').to_string();
+                        } else if let Some(value) = param.strip_prefix("filename=") {
+                            filename = Some(value.trim_matches('"').to_string());
+                        }
+                    }
+                } else if header_name == "content-type" {
+                    content_type_part = header_value.to_string();
+                }
+            }
+        }
         
-        <h2>✨ Features</h2>
-        <ul>
-            <li>⚡ High-performance epoll-based event loop (Linux)</li>
-            <li>🔍 Advanced HTTP parser with chunked encoding support</li>
-            <li>📝 Form data parsing (URL-encoded and multipart)</li>
-            <li>🍪 Cookie extraction and session management</li>
-            <li>📊 Query parameter parsing with URL decoding</li>
-            <li>📁 Static file serving with MIME type detection</li>
-            <li>🔗 Fluent API response builder</li>
-        </ul>
-    </div>
-</body>
-</html>"#;
+        // Store field or file
+        if let Some(filename) = filename {
+            files.insert(
+                field_name,
+                FormFile {
+                    filename,
+                    content_type: content_type_part,
+                    data: content.to_vec(),
+                },
+            );
+        } else {
+            fields.insert(field_name, String::from_utf8_lossy(content).into_owned());
+        }
+    }
+}
+
+type RouteHandler = Box<dyn Fn(&HttpRequest) -> HttpResponse>;
+
+/// Constraint on a path parameter, written `:name{type}`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParamKind {
+    U64,
+    I64,
+    Uuid,
+}
+
+impl ParamKind {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "u64" => Some(ParamKind::U64),
+            "i64" => Some(ParamKind::I64),
+            "uuid" => Some(ParamKind::Uuid),
+            _ => None,
+        }
+    }
+
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            ParamKind::U64 => value.parse::<u64>().is_ok(),
+            ParamKind::I64 => value.parse::<i64>().is_ok(),
+            ParamKind::Uuid => {
+                value.len() == 36
+                    && value.char_indices().all(|(i, c)| match i {
+                        8 | 13 | 18 | 23 => c == '-',
+                        _ => c.is_ascii_hexdigit(),
+                    })
+            }
+        }
+    }
+}
+
+/// One `/`-separated piece of a route pattern
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// Matches exactly this text
+    Static(String),
+    /// `:name` or `:name{type}`: captures one non-empty segment
+    Param { name: String, kind: Option<ParamKind> },
+    /// `*name`: captures the rest of the path, possibly empty; must come last
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Split a pattern such as "/users/:id{u64}/files/*rest". Patterns are
+    /// written in code, so a malformed one is a bug and panics at startup.
+    fn parse_pattern(pattern: &str) -> Vec<Segment> {
+        let invalid = |reason: &str| -> ! { panic!("invalid route pattern {:?}: {}", pattern, reason) };
+        if !pattern.starts_with('/') {
+            invalid("must start with /");
+        }
+        let parts: Vec<&str> = pattern.split('/').skip(1).collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix('*') {
+                if i + 1 != parts.len() {
+                    invalid("a wildcard must be the last segment");
+                }
+                Segment::Wildcard(name.to_string())
+            } else if let Some(param) = part.strip_prefix(':') {
+                let (name, kind) = match param.split_once('{') {
+                    Some((name, kind)) => {
+                        let kind = kind.strip_suffix('}').and_then(ParamKind::parse);
+                        (name, Some(kind.unwrap_or_else(|| invalid("unknown parameter type"))))
+                    }
+                    None => (param, None),
+                };
+                Segment::Param { name: name.to_string(), kind }
+            } else {
+                Segment::Static(part.to_string())
+            };
+            if matches!(&segment, Segment::Param { name, .. } | Segment::Wildcard(name) if name.is_empty()) {
+                invalid("parameters need a name");
+            }
+            segments.push(segment);
+        }
+        segments
+    }
+}
+
+struct Route {
+    /// The pattern as registered, for conflict messages
+    path: String,
+    handler: RouteHandler,
+    /// Group middleware followed by the route's own, outermost first
+    middleware: Vec<Rc<dyn Middleware>>,
+}
+
+impl Route {
+    /// Run `middleware` around this route's handler, inside any added before
+    fn wrap(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Rc::new(middleware));
+        self
+    }
+}
+
+/// A node of the routing tree. Literal children are keyed by their first
+/// segment and hold the whole run of literal segments leading to them, so
+/// "/api/v1/internal" is one edge until another route branches off it.
+#[derive(Default)]
+struct RouteNode {
+    /// Literal segments consumed on the way in from the parent
+    label: Vec<String>,
+    statics: HashMap<String, RouteNode>,
+    /// Parameter children: name, constraint and subtree, typed ones first
+    params: Vec<(String, Option<ParamKind>, RouteNode)>,
+    /// Trailing wildcard name and the route it leads to
+    wildcard: Option<(String, usize)>,
+    /// Route whose pattern ends at this node
+    route: Option<usize>,
+}
+
+impl RouteNode {
+    /// Add the route at index `route` under `segments`. Fails if another
+    /// route already matches exactly the same paths, or captures the same
+    /// position under a different name.
+    fn insert(&mut self, segments: &[Segment], route: usize) -> Result<(), usize> {
+        match segments.first() {
+            None => match self.route {
+                Some(existing) => Err(existing),
+                None => {
+                    self.route = Some(route);
+                    Ok(())
+                }
+            },
+            Some(Segment::Wildcard(name)) => match &self.wildcard {
+                Some((_, existing)) => Err(*existing),
+                None => {
+                    self.wildcard = Some((name.clone(), route));
+                    Ok(())
+                }
+            },
+            Some(Segment::Param { name, kind }) => {
+                let index = match self.params.iter().position(|(_, k, _)| k == kind) {
+                    Some(index) if self.params[index].0 != *name => {
+                        return Err(self.params[index].2.any_route().unwrap_or(route));
+                    }
+                    Some(index) => index,
+                    None => {
+                        self.params.push((name.clone(), *kind, RouteNode::default()));
+                        self.params.sort_by_key(|(_, kind, _)| kind.is_none());
+                        self.params.iter().position(|(_, k, _)| k == kind).unwrap_or(0)
+                    }
+                };
+                self.params[index].2.insert(&segments[1..], route)
+            }
+            Some(Segment::Static(first)) => {
+                let run: Vec<String> = segments
+                    .iter()
+                    .map_while(|segment| match segment {
+                        Segment::Static(text) => Some(text.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let child = self.statics.entry(first.clone()).or_insert_with(|| RouteNode {
+                    label: run.clone(),
+                    ..RouteNode::default()
+                });
+                let common = child.label.iter().zip(&run).take_while(|(a, b)| a == b).count();
+                if common < child.label.len() {
+                    // Split the edge where the new route branches off
+                    let mut tail = std::mem::take(child);
+                    child.label = tail.label.drain(..common).collect();
+                    child.statics.insert(tail.label[0].clone(), tail);
+                }
+                child.insert(&segments[common..], route)
+            }
+        }
+    }
+
+    /// Some route in this subtree, to name in a conflict
+    fn any_route(&self) -> Option<usize> {
+        self.route
+            .or(self.wildcard.as_ref().map(|(_, route)| *route))
+            .or_else(|| self.statics.values().find_map(RouteNode::any_route))
+            .or_else(|| self.params.iter().find_map(|(_, _, node)| node.any_route()))
+    }
+
+    /// Find the route for `path`, trying literal children, then parameters,
+    /// then the wildcard, and backtracking when a branch dead-ends
+    fn lookup(&self, path: &[&str], params: &mut Vec<(String, String)>) -> Option<usize> {
+        if let Some((segment, rest)) = path.split_first() {
+            if let Some(child) = self.statics.get(*segment) {
+                let label_matches = path.len() >= child.label.len()
+                    && child.label.iter().zip(path).all(|(label, segment)| label == segment);
+                if label_matches {
+                    if let Some(route) = child.lookup(&path[child.label.len()..], params) {
+                        return Some(route);
+                    }
+                }
+            }
+            if let Some(value) = StaticFiles::percent_decode(segment).filter(|value| !value.is_empty()) {
+                for (name, kind, child) in &self.params {
+                    if kind.is_some_and(|kind| !kind.accepts(&value)) {
+                        continue;
+                    }
+                    params.push((name.clone(), value.clone()));
+                    if let Some(route) = child.lookup(rest, params) {
+                        return Some(route);
+                    }
+                    params.pop();
+                }
+            }
+        } else if let Some(route) = self.route {
+            return Some(route);
+        }
+
+        let (name, route) = self.wildcard.as_ref()?;
+        let rest = StaticFiles::percent_decode(&path.join("/"))?;
+        if rest.split('/').any(is_dot_segment) {
+            return None;
+        }
+        params.push((name.clone(), rest));
+        Some(*route)
+    }
+}
+
+/// Routes in one tree per method, so lookup cost follows the depth of the
+/// path rather than the number of routes
+struct Router {
+    routes: Vec<Route>,
+    trees: HashMap<String, RouteNode>,
+    /// Runs around every request, including ones no route matches
+    middleware: Vec<Rc<dyn Middleware>>,
+}
+
+impl Router {
+    fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            trees: HashMap::new(),
+            middleware: Vec::new(),
+        }
+    }
     
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("text/html; charset=utf-8")
-        .body_text(html)
-        .build()
+    /// Run `middleware` around every request, inside any added before
+    fn wrap(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Rc::new(middleware));
+        self
+    }
+    
+    /// Register routes below `prefix` that share middleware
+    fn group(&mut self, prefix: &str) -> RouteGroup<'_> {
+        RouteGroup {
+            router: self,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            middleware: Vec::new(),
+        }
+    }
+    
+    /// Register a handler for a path pattern: literal segments, `:name`
+    /// parameters (optionally typed, as in `:id{u64}`) and a trailing
+    /// `*name` wildcard. Captures are available through `HttpRequest::param`.
+    /// Routes are written in code, so a conflicting one panics at startup.
+    fn register(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + 'static,
+    ) -> &mut Route {
+        let segments = Segment::parse_pattern(path);
+        match self.add(method, path, &segments, Box::new(handler)) {
+            Ok(index) => &mut self.routes[index],
+            Err(e) => panic!("{}", e),
+        }
+    }
+    
+    /// Register a handler for `prefix` and every path below it, split on
+    /// segment boundaries ("/static" matches "/static/a.css" but not "/statics").
+    /// The prefix is taken literally, so configured paths need no escaping.
+    fn register_prefix(
+        &mut self,
+        method: &str,
+        prefix: &str,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + 'static,
+    ) -> Result<(), String> {
+        let prefix = prefix.trim_end_matches('/');
+        let mut segments: Vec<Segment> = prefix.split('/').skip(1).map(|s| Segment::Static(s.to_string())).collect();
+        segments.push(Segment::Wildcard("path".to_string()));
+        self.add(method, &format!("{}/*path", prefix), &segments, Box::new(handler))
+            .map(|_| ())
+    }
+    
+    fn add(&mut self, method: &str, path: &str, segments: &[Segment], handler: RouteHandler) -> Result<usize, String> {
+        let index = self.routes.len();
+        let tree = self.trees.entry(method.to_string()).or_default();
+        tree.insert(segments, index).map_err(|existing| {
+            format!("route {} {} conflicts with {}", method, path, self.routes[existing].path)
+        })?;
+        self.routes.push(Route {
+            path: path.to_string(),
+            handler,
+            middleware: Vec::new(),
+        });
+        Ok(index)
+    }
+    
+    /// Index of the route for a method and path, filling in its parameters.
+    /// At the first segment where candidates differ, literal text beats a
+    /// typed parameter, which beats a plain one, which beats a wildcard.
+    fn find(&self, method: &str, path: &str, params: &mut Vec<(String, String)>) -> Option<usize> {
+        let path: Vec<&str> = path.split('/').skip(1).collect();
+        self.trees.get(method)?.lookup(&path, params)
+    }
+    
+    fn handle(&self, request: &mut HttpRequest) -> HttpResponse {
+        run_middleware(&self.middleware, request, |request| self.dispatch(request))
+    }
+
+    /// Dispatch a request. HEAD falls back to the GET route (the server drops
+    /// the body later, keeping its headers). A path routed only for other
+    /// methods gets 405, or for OPTIONS a 204, listing them in Allow.
+    fn dispatch(&self, request: &mut HttpRequest) -> HttpResponse {
+        let mut params = Vec::new();
+        let route = self.find(&request.method, &request.path, &mut params).or_else(|| {
+            if request.method == "HEAD" {
+                self.find("GET", &request.path, &mut params)
+            } else {
+                None
+            }
+        });
+        if let Some(index) = route {
+            request.params = params.into_iter().collect();
+            let route = &self.routes[index];
+            return run_middleware(&route.middleware, request, |request| (route.handler)(request));
+        }
+
+        let allowed = self.allowed_methods(&request.path);
+        if allowed.is_empty() {
+            return HttpResponse::new(404, "Not Found", &ErrorPages::not_found());
+        }
+        if request.method == "OPTIONS" {
+            let options = ResponseBuilder::new()
+                .status(204, "No Content")
+                .header("Allow", &allowed.join(", "))
+                .build();
+            // Answer as the path's routes would, so CORS preflights see their middleware
+            let route = allowed.iter().find_map(|method| self.find(method, &request.path, &mut Vec::new()));
+            return match route {
+                Some(index) => run_middleware(&self.routes[index].middleware, request, |_| options),
+                None => options,
+            };
+        }
+        Self::method_not_allowed(&allowed)
+    }
+
+    /// Methods `path` can be requested with, for an Allow header. "*" (as in
+    /// `OPTIONS *`) stands for the server as a whole.
+    fn allowed_methods(&self, path: &str) -> Vec<&str> {
+        let mut methods: Vec<&str> = self
+            .trees
+            .keys()
+            .filter(|method| path == "*" || self.find(method, path, &mut Vec::new()).is_some())
+            .map(|method| method.as_str())
+            .collect();
+        if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        if !methods.is_empty() && !methods.contains(&"OPTIONS") {
+            methods.push("OPTIONS");
+        }
+        methods.sort_unstable();
+        methods
+    }
+
+    fn method_not_allowed(allowed: &[&str]) -> HttpResponse {
+        let mut response = HttpResponse::new(405, "Method Not Allowed", &ErrorPages::method_not_allowed());
+        response.headers.insert("Allow", allowed.join(", "));
+        response
+    }
+}
+
+/// Routes registered through `Router::group`
+struct RouteGroup<'a> {
+    router: &'a mut Router,
+    prefix: String,
+    middleware: Vec<Rc<dyn Middleware>>,
+}
+
+impl RouteGroup<'_> {
+    /// Run `middleware` around every route in the group
+    fn wrap(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Rc::new(middleware));
+        self
+    }
+
+    /// Register `path` below the group's prefix
+    fn register(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + 'static,
+    ) -> &mut Route {
+        let route = self.router.register(method, &format!("{}{}", self.prefix, path), handler);
+        route.middleware.splice(0..0, self.middleware.iter().cloned());
+        route
+    }
+}
+
+/// Code that runs around route handlers. Hooks run outermost first on the
+/// way in and innermost first on the way out.
+trait Middleware {
+    /// Inspect or change the request. Returning a response skips the
+    /// handler, inner middleware and this layer's `after`; outer `after`
+    /// hooks still see it.
+    fn before(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    /// Inspect or replace the response
+    fn after(&self, _request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        response
+    }
+}
+
+fn run_middleware(
+    middleware: &[Rc<dyn Middleware>],
+    request: &mut HttpRequest,
+    handler: impl FnOnce(&mut HttpRequest) -> HttpResponse,
+) -> HttpResponse {
+    let mut ran = 0;
+    let mut response = None;
+    for layer in middleware {
+        response = layer.before(request);
+        if response.is_some() {
+            break;
+        }
+        ran += 1;
+    }
+    let response = response.unwrap_or_else(|| handler(request));
+    middleware[..ran]
+        .iter()
+        .rev()
+        .fold(response, |response, layer| layer.after(request, response))
+}
+
+/// Prints one line per request with the client, request line, status and
+/// size. Outermost, so it sees the response after conditional requests,
+/// ranges, compression and error pages have had their say.
+struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn after(&self, request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        let client = request.remote_addr.map(|addr| addr.to_string()).unwrap_or_else(|| "-".to_string());
+        let target = match &request.query_string {
+            Some(query) => format!("{}?{}", request.path, query),
+            None => request.path.clone(),
+        };
+        // The server drops a HEAD response's body after routing
+        let size = match response.body.len() {
+            _ if request.method == "HEAD" => "0".to_string(),
+            Some(len) => len.to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{} \"{} {} {}\" {} {}",
+            client, request.method, target, request.version, response.status, size
+        );
+        response
+    }
 }
 
-fn handle_health(_req: &HttpRequest) -> HttpResponse {
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("application/json")
-        .body_text(r#"{"status": "healthy", "timestamp": "2025-12-09T20:00:00Z"}"#)
-        .header("Cache-Control", "no-cache")
-        .build()
-}
+/// Tags each request with an X-Request-Id, keeping one the client sent,
+/// and echoes it on the response for correlating logs
+struct RequestId;
+
+impl Middleware for RequestId {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        if !request.headers.contains("X-Request-Id") {
+            if let Ok(id) = random_hex(8) {
+                request.headers.insert("X-Request-Id", id);
+            }
+        }
+        None
+    }
+
+    fn after(&self, request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+        if let Some(id) = request.header("X-Request-Id") {
+            response.headers.insert("X-Request-Id", id);
+        }
+        response
+    }
+}
+
+/// Cross-origin access for the listed origins ("*" for any): answers
+/// preflight requests and marks responses readable by those origins
+struct Cors {
+    origins: Vec<String>,
+    /// Methods a preflight may ask for
+    methods: Vec<String>,
+    /// Request headers a preflight may ask for, compared case-insensitively
+    headers: Vec<String>,
+    max_age: u64,
+}
+
+impl Cors {
+    /// Allow `origins` to use the CORS-safelisted methods and no extra headers
+    fn new(origins: &[&str]) -> Self {
+        Cors {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            headers: Vec::new(),
+            max_age: 600,
+        }
+    }
+
+    fn methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|method| method.to_string()).collect();
+        self
+    }
+
+    fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// The Access-Control-Allow-Origin value for the request, if allowed
+    fn allow_origin(&self, request: &HttpRequest) -> Option<String> {
+        let origin = request.header("Origin")?;
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*".to_string())
+        } else {
+            self.origins.iter().find(|allowed| *allowed == origin).cloned()
+        }
+    }
+
+    /// Whether a preflight's requested method and headers are all allowed
+    fn allows(&self, method: &str, headers: Option<&str>) -> bool {
+        let header_allowed = |name: &str| self.headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(name));
+        self.methods.iter().any(|allowed| allowed == method)
+            && headers
+                .into_iter()
+                .flat_map(|headers| headers.split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .all(header_allowed)
+    }
+}
+
+impl Middleware for Cors {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        let method = request.header("Access-Control-Request-Method")?;
+        if request.method != "OPTIONS" {
+            return None;
+        }
+        let origin = self.allow_origin(request)?;
+        let builder = ResponseBuilder::new()
+            .status(204, "No Content")
+            .header("Vary", "Origin");
+        // Answered without any grant, so the browser refuses the real request
+        if !self.allows(method, request.header("Access-Control-Request-Headers")) {
+            return Some(builder.build());
+        }
+        let mut builder = builder
+            .header("Access-Control-Allow-Origin", &origin)
+            .header("Access-Control-Allow-Methods", &self.methods.join(", "))
+            .header("Access-Control-Max-Age", &self.max_age.to_string());
+        if !self.headers.is_empty() {
+            builder = builder.header("Access-Control-Allow-Headers", &self.headers.join(", "));
+        }
+        Some(builder.build())
+    }
+
+    fn after(&self, request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+        if let Some(origin) = self.allow_origin(request) {
+            response.headers.insert("Access-Control-Allow-Origin", origin);
+            response.headers.append("Vary", "Origin");
+        }
+        response
+    }
+}
+
+/// Sends visitors without `key` in their session to a login page
+struct RequireSession {
+    key: String,
+    login: String,
+}
+
+impl RequireSession {
+    fn new(key: &str, login: &str) -> Self {
+        RequireSession {
+            key: key.to_string(),
+            login: login.to_string(),
+        }
+    }
+}
+
+impl Middleware for RequireSession {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        if request.session().get(&self.key).is_some() {
+            return None;
+        }
+        Some(
+            ResponseBuilder::new()
+                .status(303, "See Other")
+                .header("Location", &self.login)
+                .build(),
+        )
+    }
+}
+
+/// Allows each client address `limit` requests per `window`, answering 429
+/// beyond that
+struct RateLimit {
+    limit: u32,
+    window: Duration,
+    /// Client to the start of its current window and requests made in it
+    clients: RefCell<HashMap<std::net::IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    /// Clients tracked before idle ones are pruned
+    const PRUNE_AT: usize = 1024;
+
+    fn new(limit: u32, window: Duration) -> Self {
+        RateLimit {
+            limit,
+            window,
+            clients: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        let ip = request.remote_addr?.ip();
+        let mut clients = self.clients.borrow_mut();
+        if clients.len() >= Self::PRUNE_AT {
+            clients.retain(|_, (start, _)| start.elapsed() < self.window);
+        }
+        let (start, count) = clients.entry(ip).or_insert((Instant::now(), 0));
+        if start.elapsed() >= self.window {
+            *start = Instant::now();
+            *count = 0;
+        }
+        *count += 1;
+        if *count <= self.limit {
+            return None;
+        }
+        let retry_after = self.window.saturating_sub(start.elapsed()).as_secs().max(1);
+        Some(
+            ResponseBuilder::new()
+                .status(429, "Too Many Requests")
+                .header("Retry-After", &retry_after.to_string())
+                .content_type("text/plain; charset=utf-8")
+                .body_text("Too many requests, try again later\n")
+                .build(),
+        )
+    }
+}
+
+// Error page builder
+#[allow(dead_code)]
+struct ErrorPages;
+
+impl ErrorPages {
+    #[allow(dead_code)]
+    fn not_found() -> String {
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>404 Not Found</title>
+    <style>
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
+            margin: 0;
+            padding: 0;
+            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+            height: 100vh;
+            display: flex;
+            justify-content: center;
+            align-items: center;
+        }
+        .container {
+            text-align: center;
+            background: white;
+            padding: 50px;
+            border-radius: 10px;
+            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
+            max-width: 600px;
+        }
+        h1 {
+            color: #e74c3c;
+            font-size: 72px;
+            margin: 0;
+            font-weight: 700;
+        }
+        p {
+            color: #666;
+            font-size: 18px;
+            margin: 20px 0;
+        }
+        a {
+            display: inline-block;
+            margin-top: 20px;
+            padding: 12px 30px;
+            background: #667eea;
+            color: white;
+            text-decoration: none;
+            border-radius: 5px;
+            transition: background 0.3s;
+        }
+        a:hover {
+            background: #764ba2;
+        }
+        .error-details {
+            text-align: left;
+            background: #f5f5f5;
+            padding: 20px;
+            border-radius: 5px;
+            margin-top: 30px;
+            font-size: 14px;
+            color: #333;
+        }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>404</h1>
+        <p>The page you're looking for doesn't exist.</p>
+        <p>It might have been removed or the URL might be incorrect.</p>
+        <a href="/">Go Home</a>
+        <div class="error-details">
+            <strong>Error Details:</strong><br>
+            Resource not found on this server.
+        </div>
+    </div>
+</body>
+</html>"#
+            .to_string()
+    }
+
+    #[allow(dead_code)]
+    fn bad_request() -> String {
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>400 Bad Request</title>
+    <style>
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
+            margin: 0;
+            padding: 0;
+            background: linear-gradient(135deg, #f093fb 0%, #f5576c 100%);
+            height: 100vh;
+            display: flex;
+            justify-content: center;
+            align-items: center;
+        }
+        .container {
+            text-align: center;
+            background: white;
+            padding: 50px;
+            border-radius: 10px;
+            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
+            max-width: 600px;
+        }
+        h1 {
+            color: #f5576c;
+            font-size: 72px;
+            margin: 0;
+            font-weight: 700;
+        }
+        p {
+            color: #666;
+            font-size: 18px;
+            margin: 20px 0;
+        }
+        a {
+            display: inline-block;
+            margin-top: 20px;
+            padding: 12px 30px;
+            background: #f5576c;
+            color: white;
+            text-decoration: none;
+            border-radius: 5px;
+            transition: background 0.3s;
+        }
+        a:hover {
+            background: #f093fb;
+        }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>400</h1>
+        <p>Bad Request</p>
+        <p>The server cannot process your request due to a client error.</p>
+        <a href="/">Go Home</a>
+    </div>
+</body>
+</html>"#
+            .to_string()
+    }
+
+    #[allow(dead_code)]
+    fn internal_error() -> String {
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>500 Internal Server Error</title>
+    <style>
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
+            margin: 0;
+            padding: 0;
+            background: linear-gradient(135deg, #eb3349 0%, #f45c43 100%);
+            height: 100vh;
+            display: flex;
+            justify-content: center;
+            align-items: center;
+        }
+        .container {
+            text-align: center;
+            background: white;
+            padding: 50px;
+            border-radius: 10px;
+            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
+            max-width: 600px;
+        }
+        h1 {
+            color: #eb3349;
+            font-size: 72px;
+            margin: 0;
+            font-weight: 700;
+        }
+        p {
+            color: #666;
+            font-size: 18px;
+            margin: 20px 0;
+        }
+        a {
+            display: inline-block;
+            margin-top: 20px;
+            padding: 12px 30px;
+            background: #eb3349;
+            color: white;
+            text-decoration: none;
+            border-radius: 5px;
+            transition: background 0.3s;
+        }
+        a:hover {
+            background: #f45c43;
+        }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>500</h1>
+        <p>Internal Server Error</p>
+        <p>Something went wrong on our end. Please try again later.</p>
+        <a href="/">Go Home</a>
+    </div>
+</body>
+</html>"#
+            .to_string()
+    }
+
+    fn method_not_allowed() -> String {
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>405 Method Not Allowed</title>
+    <style>
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
+            margin: 0;
+            padding: 0;
+            background: linear-gradient(135deg, #fa709a 0%, #fee140 100%);
+            height: 100vh;
+            display: flex;
+            justify-content: center;
+            align-items: center;
+        }
+        .container {
+            text-align: center;
+            background: white;
+            padding: 50px;
+            border-radius: 10px;
+            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
+            max-width: 600px;
+        }
+        h1 {
+            color: #fa709a;
+            font-size: 72px;
+            margin: 0;
+            font-weight: 700;
+        }
+        p {
+            color: #666;
+            font-size: 18px;
+            margin: 20px 0;
+        }
+        a {
+            display: inline-block;
+            margin-top: 20px;
+            padding: 12px 30px;
+            background: #fa709a;
+            color: white;
+            text-decoration: none;
+            border-radius: 5px;
+            transition: background 0.3s;
+        }
+        a:hover {
+            background: #fee140;
+            color: #333;
+        }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>405</h1>
+        <p>Method Not Allowed</p>
+        <p>The HTTP method used is not supported for this resource.</p>
+        <a href="/">Go Home</a>
+    </div>
+</body>
+</html>"#
+            .to_string()
+    }
+
+    fn forbidden() -> String {
+        Self::page(
+            403,
+            "Forbidden",
+            "You don't have permission to access this resource.",
+            ("#cb2d3e", "#ef473a"),
+        )
+    }
+
+    fn request_timeout() -> String {
+        Self::page(
+            408,
+            "Request Timeout",
+            "The server timed out waiting for the rest of your request.",
+            ("#4facfe", "#00f2fe"),
+        )
+    }
+
+    fn uri_too_long() -> String {
+        Self::page(
+            414,
+            "URI Too Long",
+            "The request line is longer than the server is willing to read.",
+            ("#f7971e", "#ffd200"),
+        )
+    }
+
+    fn header_fields_too_large() -> String {
+        Self::page(
+            431,
+            "Request Header Fields Too Large",
+            "The request carries too many or too large header fields.",
+            ("#f7971e", "#ffd200"),
+        )
+    }
+
+    fn precondition_failed() -> String {
+        Self::page(
+            412,
+            "Precondition Failed",
+            "The resource has changed since the version your request was based on.",
+            ("#f7971e", "#ffd200"),
+        )
+    }
+
+    fn range_not_satisfiable() -> String {
+        Self::page(
+            416,
+            "Range Not Satisfiable",
+            "None of the requested byte ranges overlap the resource.",
+            ("#ee0979", "#ff6a00"),
+        )
+    }
+
+    fn payload_too_large() -> String {
+        Self::page(
+            413,
+            "Payload Too Large",
+            "The request body exceeds the size this server accepts.",
+            ("#ee0979", "#ff6a00"),
+        )
+    }
+
+    /// Shared layout for the less common error pages: the status code over a
+    /// short explanation on a two-colour gradient
+    fn page(code: u16, title: &str, message: &str, (primary, secondary): (&str, &str)) -> String {
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{code} {title}</title>
+    <style>
+        body {{
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
+            margin: 0;
+            padding: 0;
+            background: linear-gradient(135deg, {primary} 0%, {secondary} 100%);
+            height: 100vh;
+            display: flex;
+            justify-content: center;
+            align-items: center;
+        }}
+        .container {{
+            text-align: center;
+            background: white;
+            padding: 50px;
+            border-radius: 10px;
+            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
+            max-width: 600px;
+        }}
+        h1 {{
+            color: {primary};
+            font-size: 72px;
+            margin: 0;
+            font-weight: 700;
+        }}
+        p {{
+            color: #666;
+            font-size: 18px;
+            margin: 20px 0;
+        }}
+        a {{
+            display: inline-block;
+            margin-top: 20px;
+            padding: 12px 30px;
+            background: {primary};
+            color: white;
+            text-decoration: none;
+            border-radius: 5px;
+            transition: background 0.3s;
+        }}
+        a:hover {{
+            background: {secondary};
+        }}
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>{code}</h1>
+        <p>{title}</p>
+        <p>{message}</p>
+        <a href="/">Go Home</a>
+    </div>
+</body>
+</html>"#
+        )
+    }
+}
+
+// Route handlers
+fn handle_root(_req: &HttpRequest) -> HttpResponse {
+    let html = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Localhost Server</title>
+    <style>
+        body { font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; margin: 0; padding: 20px; background: linear-gradient(135deg, #667eea 0%, #764ba2 100%); min-height: 100vh; }
+        .container { max-width: 900px; margin: 0 auto; background: white; padding: 40px; border-radius: 10px; box-shadow: 0 10px 40px rgba(0,0,0,0.2); }
+        h1 { color: #667eea; margin-top: 0; }
+        h2 { color: #764ba2; margin-top: 30px; }
+        .endpoint { background: #f5f5f5; padding: 15px; margin: 10px 0; border-left: 4px solid #667eea; border-radius: 4px; }
+        .endpoint code { background: #e8e8ff; padding: 2px 6px; border-radius: 3px; font-weight: bold; }
+        .endpoint p { margin: 5px 0; color: #555; }
+        a { color: #667eea; text-decoration: none; font-weight: bold; }
+        a:hover { text-decoration: underline; }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>🚀 Welcome to Localhost Server</h1>
+        <p>A high-performance HTTP server built in Rust with epoll event loop and advanced HTTP parsing.</p>
+        
+        <h2>📍 Available Endpoints</h2>
+        
+        <div class="endpoint">
+            <code>GET /health</code>
+            <p>Check server health status in JSON format</p>
+            <p><a href="/health">Visit /health</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /api/users</code>
+            <p>Get user information with session management demonstration</p>
+            <p><a href="/api/users">Visit /api/users</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /api/users/:id{u64}</code>
+            <p>Route parameter with a type constraint; non-numeric ids fall through to the catch-all</p>
+            <p><a href="/api/users/42">Example: /api/users/42</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /inspect</code>
+            <p>Inspect HTTP request details (headers, cookies, query params, etc.)</p>
+            <p><a href="/inspect">Visit /inspect</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET/POST /form-test</code>
+            <p>Test form parsing with URL-encoded and multipart/form-data support</p>
+            <p><a href="/form-test">Visit /form-test</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /download</code>
+            <p>Chunked transfer encoding demonstration for streaming responses</p>
+            <p><a href="/download">Visit /download</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /login</code>
+            <p>Session management demonstration with cookie options</p>
+            <p><a href="/login">Visit /login</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /static</code>
+            <p>Static file serving with automatic MIME type detection</p>
+            <p><a href="/static">Visit /static</a></p>
+        </div>
+        
+        <div class="endpoint">
+            <code>GET /api/*path</code>
+            <p>Catch-all API endpoint for other paths</p>
+            <p><a href="/api/posts">Example: /api/posts</a></p>
+        </div>
+        
+        <h2>✨ Features</h2>
+        <ul>
+            <li>⚡ High-performance epoll-based event loop (Linux)</li>
+            <li>🔍 Advanced HTTP parser with chunked encoding support</li>
+            <li>📝 Form data parsing (URL-encoded and multipart)</li>
+            <li>🍪 Cookie extraction and session management</li>
+            <li>📊 Query parameter parsing with URL decoding</li>
+            <li>📁 Static file serving with MIME type detection</li>
+            <li>🔗 Fluent API response builder</li>
+        </ul>
+    </div>
+</body>
+</html>"#;
+    
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("text/html; charset=utf-8")
+        .body_text(html)
+        .etag_from_body()
+        .build()
+}
+
+fn handle_health(_req: &HttpRequest) -> HttpResponse {
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("application/json")
+        .body_text(r#"{"status": "healthy", "timestamp": "2025-12-09T20:00:00Z"}"#)
+        .header("Cache-Control", "no-cache")
+        .build()
+}
+
+fn handle_users(req: &HttpRequest) -> HttpResponse {
+    // Keep a verified session id, or issue a fresh random one
+    let jar = req.cookie_jar();
+    let existing = jar.get_signed("user_session");
+    let session = match (&existing, jar.get("user_session")) {
+        (Some(_), _) => "valid",
+        (None, Some(_)) => "invalid",
+        (None, None) => "new",
+    };
+    let session_id = existing.unwrap_or_else(|| random_hex(16).unwrap_or_default());
+    let body = format!(
+        r#"{{"path": "{}", "method": "{}", "session": "{}"}}"#,
+        req.path, req.method, session
+    );
+    let mut builder = ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("application/json")
+        .body_text(&body);
+    let cookie = Cookie::new("user_session", &session_id)
+        .path("/api")
+        .max_age(3600)
+        .http_only(true)
+        .same_site(SameSite::Lax);
+    if let Some(cookie) = jar.signed(cookie) {
+        builder = builder.set_cookie(&cookie);
+    }
+    builder.build()
+}
+
+fn handle_user(req: &HttpRequest) -> HttpResponse {
+    let id = req.param("id").unwrap_or_default();
+    let body = format!(r#"{{"id": {}, "name": "user{}"}}"#, id, id);
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("application/json")
+        .body_text(&body)
+        .build()
+}
+
+fn handle_api_catch_all(req: &HttpRequest) -> HttpResponse {
+    let body = format!(
+        r#"{{"message": "API endpoint", "path": "{}", "endpoint": "{}", "method": "{}", "timestamp": "2025-12-09T20:00:00Z"}}"#,
+        req.path,
+        json_escape(req.param("path").unwrap_or_default()),
+        req.method
+    );
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("application/json")
+        .body_text(&body)
+        .header("X-API-Version", "1.0")
+        .build()
+}
+
+fn handle_inspect(req: &HttpRequest) -> HttpResponse {
+    let mut body = String::from(r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Request Inspector</title>
+    <style>
+        body { font-family: monospace; margin: 20px; }
+        .section { margin: 20px 0; padding: 10px; background: #f5f5f5; border-left: 4px solid #667eea; }
+        h2 { color: #667eea; margin-top: 0; }
+        table { width: 100%; border-collapse: collapse; }
+        td { padding: 8px; border-bottom: 1px solid #ddd; }
+        td:first-child { font-weight: bold; color: #333; width: 20%; }
+        a { color: #667eea; text-decoration: none; }
+        a:hover { text-decoration: underline; }
+    </style>
+</head>
+<body>
+    <h1>Request Inspector</h1>
+"#);
+    
+    // Request line info
+    body.push_str(&format!(
+        r#"<div class="section">
+        <h2>Request Line</h2>
+        <table>
+            <tr><td>Method:</td><td>{}</td></tr>
+            <tr><td>Path:</td><td>{}</td></tr>
+            <tr><td>Query String:</td><td>{}</td></tr>
+            <tr><td>HTTP Version:</td><td>{}</td></tr>
+        </table>
+    </div>"#,
+        req.method,
+        req.path,
+        req.query_string.as_ref().unwrap_or(&"(none)".to_string()),
+        req.version
+    ));
+    
+    // Headers
+    if !req.headers.is_empty() {
+        body.push_str(r#"<div class="section">
+        <h2>Headers</h2>
+        <table>"#);
+        for (key, value) in req.headers.iter() {
+            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", key, value));
+        }
+        body.push_str("</table></div>");
+    }
+    
+    // Trailers (only present on chunked requests)
+    if !req.trailers.is_empty() {
+        body.push_str(r#"<div class="section">
+        <h2>Trailers</h2>
+        <table>"#);
+        for (key, value) in req.trailers.iter() {
+            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", key, value));
+        }
+        body.push_str("</table></div>");
+    }
+    
+    // Cookies
+    if !req.cookies.is_empty() {
+        body.push_str(r#"<div class="section">
+        <h2>Cookies</h2>
+        <table>"#);
+        for (name, value) in &req.cookies {
+            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", name, value));
+        }
+        body.push_str("</table></div>");
+    } else {
+        body.push_str(r#"<div class="section">
+        <h2>Cookies</h2>
+        <p>(no cookies)</p>
+    </div>"#);
+    }
+    
+    // Query Parameters
+    if !req.query_params.is_empty() {
+        body.push_str(r#"<div class="section">
+        <h2>Query Parameters</h2>
+        <table>"#);
+        for (key, value) in &req.query_params {
+            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", key, value));
+        }
+        body.push_str("</table></div>");
+    } else {
+        body.push_str(r#"<div class="section">
+        <h2>Query Parameters</h2>
+        <p>(no query parameters)</p>
+    </div>"#);
+    }
+    
+    body.push_str(r#"<div class="section">
+        <h2>Test Links</h2>
+        <p><a href="/inspect?name=John&age=30&city=NYC">With Query Params</a></p>
+    </div>
+    
+    <div class="section">
+        <h2>cURL Examples</h2>
+        <p>Test with cookies:<br>
+        <code>curl -b "session_id=abc123; user_id=42" http://localhost:8080/inspect</code></p>
+        <p>Test with query params:<br>
+        <code>curl "http://localhost:8080/inspect?key=value&name=test"</code></p>
+    </div>
+    
+    </body>
+</html>"#);
+    
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("text/html; charset=utf-8")
+        .body_text(&body)
+        .header("X-Inspector", "true")
+        .build()
+}
+
+fn handle_form_test(req: &HttpRequest) -> HttpResponse {
+    let mut body = String::from(r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Form Parser Test</title>
+    <style>
+        body { font-family: monospace; margin: 20px; background: #f5f5f5; }
+        .container { max-width: 800px; margin: 0 auto; background: white; padding: 20px; border-radius: 8px; }
+        h1 { color: #667eea; }
+        .section { margin: 20px 0; padding: 15px; background: #f9f9f9; border-left: 4px solid #667eea; }
+        h2 { color: #333; margin: 0 0 10px 0; }
+        table { width: 100%; border-collapse: collapse; }
+        td { padding: 8px; border-bottom: 1px solid #ddd; }
+        td:first-child { font-weight: bold; width: 25%; color: #667eea; }
+        code { background: #f0f0f0; padding: 2px 6px; border-radius: 3px; }
+        .form-section { background: white; padding: 20px; margin: 20px 0; border: 1px solid #ddd; border-radius: 8px; }
+        input, textarea { width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-family: monospace; }
+        button { background: #667eea; color: white; padding: 10px 20px; border: none; border-radius: 4px; cursor: pointer; margin-top: 10px; }
+        button:hover { background: #764ba2; }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>HTTP Parser Features Demo</h1>"#);
+    
+    body.push_str(r#"
+        <div class="section">
+            <h2>✅ Implemented Features</h2>
+            <ul>
+                <li><strong>Parse Request Line:</strong> Method, Path, Query String, HTTP Version</li>
+                <li><strong>Extract Cookies:</strong> Automatic parsing from Cookie header</li>
+                <li><strong>Query Parameters:</strong> URL decoding support</li>
+                <li><strong>Chunked Encoding:</strong> Automatic decoding of chunked transfer encoding</li>
+                <li><strong>Form Data:</strong> URL-encoded and multipart/form-data parsing</li>
+                <li><strong>Headers:</strong> All HTTP headers parsed and accessible</li>
+            </ul>
+        </div>"#);
+    
+    // Show current request details
+    body.push_str(r#"
+        <div class="section">
+            <h2>Current Request Info</h2>
+            <table>
+                <tr><td>Method:</td><td>"#);
+    body.push_str(&req.method);
+    body.push_str(r#"</td></tr>
+                <tr><td>Path:</td><td>"#);
+    body.push_str(&req.path);
+    body.push_str(r#"</td></tr>
+                <tr><td>HTTP Version:</td><td>"#);
+    body.push_str(&req.version);
+    body.push_str(r#"</td></tr>
+            </table>
+        </div>"#);
+    
+    // Form fields
+    if !req.form_fields.is_empty() {
+        body.push_str(r#"
+        <div class="section">
+            <h2>Form Fields Parsed</h2>
+            <table>"#);
+        for (name, value) in &req.form_fields {
+            body.push_str(&format!(
+                r#"<tr><td>{}:</td><td><code>{}</code></td></tr>"#,
+                name, value
+            ));
+        }
+        body.push_str("</table></div>");
+    }
+    
+    // Form files
+    if !req.form_files.is_empty() {
+        body.push_str(r#"
+        <div class="section">
+            <h2>Files Uploaded</h2>
+            <table>"#);
+        for (field_name, file) in &req.form_files {
+            body.push_str(&format!(
+                r#"<tr><td>{}:</td><td><code>{}</code> ({} bytes, type: {})</td></tr>"#,
+                field_name, file.filename, file.data.len(), file.content_type
+            ));
+        }
+        body.push_str("</table></div>");
+    }
+    
+    // Test forms
+    body.push_str(r#"
+        <div class="form-section">
+            <h2>📝 Test URL-Encoded Form</h2>
+            <form method="POST" action="/form-test" enctype="application/x-www-form-urlencoded">
+                <label>Username:</label><input type="text" name="username" value="john_doe">
+                <label>Email:</label><input type="email" name="email" value="john@example.com">
+                <label>Message:</label><textarea name="message">Hello from form!</textarea>
+                <button type="submit">Submit Form (URL-Encoded)</button>
+            </form>
+        </div>
+        
+        <div class="form-section">
+            <h2>📤 Test Multipart Form</h2>
+            <form method="POST" action="/form-test" enctype="multipart/form-data">
+                <label>Name:</label><input type="text" name="name" value="John Doe">
+                <label>File:</label><input type="file" name="upload">
+                <label>Description:</label><textarea name="description">File upload test</textarea>
+                <button type="submit">Submit Form (Multipart)</button>
+            </form>
+        </div>
+        
+        <div class="section">
+            <h2>cURL Examples</h2>
+            <p><strong>URL-Encoded POST:</strong><br>
+            <code>curl -X POST -d "username=john&email=john@example.com&message=hello" http://localhost:8080/form-test</code></p>
+            <p><strong>With Chunked Encoding:</strong><br>
+            <code>curl -X POST -H "Transfer-Encoding: chunked" -d "data=value" http://localhost:8080/form-test</code></p>
+        </div>
+    </div>
+</body>
+</html>"#);
+    
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("text/html; charset=utf-8")
+        .body_text(&body)
+        .header("X-Form-Parser", "enabled")
+        .build()
+}
+
+fn handle_download(_req: &HttpRequest) -> HttpResponse {
+    // Demonstrate chunked transfer encoding for streaming responses
+    let large_content = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Chunked Response Demo</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 20px; padding: 20px; background: #f5f5f5; }
+        .container { max-width: 800px; margin: 0 auto; background: white; padding: 20px; border-radius: 8px; }
+        h1 { color: #667eea; }
+        p { line-height: 1.6; color: #333; }
+        .highlight { background: #fff3cd; padding: 10px; border-left: 4px solid #ffc107; margin: 10px 0; }
+        code { background: #f0f0f0; padding: 2px 6px; border-radius: 3px; }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>📥 Chunked Transfer Encoding Demo</h1>
+        <p>This response was sent using <strong>Transfer-Encoding: chunked</strong>, allowing the server to stream large content without knowing the total size in advance.</p>
+        
+        <div class="highlight">
+            <strong>✨ Benefits of Chunked Encoding:</strong>
+            <ul>
+                <li>Stream responses without pre-calculating Content-Length</li>
+                <li>Ideal for dynamic or generated content</li>
+                <li>Enable HTTP/1.1 trailers</li>
+                <li>Support for gzip-like compression</li>
+                <li>Better for real-time data (SSE, WebSocket upgrades)</li>
+            </ul>
+        </div>
+        
+        <h2>How It Works</h2>
+        <p>With chunked encoding enabled, the response body is sent as a series of chunks:</p>
+        <code>[chunk size in hex]\r\n[chunk data]\r\n[next chunk size]\r\n[chunk data]\r\n0\r\n</code>
+        
+        <p>The ResponseBuilder handles the framing when you hand it an iterator of chunks; each chunk is only produced once the client is ready for it:</p>
+        <code>ResponseBuilder::new().stream(chunks).build()</code>
+        
+        <h2>Use Cases</h2>
+        <ul>
+            <li><strong>Server-Sent Events (SSE):</strong> Send real-time updates to clients</li>
+            <li><strong>Large File Downloads:</strong> Stream without buffering entire file</li>
+            <li><strong>API Responses:</strong> Generate large JSON responses on-the-fly</li>
+            <li><strong>Web Sockets Upgrade:</strong> Handshake mechanism uses chunked encoding</li>
+            <li><strong>Streaming Analytics:</strong> Send metrics as they're collected</li>
+        </ul>
+        
+        <p><strong>View the HTTP headers:</strong> Open Developer Tools (F12) → Network tab and check the response headers for <code>Transfer-Encoding: chunked</code></p>
+        
+        <h2>Generated Rows</h2>
+        <table>
+"#;
+    let footer = r#"        </table>
+    </div>
+</body>
+</html>"#;
+    
+    // The table is generated 100 rows per chunk as the socket drains
+    let rows = (0..50u64).map(|batch| {
+        let mut html = String::new();
+        for row in batch * 100..(batch + 1) * 100 {
+            html.push_str(&format!(
+                "            <tr><td>{}</td><td><code>{:016x}</code></td></tr>\n",
+                row,
+                row.wrapping_mul(0x9e3779b97f4a7c15)
+            ));
+        }
+        html.into_bytes()
+    });
+    let chunks = std::iter::once(large_content.as_bytes().to_vec())
+        .chain(rows)
+        .chain(std::iter::once(footer.as_bytes().to_vec()));
+    
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("text/html; charset=utf-8")
+        .stream(chunks)
+        .header("Cache-Control", "no-store")
+        .build()
+}
+
+fn handle_login(req: &HttpRequest) -> HttpResponse {
+    let jar = req.cookie_jar();
+    if req.method == "POST" {
+        let username = req.form_fields.get("username").map(|u| u.trim()).unwrap_or_default();
+        if username.is_empty() {
+            return ResponseBuilder::new()
+                .status(303, "See Other")
+                .header("Location", "/login")
+                .build();
+        }
+        // A fresh id on login, so one planted beforehand is worthless
+        let mut session = req.session();
+        session.renew();
+        session.insert("user", username);
+        session.insert("logged_in_at", format_http_date(SystemTime::now()));
+        let mut builder = ResponseBuilder::new()
+            .status(303, "See Other")
+            .header("Location", "/protected");
+        let remembered = Cookie::new("last_user", username)
+            .path("/login")
+            .max_age(2592000)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        if let Some(cookie) = jar.private(remembered) {
+            builder = builder.set_cookie(&cookie);
+        }
+        return builder.build();
+    }
+
+    // Demonstrate advanced cookie management for sessions
+    let mut html = String::from(r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Session Management Demo</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 20px; padding: 20px; background: linear-gradient(135deg, #667eea 0%, #764ba2 100%); min-height: 100vh; }
+        .container { max-width: 600px; margin: 0 auto; background: white; padding: 30px; border-radius: 10px; box-shadow: 0 10px 40px rgba(0,0,0,0.2); }
+        h1 { color: #667eea; margin-top: 0; }
+        .session-box { background: #f0f7ff; padding: 15px; border: 2px solid #667eea; border-radius: 8px; margin: 20px 0; }
+        .cookie-item { background: white; padding: 10px; margin: 10px 0; border-left: 4px solid #764ba2; }
+        code { background: #f5f5f5; padding: 2px 6px; border-radius: 3px; font-weight: bold; }
+        ul { line-height: 1.8; }
+        .note { background: #fff3cd; padding: 10px; border-radius: 4px; margin: 10px 0; }
+        input { padding: 8px; border: 1px solid #ddd; border-radius: 4px; width: 60%; }
+        button { background: #667eea; color: white; padding: 8px 16px; border: none; border-radius: 4px; cursor: pointer; }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>🔐 Session Management Demo</h1>
+        
+        <div class="session-box">
+"#);
+    match req.session().get("user") {
+        Some(user) => html.push_str(&format!(
+            r#"            <h2>Signed in as {}</h2>
+            <p><a href="/protected">Go to /protected</a></p>
+            <form method="POST" action="/logout"><button type="submit">Log out</button></form>
+"#,
+            html_escape(user)
+        )),
+        None => html.push_str(&format!(
+            r#"            <h2>Sign In</h2>
+            <form method="POST" action="/login">
+                <input name="username" placeholder="Any username" value="{}">
+                <button type="submit">Log in</button>
+            </form>
+"#,
+            html_escape(&jar.get_private("last_user").unwrap_or_default())
+        )),
+    }
+    html.push_str(r#"        </div>
+        
+        <div class="session-box">
+            <h2>Cookies Used by This Demo:</h2>
+            <div class="cookie-item">
+                <strong>sid:</strong> <code>random id, signed</code><br>
+                <small>HttpOnly, SameSite=Lax, Path: /. Only the id is sent; the session data stays on the server</small>
+            </div>
+            <div class="cookie-item">
+                <strong>last_user:</strong> <code>username, encrypted</code><br>
+                <small>HttpOnly, SameSite=Lax, Max-Age: 2592000 seconds (30 days), Path: /login</small>
+            </div>
+            <div class="cookie-item">
+                <strong>preferences:</strong> <code>theme=dark&lang=en, signed</code><br>
+                <small>SameSite=Lax, Max-Age: 2592000 seconds (30 days), Path: /</small>
+            </div>
+        </div>
+        
+        <h2>💡 How SessionManagement Works</h2>
+        <ul>
+            <li><strong>Session id:</strong> 128 bits from <code>/dev/urandom</code>, replaced on every login</li>
+            <li><strong>Expiry:</strong> Sessions end after 30 idle minutes or 24 hours, whichever comes first</li>
+            <li><strong>HttpOnly Flag:</strong> Prevents JavaScript access, protects against XSS attacks</li>
+            <li><strong>Path:</strong> Cookie scope (/ = entire domain)</li>
+            <li><strong>Secure Flag:</strong> Should be set in production (HTTPS only)</li>
+            <li><strong>SameSite:</strong> CSRF protection (Lax keeps cookies off cross-site POSTs)</li>
+            <li><strong>Signed:</strong> HMAC-SHA256 over name and value; tampered cookies read as missing</li>
+            <li><strong>Private:</strong> ChaCha20-encrypted and authenticated, unreadable by the client</li>
+        </ul>
+        
+        <div class="note">
+            <strong>✅ Recommended Practices:</strong>
+            <ul>
+                <li>Store sensitive data server-side, use session ID in cookie</li>
+                <li>Always use HttpOnly flag for session cookies</li>
+                <li>Use Secure flag in production (HTTPS only)</li>
+                <li>Issue a new session ID when privileges change</li>
+                <li>Expire idle sessions on the server</li>
+                <li>Implement logout to clear session cookies</li>
+            </ul>
+        </div>
+        
+        <h2>Implementation Example</h2>
+        <p>Using <code>HttpRequest::session()</code> in a handler:</p>
+        <code style="display: block; background: #f5f5f5; padding: 10px; border-radius: 4px; margin: 10px 0; overflow-x: auto;">
+let mut session = req.session();<br>
+session.renew();<br>
+session.insert("user", username);<br>
+ResponseBuilder::new()<br>
+&nbsp;&nbsp;.status(303, "See Other")<br>
+&nbsp;&nbsp;.header("Location", "/protected")<br>
+&nbsp;&nbsp;.build()
+        </code>
+        
+        <p>Next step: <a href="/protected" style="color: #667eea; font-weight: bold;">Visit /protected</a> to see session validation</p>
+    </div>
+</body>
+</html>"#);
+    
+    let preferences = Cookie::new("preferences", "theme=dark&lang=en")
+        .path("/")
+        .max_age(2592000)
+        .same_site(SameSite::Lax);
+    let mut builder = ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("text/html; charset=utf-8")
+        .body_text(&html);
+    if let Some(cookie) = jar.signed(preferences) {
+        builder = builder.set_cookie(&cookie);
+    }
+    builder
+        .header("X-Session-Demo", "true")
+        .build()
+}
+
+fn handle_protected(req: &HttpRequest) -> HttpResponse {
+    // RequireSession has already sent anonymous visitors to /login
+    let mut session = req.session();
+    let user = html_escape(session.get("user").unwrap_or_default());
+    let visits = session.get("visits").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0) + 1;
+    session.insert("visits", visits.to_string());
+    let logged_in_at = html_escape(session.get("logged_in_at").unwrap_or_default());
+
+    let html = format!(r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Protected Page</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 20px; padding: 20px; background: linear-gradient(135deg, #667eea 0%, #764ba2 100%); min-height: 100vh; }}
+        .container {{ max-width: 600px; margin: 0 auto; background: white; padding: 30px; border-radius: 10px; box-shadow: 0 10px 40px rgba(0,0,0,0.2); }}
+        h1 {{ color: #667eea; margin-top: 0; }}
+        .session-box {{ background: #f0f7ff; padding: 15px; border: 2px solid #667eea; border-radius: 8px; margin: 20px 0; }}
+        td {{ padding: 6px 12px 6px 0; }}
+        button {{ background: #764ba2; color: white; padding: 8px 16px; border: none; border-radius: 4px; cursor: pointer; }}
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>🛡️ Protected Page</h1>
+        <div class="session-box">
+            <table>
+                <tr><td><strong>User</strong></td><td>{}</td></tr>
+                <tr><td><strong>Logged in at</strong></td><td>{}</td></tr>
+                <tr><td><strong>Session started</strong></td><td>{}</td></tr>
+                <tr><td><strong>Visits this session</strong></td><td>{}</td></tr>
+            </table>
+        </div>
+        <form method="POST" action="/logout"><button type="submit">Log out</button></form>
+    </div>
+</body>
+</html>"#,
+        user,
+        logged_in_at,
+        if session.is_new() { "just now".to_string() } else { format_http_date(session.created()) },
+        visits
+    );
+
+    ResponseBuilder::new()
+        .status(200, "OK")
+        .content_type("text/html; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body_text(&html)
+        .build()
+}
+
+fn handle_logout(req: &HttpRequest) -> HttpResponse {
+    req.session().destroy();
+    ResponseBuilder::new()
+        .status(303, "See Other")
+        .header("Location", "/login")
+        .build()
+}
+
+fn handle_static(req: &HttpRequest, files: &StaticFiles) -> HttpResponse {
+    // Serve /static/* from the server root, with example.html as the directory index
+    let url_path = req.path.strip_prefix("/static").unwrap_or(&req.path);
+    let mut response = files.serve(req, url_path);
+    if response.status == 200 {
+        response.headers.insert("Cache-Control", "public, max-age=3600");
+    }
+    response
+}
+
+fn handle_cgi(req: &HttpRequest, script: &CgiScript) -> HttpResponse {
+    let cgi_path = &script.path;
+    
+    match CGIExecutor::execute(script, req) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("CGI execution error: {}", e);
+            ResponseBuilder::new()
+                .status(500, "Internal Server Error")
+                .content_type("text/html; charset=utf-8")
+                .body_text(&format!(
+                    r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>CGI Error</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 20px; background: #f5f5f5; }}
+        .error {{ background: #ffebee; padding: 20px; border-left: 4px solid #f44336; border-radius: 4px; }}
+        code {{ background: #f0f0f0; padding: 2px 6px; border-radius: 3px; }}
+    </style>
+</head>
+<body>
+    <div class="error">
+        <h1>CGI Execution Error</h1>
+        <p><strong>Error:</strong> <code>{}</code></p>
+        <p>Failed to execute CGI script at <code>{}</code></p>
+    </div>
+</body>
+</html>"#,
+                    e, cgi_path
+                ))
+                .build()
+        }
+    }
+}
+
+/// Store uploaded files in `upload_dir`: every file part of a multipart
+/// POST, or the raw body of a PUT under the last path segment
+fn handle_upload(req: &HttpRequest, upload_dir: &str) -> HttpResponse {
+    if let Err(e) = fs::create_dir_all(upload_dir) {
+        eprintln!("Error creating upload directory {}: {}", upload_dir, e);
+        return HttpResponse::new(500, "Internal Server Error", &ErrorPages::internal_error());
+    }
+
+    let uploads: Vec<(String, &[u8])> = if req.method == "PUT" {
+        vec![(req.path.rsplit('/').next().unwrap_or("").to_string(), &req.body[..])]
+    } else {
+        req.form_files
+            .values()
+            .map(|file| (file.filename.clone(), &file.data[..]))
+            .collect()
+    };
+    if uploads.is_empty() {
+        return HttpResponse::new(400, "Bad Request", &ErrorPages::bad_request());
+    }
+
+    let mut stored = Vec::new();
+    for (filename, data) in uploads {
+        // Keep only the final path component so uploads cannot escape the directory
+        let name = filename.rsplit(['/', '\\']).next().unwrap_or("");
+        if name.is_empty() || name == "." || name == ".." {
+            return HttpResponse::new(400, "Bad Request", &ErrorPages::bad_request());
+        }
+        let path = format!("{}/{}", upload_dir.trim_end_matches('/'), name);
+        if let Err(e) = fs::write(&path, data) {
+            eprintln!("Error writing upload {}: {}", path, e);
+            return HttpResponse::new(500, "Internal Server Error", &ErrorPages::internal_error());
+        }
+        stored.push(format!(r#"{{"filename": "{}", "size": {}}}"#, json_escape(name), data.len()));
+    }
+
+    ResponseBuilder::new()
+        .status(201, "Created")
+        .content_type("application/json")
+        .body_text(&format!(r#"{{"uploaded": [{}]}}"#, stored.join(", ")))
+        .build()
+}
+
+#[derive(Deserialize)]
+struct Config {
+    server: ServerConfig,
+    /// Virtual hosts; when empty a single server is built from `server.host`
+    /// and `server.port`
+    #[serde(default)]
+    servers: Vec<VirtualServerConfig>,
+    /// Keys for signed and private cookies
+    #[serde(default)]
+    cookies: CookieConfig,
+    /// Server-side session storage
+    #[serde(default)]
+    sessions: SessionConfig,
+    #[allow(dead_code)]
+    logging: LoggingConfig,
+}
+
+/// `[cookies]`: secrets for signed and private cookies, at least 32 bytes each
+#[derive(Deserialize, Default)]
+struct CookieConfig {
+    /// Secret used for new cookies
+    signing_key: Option<String>,
+    /// Retired secrets whose cookies are still accepted
+    #[serde(default)]
+    verify_keys: Vec<String>,
+}
+
+/// `[sessions]`: where sessions are kept and when they expire
+#[derive(Deserialize, Clone)]
+struct SessionConfig {
+    #[serde(default)]
+    store: SessionBackend,
+    /// Directory for the file store
+    #[serde(default = "default_session_dir")]
+    dir: String,
+    /// Name of the signed cookie carrying the session id
+    #[serde(default = "default_session_cookie_name")]
+    cookie_name: String,
+    /// A session unused for this long expires
+    #[serde(default = "default_session_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    /// A session expires this long after it was created, however active
+    #[serde(default = "default_session_absolute_timeout_secs")]
+    absolute_timeout_secs: u64,
+    /// How often expired sessions are swept from the store
+    #[serde(default = "default_session_sweep_interval_secs")]
+    sweep_interval_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            store: SessionBackend::default(),
+            dir: default_session_dir(),
+            cookie_name: default_session_cookie_name(),
+            idle_timeout_secs: default_session_idle_timeout_secs(),
+            absolute_timeout_secs: default_session_absolute_timeout_secs(),
+            sweep_interval_secs: default_session_sweep_interval_secs(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum SessionBackend {
+    #[default]
+    Memory,
+    File,
+}
+
+fn default_session_dir() -> String {
+    "sessions".to_string()
+}
+
+fn default_session_cookie_name() -> String {
+    "sid".to_string()
+}
+
+fn default_session_idle_timeout_secs() -> u64 {
+    1800
+}
+
+fn default_session_absolute_timeout_secs() -> u64 {
+    86400
+}
+
+fn default_session_sweep_interval_secs() -> u64 {
+    60
+}
+
+/// Process-wide settings shared by every virtual host
+#[derive(Deserialize)]
+struct ServerConfig {
+    #[serde(default = "default_host")]
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    timeout_ms: i32,
+    max_events: usize,
+    /// How long an idle persistent connection is kept open
+    #[serde(default = "default_keep_alive_timeout_ms")]
+    keep_alive_timeout_ms: u64,
+    /// Requests served on one connection before it is closed (0 = unlimited)
+    #[serde(default = "default_max_keep_alive_requests")]
+    max_keep_alive_requests: usize,
+    /// Time allowed to receive a request line and headers
+    #[serde(default = "default_header_timeout_ms")]
+    header_timeout_ms: u64,
+    /// Time allowed between two reads while receiving a request body
+    #[serde(default = "default_body_timeout_ms")]
+    body_timeout_ms: u64,
+    /// Time allowed between two writes while the client is not reading
+    #[serde(default = "default_write_timeout_ms")]
+    write_timeout_ms: u64,
+    /// Largest accepted request body, in bytes
+    #[serde(default = "default_client_max_body_size")]
+    client_max_body_size: usize,
+    /// Largest accepted request line plus header section, in bytes
+    #[serde(default = "default_max_header_bytes")]
+    max_header_bytes: usize,
+    /// Most header fields accepted in one request
+    #[serde(default = "default_max_headers")]
+    max_headers: usize,
+    /// Compress responses for clients that accept gzip or deflate
+    #[serde(default)]
+    compression: bool,
+    /// Smallest body worth compressing, in bytes
+    #[serde(default = "default_compression_min_length")]
+    compression_min_length: usize,
+    /// Largest body compressed on the fly, in bytes. Compression runs on the
+    /// event loop, so bigger bodies are sent as they are.
+    #[serde(default = "default_compression_max_length")]
+    compression_max_length: u64,
+    /// MIME types compressed; "text/*" matches a whole top-level type
+    #[serde(default = "default_compression_types")]
+    compression_types: Vec<String>,
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    8000
+}
+
+fn default_keep_alive_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_keep_alive_requests() -> usize {
+    100
+}
+
+fn default_header_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_body_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_write_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_client_max_body_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_header_bytes() -> usize {
+    8192
+}
+
+fn default_max_headers() -> usize {
+    100
+}
+
+fn default_compression_min_length() -> usize {
+    1024
+}
+
+fn default_compression_max_length() -> u64 {
+    256 * 1024
+}
+
+fn default_compression_types() -> Vec<String> {
+    ["text/*", "application/json", "application/javascript", "application/xml", "image/svg+xml"]
+        .iter()
+        .map(|t| t.to_string())
+        .collect()
+}
+
+/// One `[[servers]]` block: a virtual host
+#[derive(Deserialize, Clone)]
+struct VirtualServerConfig {
+    /// Addresses to accept connections on, as "host:port"
+    listen: Vec<String>,
+    /// Host header values this server answers to; "*.example.com" matches
+    /// any subdomain
+    #[serde(default)]
+    server_name: Vec<String>,
+    /// Directory files are served from
+    #[serde(default = "default_root")]
+    root: String,
+    /// Answer requests whose Host matches no server_name on the same address
+    #[serde(default)]
+    default: bool,
+    /// Status code to HTML file, e.g. `404 = "errors/404.html"`
+    #[serde(default)]
+    error_pages: HashMap<String, String>,
+    /// Register the built-in demo routes (/, /health, /api/...) next to the locations
+    #[serde(default = "default_builtin_routes")]
+    builtin_routes: bool,
+    /// `[[servers.location]]` blocks
+    #[serde(default)]
+    location: Vec<LocationConfig>,
+}
+
+fn default_root() -> String {
+    "static".to_string()
+}
+
+fn default_builtin_routes() -> bool {
+    true
+}
+
+/// One `[[servers.location]]` block: what to do with requests under a URL prefix
+#[derive(Deserialize, Clone, Debug)]
+struct LocationConfig {
+    /// URL prefix, matched on segment boundaries; the longest match wins
+    path: String,
+    /// Methods accepted by this location
+    #[serde(default = "default_location_methods")]
+    methods: Vec<String>,
+    /// Directory the full request path is resolved under (defaults to the server root)
+    root: Option<String>,
+    /// Directory that replaces the location prefix, instead of `root`
+    alias: Option<String>,
+    /// File served when a directory is requested
+    #[serde(default = "default_index")]
+    index: String,
+    /// List directory contents when there is no index file
+    #[serde(default)]
+    autoindex: bool,
+    /// Send every request here with a redirect instead of serving it
+    redirect: Option<String>,
+    #[serde(default = "default_redirect_code")]
+    redirect_code: u16,
+    /// File extension to interpreter, e.g. `".py" = "/usr/bin/python3"`; an
+    /// empty interpreter executes the script directly
+    #[serde(default)]
+    cgi: HashMap<String, String>,
+    /// Directory POST/PUT uploads are written to
+    upload_dir: Option<String>,
+    /// Override `[server] compression` for this location
+    compression: Option<bool>,
+    /// Override `[server] compression_min_length` for this location
+    compression_min_length: Option<usize>,
+}
+
+fn default_location_methods() -> Vec<String> {
+    vec!["GET".to_string()]
+}
+
+fn default_index() -> String {
+    "index.html".to_string()
+}
+
+fn default_redirect_code() -> u16 {
+    301
+}
+
+#[derive(Deserialize)]
+struct LoggingConfig {
+    #[allow(dead_code)]
+    level: String,
+    #[allow(dead_code)]
+    file: String,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+enum ServerError {
+    Io(io::Error),
+    Config(toml::de::Error),
+    InvalidConfig(String),
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ServerError {
+    fn from(err: toml::de::Error) -> ServerError {
+        ServerError::Config(err)
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Listening socket the connection was accepted on
+    listener_fd: RawFd,
+    buffer: Vec<u8>,
+    parser: HttpParser,
+    /// Requests answered so far on this connection
+    requests_served: usize,
+    /// When the first byte of the request being read arrived (or when the
+    /// connection was accepted, for the first request)
+    request_started: Instant,
+    /// Last time bytes were read from the client
+    last_read: Instant,
+    /// Last time bytes were written to the client
+    last_write: Instant,
+    /// Serialized responses not yet accepted by the socket: in-memory
+    /// pieces, file regions and streams, never `Body::Parts`
+    output: VecDeque<Body>,
+    /// How much of the front in-memory piece has already been written
+    write_offset: usize,
+    /// Cleared when sendfile(2) is unsupported for a file, after which file
+    /// regions are copied through a buffer instead
+    use_sendfile: bool,
+    /// Close once `output` has drained
+    close_after_write: bool,
+    /// Events currently registered with epoll for this socket
+    interest: u32,
+}
+
+/// What to do with a connection whose deadline has passed
+enum Expiry {
+    /// Answer 408 Request Timeout, then close
+    RequestTimeout,
+    /// Close without a response
+    Close,
+}
+
+impl Connection {
+    fn has_pending_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// Check the deadline that applies to the connection's current phase
+    fn expiry(&self, config: &ServerConfig, now: Instant) -> Option<Expiry> {
+        let past = |since: Instant, timeout_ms: u64| {
+            now.duration_since(since) >= Duration::from_millis(timeout_ms)
+        };
+
+        if self.has_pending_output() {
+            // The client stopped reading what we send
+            return past(self.last_write, config.write_timeout_ms).then_some(Expiry::Close);
+        }
+        if self.close_after_write {
+            return None;
+        }
+        if self.parser.in_body() {
+            return past(self.last_read, config.body_timeout_ms).then_some(Expiry::RequestTimeout);
+        }
+        if self.parser.is_idle() && self.buffer.is_empty() {
+            if self.requests_served == 0 {
+                // Nothing was ever sent: nobody to answer
+                return past(self.request_started, config.header_timeout_ms).then_some(Expiry::Close);
+            }
+            let idle_since = std::cmp::max(self.last_read, self.last_write);
+            return past(idle_since, config.keep_alive_timeout_ms).then_some(Expiry::Close);
+        }
+        // Part of a request line or header section has arrived
+        past(self.request_started, config.header_timeout_ms).then_some(Expiry::RequestTimeout)
+    }
+
+    /// Append a response to the outgoing buffer
+    fn queue_response(&mut self, response: HttpResponse) {
+        for segment in response.into_segments() {
+            self.queue_segment(segment);
+        }
+    }
+
+    fn queue_segment(&mut self, segment: Body) {
+        match (self.output.back_mut(), segment) {
+            (Some(Body::Bytes(previous)), Body::Bytes(bytes)) => previous.extend_from_slice(&bytes),
+            (_, segment) => self.output.push_back(segment),
+        }
+    }
+
+    /// Write as much pending output as the socket accepts without blocking.
+    /// While output is pending the socket is watched for EPOLLOUT only, so no
+    /// new requests are read until the client has caught up. Returns false
+    /// once the connection should be closed.
+    fn flush(&mut self, epoll_fd: RawFd) -> bool {
+        while let Some(front) = self.output.front_mut() {
+            let result = match front {
+                Body::Bytes(bytes) => {
+                    if self.write_offset == bytes.len() {
+                        self.output.pop_front();
+                        self.write_offset = 0;
+                        continue;
+                    }
+                    self.stream.write(&bytes[self.write_offset..]).inspect(|&n| self.write_offset += n)
+                }
+                Body::File { len: 0, .. } => {
+                    self.output.pop_front();
+                    continue;
+                }
+                Body::File { file, offset, len } => {
+                    if self.use_sendfile {
+                        Self::send_file(&self.stream, file, offset, len)
+                    } else {
+                        // Copy the next piece of the file into memory and
+                        // write it like any other buffered output
+                        match Self::read_file(file, offset, len) {
+                            Ok(data) => {
+                                self.output.push_front(Body::Bytes(data));
+                                continue;
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                }
+                Body::Stream(stream) => {
+                    // Pull the next chunk only now that there is room for it
+                    let framed = match stream.chunks.next() {
+                        Some(chunk) if chunk.is_empty() => continue,
+                        Some(chunk) if stream.chunked => {
+                            let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+                            framed.extend_from_slice(&chunk);
+                            framed.extend_from_slice(b"\r\n");
+                            framed
+                        }
+                        Some(chunk) => chunk,
+                        None => {
+                            let chunked = stream.chunked;
+                            self.output.pop_front();
+                            if chunked {
+                                self.output.push_front(Body::Bytes(b"0\r\n\r\n".to_vec()));
+                            }
+                            continue;
+                        }
+                    };
+                    self.output.push_front(Body::Bytes(framed));
+                    continue;
+                }
+                Body::Parts(_) => unreachable!("parts are flattened when queued"),
+            };
+            match result {
+                Ok(0) => return false,
+                Ok(_) => self.last_write = Instant::now(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return self.set_interest(epoll_fd, EPOLLOUT as u32).is_ok();
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if self.use_sendfile && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)) => {
+                    self.use_sendfile = false;
+                }
+                Err(e) => {
+                    eprintln!("Error writing to client: {}", e);
+                    return false;
+                }
+            }
+        }
+
+        if self.close_after_write {
+            return false;
+        }
+        self.set_interest(epoll_fd, EPOLLIN as u32).is_ok()
+    }
+
+    /// Send part of a file region with sendfile(2), advancing the region past
+    /// what the socket took. Returns 0 if the file ended early (it shrank
+    /// after the headers went out), which leaves the response unfinishable.
+    fn send_file(stream: &TcpStream, file: &fs::File, offset: &mut u64, len: &mut u64) -> io::Result<usize> {
+        let mut file_offset = *offset as libc::off_t;
+        let count = (*len).min(Self::SENDFILE_MAX) as usize;
+        let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut file_offset, count) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *offset += sent as u64;
+        *len -= sent as u64;
+        Ok(sent as usize)
+    }
+
+    /// Read up to `FALLBACK_CHUNK` bytes of a file region, advancing it
+    fn read_file(file: &fs::File, offset: &mut u64, len: &mut u64) -> io::Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        let mut data = vec![0; (*len).min(Self::FALLBACK_CHUNK) as usize];
+        let read = file.read_at(&mut data, *offset)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated while sending"));
+        }
+        data.truncate(read);
+        *offset += read as u64;
+        *len -= read as u64;
+        Ok(data)
+    }
+
+    /// Largest count passed to a single sendfile(2) call
+    const SENDFILE_MAX: u64 = 1 << 30;
+    /// Buffer size used when sendfile(2) is unavailable
+    const FALLBACK_CHUNK: u64 = 64 * 1024;
+
+    /// Change the epoll events watched for this socket
+    fn set_interest(&mut self, epoll_fd: RawFd, events: u32) -> io::Result<()> {
+        if self.interest == events {
+            return Ok(());
+        }
+        let fd = self.stream.as_raw_fd();
+        let mut event = epoll_event {
+            events,
+            u64: fd as u64,
+        };
+        unsafe {
+            if epoll_ctl(epoll_fd, EPOLL_CTL_MOD, fd, &mut event as *mut epoll_event) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.interest = events;
+        Ok(())
+    }
+}
+
+/// Serves files from beneath a document root
+struct StaticFiles {
+    root: String,
+    /// File served when a directory is requested
+    index: String,
+    /// List directories that have no index file
+    autoindex: bool,
+}
+
+impl StaticFiles {
+    fn new(root: &str, index: &str) -> Self {
+        StaticFiles {
+            root: root.to_string(),
+            index: index.to_string(),
+            autoindex: false,
+        }
+    }
+
+    fn with_autoindex(mut self, enable: bool) -> Self {
+        self.autoindex = enable;
+        self
+    }
+
+    /// Percent-decode a URL path and resolve "." and ".." segments.
+    /// Returns None when the path climbs above its starting point.
+    fn normalize(url_path: &str) -> Option<Vec<String>> {
+        let mut segments: Vec<String> = Vec::new();
+        for segment in url_path.split('/') {
+            let segment = Self::percent_decode(segment)?;
+            match segment.as_str() {
+                "" | "." => {}
+                ".." => {
+                    segments.pop()?;
+                }
+                _ => {
+                    // An encoded slash must not introduce a new segment
+                    if segment.contains('/') {
+                        return None;
+                    }
+                    segments.push(segment);
+                }
+            }
+        }
+        Some(segments)
+    }
+
+    /// Percent-decoding for paths: unlike form data, "+" stays a plus.
+    /// None for malformed escapes, NUL bytes or invalid UTF-8.
+    fn percent_decode(segment: &str) -> Option<String> {
+        let bytes = segment.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            } else {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+        if decoded.contains(&0) {
+            return None;
+        }
+        String::from_utf8(decoded).ok()
+    }
+
+    /// Map a URL path onto a file below the root. Fails with the response to
+    /// send when the path escapes the root or cannot be accessed.
+    fn resolve(&self, url_path: &str) -> Result<std::path::PathBuf, HttpResponse> {
+        let segments = Self::normalize(url_path).ok_or_else(Self::forbidden)?;
+        let mut path = std::path::PathBuf::from(&self.root);
+        path.extend(&segments);
+
+        // Symlinks may still lead outside the root
+        let root = fs::canonicalize(&self.root).map_err(|_| Self::not_found())?;
+        let real = fs::canonicalize(&path).map_err(|e| Self::io_error_response(&e))?;
+        if !real.starts_with(&root) {
+            return Err(Self::forbidden());
+        }
+        Ok(path)
+    }
+
+    fn serve(&self, req: &HttpRequest, url_path: &str) -> HttpResponse {
+        let path = match self.resolve(url_path) {
+            Ok(path) => path,
+            Err(response) => return response,
+        };
+
+        if path.is_dir() {
+            // Redirect "/dir" to "/dir/" so relative links inside resolve correctly
+            if !req.path.ends_with('/') {
+                let mut location = format!("{}/", req.path);
+                if let Some(query) = &req.query_string {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                return ResponseBuilder::new()
+                    .status(301, "Moved Permanently")
+                    .header("Location", &location)
+                    .content_type("text/html; charset=utf-8")
+                    .body_text(&format!(r#"<a href="{}">Moved</a>"#, html_escape(&location)))
+                    .build();
+            }
+            let index = path.join(&self.index);
+            if index.is_file() {
+                return self.serve_file(req, &index);
+            }
+            if self.autoindex {
+                return match DirectoryListing::read(&path, &req.path) {
+                    Ok(listing) => listing.into_response(req),
+                    Err(e) => Self::io_error_response(&e),
+                };
+            }
+            return Self::forbidden();
+        }
+
+        self.serve_file(req, &path)
+    }
+
+    /// Precompressed siblings looked for next to a file, best first
+    const SIDECARS: [(ContentCoding, &'static str); 2] = [(ContentCoding::Brotli, "br"), (ContentCoding::Gzip, "gz")];
+
+    /// Serve a file, or its precompressed `.br` / `.gz` sibling when one
+    /// exists and the client accepts that coding. Sidecars get the same
+    /// symlink check as `resolve` and are skipped when they leave the root.
+    fn serve_file(&self, req: &HttpRequest, path: &std::path::Path) -> HttpResponse {
+        let root = fs::canonicalize(&self.root).ok();
+        let sidecars: Vec<(ContentCoding, std::path::PathBuf)> = Self::SIDECARS
+            .iter()
+            .map(|(coding, extension)| {
+                let mut sidecar = path.as_os_str().to_owned();
+                sidecar.push(".");
+                sidecar.push(extension);
+                (*coding, std::path::PathBuf::from(sidecar))
+            })
+            .filter(|(_, sidecar)| match (&root, fs::canonicalize(sidecar)) {
+                (Some(root), Ok(real)) => real.starts_with(root) && real.is_file(),
+                _ => false,
+            })
+            .collect();
+        if sidecars.is_empty() {
+            return match ResponseBuilder::new().file(&path.to_string_lossy()) {
+                Ok(builder) => builder.status(200, "OK").build(),
+                Err(e) => Self::io_error_response(&e),
+            };
+        }
+
+        let offered: Vec<ContentCoding> = sidecars.iter().map(|(coding, _)| *coding).collect();
+        let chosen = req
+            .header("Accept-Encoding")
+            .and_then(|accept| Compression::negotiate(accept, &offered))
+            .and_then(|coding| sidecars.iter().find(|(c, _)| *c == coding));
+        let content_type = ResponseBuilder::get_content_type(&path.to_string_lossy());
+        let builder = match chosen {
+            Some((coding, sidecar)) => ResponseBuilder::new().file(&sidecar.to_string_lossy()).map(|builder| {
+                builder
+                    .content_type(&content_type)
+                    .header("Content-Encoding", coding.token())
+            }),
+            None => ResponseBuilder::new().file(&path.to_string_lossy()),
+        };
+        match builder {
+            Ok(builder) => builder.status(200, "OK").header("Vary", "Accept-Encoding").build(),
+            Err(e) => Self::io_error_response(&e),
+        }
+    }
+
+    fn io_error_response(error: &io::Error) -> HttpResponse {
+        match error.kind() {
+            io::ErrorKind::NotFound => Self::not_found(),
+            io::ErrorKind::PermissionDenied => Self::forbidden(),
+            _ => HttpResponse::new(500, "Internal Server Error", &ErrorPages::internal_error()),
+        }
+    }
+
+    fn not_found() -> HttpResponse {
+        HttpResponse::new(404, "Not Found", &ErrorPages::not_found())
+    }
+
+    fn forbidden() -> HttpResponse {
+        HttpResponse::new(403, "Forbidden", &ErrorPages::forbidden())
+    }
+}
+
+/// Byte ranges requested with a `Range: bytes=...` header, resolved against
+/// a representation of known length as inclusive (first, last) offsets
+struct ByteRanges;
+
+impl ByteRanges {
+    /// More ranges than this are treated as abuse and answered with the full body
+    const MAX_RANGES: usize = 32;
+
+    /// Parse a Range header value. `None` means the header should be
+    /// ignored (unknown unit or bad syntax), `Some(vec![])` that it is valid
+    /// but nothing in it is satisfiable.
+    fn parse(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        let (unit, specs) = header.split_once('=')?;
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+
+        let mut ranges = Vec::new();
+        for spec in specs.split(',') {
+            let spec = spec.trim();
+            if spec.is_empty() {
+                continue;
+            }
+            let (first, last) = spec.split_once('-')?;
+            let parse = |digits: &str| {
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                // Values too large for u64 are still syntactically valid
+                Some(digits.parse::<u64>().unwrap_or(u64::MAX))
+            };
+            let range = if first.is_empty() {
+                // Suffix range: the last N bytes
+                let suffix = parse(last)?;
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                (len.saturating_sub(suffix), len - 1)
+            } else {
+                let first = parse(first)?;
+                let last = if last.is_empty() { u64::MAX } else { parse(last)? };
+                if last < first {
+                    return None;
+                }
+                if first >= len {
+                    continue;
+                }
+                (first, last.min(len - 1))
+            };
+            ranges.push(range);
+        }
+        Some(Self::coalesce(ranges))
+    }
+
+    /// Sort and merge overlapping or adjacent ranges
+    fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+        ranges.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(previous) if first <= previous.1.saturating_add(1) => {
+                    previous.1 = previous.1.max(last);
+                }
+                _ => merged.push((first, last)),
+            }
+        }
+        merged
+    }
+
+    /// Turn a complete 200 response that advertises `Accept-Ranges: bytes`
+    /// into a 206 or 416 according to the request's Range and If-Range
+    /// headers. Anything else is returned unchanged.
+    fn apply(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        if response.status != 200 || !matches!(req.method.as_str(), "GET" | "HEAD") {
+            return response;
+        }
+        let accepts_ranges = response.headers.get("Accept-Ranges")
+            .map(|value| value.eq_ignore_ascii_case("bytes"))
+            .unwrap_or(false);
+        if !accepts_ranges {
+            return response;
+        }
+        let Some(header) = req.header("Range") else {
+            return response;
+        };
+        // A stale If-Range means the client's partial copy is useless: send it all
+        if let Some(if_range) = req.header("If-Range") {
+            if !Preconditions::if_range_matches(if_range, &response) {
+                return response;
+            }
+        }
+        let (Body::Bytes(_) | Body::File { .. }) = response.body else {
+            return response;
+        };
+        let Some(len) = response.body.len() else {
+            return response;
+        };
+        let ranges = match Self::parse(header, len) {
+            Some(ranges) if ranges.len() <= Self::MAX_RANGES => ranges,
+            _ => return response,
+        };
+
+        match ranges.as_slice() {
+            [] => {
+                let mut unsatisfiable = HttpResponse::new(416, "Range Not Satisfiable", &ErrorPages::range_not_satisfiable());
+                unsatisfiable.headers.insert("Content-Range", format!("bytes */{}", len));
+                unsatisfiable.headers.insert("Accept-Ranges", "bytes");
+                unsatisfiable
+            }
+            [(first, last)] => {
+                let mut partial = response;
+                let Some(body) = partial.body.slice(*first, *last) else {
+                    return partial;
+                };
+                partial.body = body;
+                partial.status = 206;
+                partial.status_text = "Partial Content".to_string();
+                partial.headers.insert("Content-Range", format!("bytes {}-{}/{}", first, last, len));
+                partial.headers.insert("Content-Length", (last - first + 1).to_string());
+                partial
+            }
+            _ => Self::multipart(response, &ranges),
+        }
+    }
+
+    /// Build a multipart/byteranges body with one part per range
+    fn multipart(mut response: HttpResponse, ranges: &[(u64, u64)]) -> HttpResponse {
+        let len = response.body.len().unwrap_or(0);
+        let content_type = response
+            .headers
+            .get("Content-Type")
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let boundary = format!("localhost-byteranges-{:x}", nanos);
+
+        let mut parts = Vec::new();
+        for &(first, last) in ranges {
+            let Some(data) = response.body.slice(first, last) else {
+                return response;
+            };
+            parts.push(Body::Bytes(
+                format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, first, last, len
+                )
+                .into_bytes(),
+            ));
+            parts.push(data);
+            parts.push(Body::Bytes(b"\r\n".to_vec()));
+        }
+        parts.push(Body::Bytes(format!("--{}--\r\n", boundary).into_bytes()));
+        let body = Body::Parts(parts);
+
+        response.status = 206;
+        response.status_text = "Partial Content".to_string();
+        response.headers.insert("Content-Type", format!("multipart/byteranges; boundary={}", boundary));
+        response.headers.insert("Content-Length", body.len().unwrap_or(0).to_string());
+        response.body = body;
+        response
+    }
+}
+
+impl Middleware for ByteRanges {
+    fn after(&self, request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        Self::apply(request, response)
+    }
+}
+
+/// Evaluation of conditional request headers (RFC 9110 section 13) against
+/// the validators a response carries. Responses without an ETag or
+/// Last-Modified header are left alone, so handlers opt in by setting one.
+struct Preconditions;
+
+impl Preconditions {
+    /// Strong validator for a file: changes whenever its size, mtime or inode does
+    fn file_etag(metadata: &fs::Metadata) -> String {
+        use std::os::unix::fs::MetadataExt;
+        let mtime_nanos = metadata.mtime() as u64 * 1_000_000_000 + metadata.mtime_nsec() as u64;
+        format!("\"{:x}-{:x}-{:x}\"", metadata.len(), mtime_nanos, metadata.ino())
+    }
+
+    /// Strong validator for an in-memory body (64-bit FNV-1a of its bytes)
+    fn body_etag(body: &[u8]) -> String {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &byte in body {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        format!("\"{:x}-{:016x}\"", body.len(), hash)
+    }
+
+    /// Answer with 304 or 412 when the request's preconditions say so;
+    /// otherwise return the response unchanged
+    fn evaluate(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        if !(200..300).contains(&response.status) {
+            return response;
+        }
+        let etag = response.headers.get("ETag").map(str::to_string);
+        let last_modified = response.headers.get("Last-Modified").and_then(parse_http_date);
+        if etag.is_none() && last_modified.is_none() {
+            return response;
+        }
+        let safe = matches!(req.method.as_str(), "GET" | "HEAD");
+
+        // Steps 1 and 2: the client's copy must still be current
+        if let Some(if_match) = req.header("If-Match") {
+            if !Self::etag_list_matches(if_match, etag.as_deref(), false) {
+                return Self::precondition_failed();
+            }
+        } else if let Some(since) = req.header("If-Unmodified-Since").and_then(parse_http_date) {
+            if last_modified.map(|modified| modified > since).unwrap_or(false) {
+                return Self::precondition_failed();
+            }
+        }
+
+        // Steps 3 and 4: the client's copy is already current
+        if let Some(if_none_match) = req.header("If-None-Match") {
+            if Self::etag_list_matches(if_none_match, etag.as_deref(), true) {
+                return if safe { Self::not_modified(response) } else { Self::precondition_failed() };
+            }
+        } else if safe {
+            if let Some(since) = req.header("If-Modified-Since").and_then(parse_http_date) {
+                if last_modified.map(|modified| modified <= since).unwrap_or(false) {
+                    return Self::not_modified(response);
+                }
+            }
+        }
+        response
+    }
+
+    /// Whether an If-Range value still describes the response: a strong
+    /// ETag match, or a date exactly equal to Last-Modified
+    fn if_range_matches(if_range: &str, response: &HttpResponse) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            let etag = response.headers.get("ETag");
+            return !if_range.starts_with("W/") && etag.map(|etag| etag == if_range).unwrap_or(false);
+        }
+        let last_modified = response.headers.get("Last-Modified").and_then(parse_http_date);
+        match (parse_http_date(if_range), last_modified) {
+            (Some(date), Some(modified)) => date == modified,
+            _ => false,
+        }
+    }
+
+    /// Match a comma-separated entity-tag list (or "*") against the current
+    /// ETag. Weak comparison ignores the W/ prefix; strong comparison never
+    /// matches a weak tag.
+    fn etag_list_matches(list: &str, current: Option<&str>, weak: bool) -> bool {
+        let Some(current) = current else {
+            return false;
+        };
+        if list.trim() == "*" {
+            return true;
+        }
+        let strip = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+        if !weak && current.starts_with("W/") {
+            return false;
+        }
+        let current = strip(current);
+        list.split(',').map(str::trim).filter(|tag| !tag.is_empty()).any(|tag| {
+            if !weak && tag.starts_with("W/") {
+                return false;
+            }
+            strip(tag) == current
+        })
+    }
+
+    /// A bodiless 304 keeping only the headers a cache needs to refresh its copy
+    fn not_modified(response: HttpResponse) -> HttpResponse {
+        const KEPT: [&str; 6] = ["ETag", "Last-Modified", "Cache-Control", "Vary", "Expires", "Content-Location"];
+        let mut headers = response.headers;
+        headers.retain(|key, _| KEPT.iter().any(|kept| key.eq_ignore_ascii_case(kept)));
+        HttpResponse {
+            status: 304,
+            status_text: "Not Modified".to_string(),
+            headers,
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    fn precondition_failed() -> HttpResponse {
+        HttpResponse::new(412, "Precondition Failed", &ErrorPages::precondition_failed())
+    }
+}
+
+impl Middleware for Preconditions {
+    fn after(&self, request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        Self::evaluate(request, response)
+    }
+}
+
+/// Content codings a response body can be sent with
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ContentCoding {
+    /// Only ever served from precompressed `.br` files
+    Brotli,
+    Gzip,
+    /// zlib-wrapped DEFLATE, which is what "deflate" means in HTTP
+    Deflate,
+}
+
+impl ContentCoding {
+    /// Name used in Accept-Encoding and Content-Encoding
+    fn token(self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Negotiated response compression, configured per server and location
+struct Compression {
+    enabled: bool,
+    min_length: usize,
+    /// Larger bodies are sent uncompressed rather than stall the event loop;
+    /// precompressed sidecars cover large static assets instead
+    max_length: u64,
+    types: Vec<String>,
+}
+
+impl Compression {
+    /// Types that are already compressed, never compressed again even if
+    /// a configured pattern covers them
+    const PRECOMPRESSED: [&'static str; 12] = [
+        "image/png", "image/jpeg", "image/gif", "image/webp", "image/avif",
+        "application/zip", "application/gzip", "application/x-gzip", "application/pdf",
+        "font/woff", "font/woff2", "application/octet-stream",
+    ];
+
+    fn from_config(config: &ServerConfig) -> Self {
+        Compression {
+            enabled: config.compression,
+            min_length: config.compression_min_length,
+            max_length: config.compression_max_length,
+            types: config.compression_types.clone(),
+        }
+    }
+
+    /// Whether responses of this Content-Type are worth compressing
+    fn compressible(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if Self::PRECOMPRESSED.contains(&mime.as_str()) || mime.starts_with("video/") || mime.starts_with("audio/") {
+            return false;
+        }
+        self.types.iter().any(|pattern| match pattern.strip_suffix("/*") {
+            Some(top) => mime.split('/').next() == Some(top),
+            None => pattern.eq_ignore_ascii_case(&mime),
+        }) || mime.ends_with("+json")
+            || mime.ends_with("+xml")
+    }
+
+    /// Pick one of `offered` from an Accept-Encoding value. Codings with
+    /// q=0 are refused and "*" covers codings not listed; on equal q the
+    /// earlier entry of `offered` wins.
+    fn negotiate(accept_encoding: &str, offered: &[ContentCoding]) -> Option<ContentCoding> {
+        let mut listed: Vec<(String, f32)> = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let mut q = 1.0f32;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse().unwrap_or(0.0);
+                    }
+                }
+            }
+            let coding = if coding == "x-gzip" { "gzip".to_string() } else { coding };
+            listed.push((coding, q));
+        }
+        let quality = |name: &str| listed.iter().find(|(coding, _)| coding == name).map(|(_, q)| *q);
+        let any = quality("*");
+
+        let mut best: Option<(ContentCoding, f32)> = None;
+        for &coding in offered {
+            let q = quality(coding.token()).or(any).unwrap_or(0.0);
+            if q > 0.0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((coding, q));
+            }
+        }
+        best.map(|(coding, _)| coding)
+    }
+
+    /// Decide how `apply` treats a response, before anything is encoded
+    fn plan(&self, req: &HttpRequest, response: &HttpResponse) -> CompressionPlan {
+        let skip = CompressionPlan { vary: false, coding: None };
+        if !self.enabled || !(200..300).contains(&response.status) || matches!(response.status, 204 | 206) {
+            return skip;
+        }
+        if response.headers.contains("Content-Encoding") {
+            return skip;
+        }
+        let no_transform = response
+            .headers
+            .get_all("Cache-Control")
+            .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+        let compressible = response
+            .headers
+            .get("Content-Type")
+            .map(|content_type| self.compressible(content_type))
+            .unwrap_or(false);
+        let len = match (&response.body, response.body.len()) {
+            (Body::Bytes(_) | Body::File { .. }, Some(len)) => len,
+            _ => return skip,
+        };
+        if no_transform || !compressible || len < self.min_length as u64 || len > self.max_length {
+            return skip;
+        }
+
+        // Range requests keep the identity bytes for `ByteRanges` to slice
+        let ranged = req.header("Range").is_some() && response.headers.contains("Accept-Ranges");
+        let offered = [ContentCoding::Gzip, ContentCoding::Deflate];
+        CompressionPlan {
+            vary: true,
+            coding: req
+                .header("Accept-Encoding")
+                .filter(|_| !ranged)
+                .and_then(|accept| Self::negotiate(accept, &offered)),
+        }
+    }
+
+    /// Compress a response when it is large enough, of a compressible type
+    /// and the client accepts a coding we support. Responses that could be
+    /// compressed always get `Vary: Accept-Encoding`, so caches keep the
+    /// variants apart.
+    fn apply(&self, req: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+        let plan = self.plan(req, &response);
+        if plan.vary {
+            Self::add_vary(&mut response, "Accept-Encoding");
+        }
+        let Some(coding) = plan.coding else {
+            return response;
+        };
+        let data = match Self::read_body(&response.body) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error reading body to compress: {}", e);
+                return response;
+            }
+        };
+        let encoded = match Self::encode(coding, &data) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                eprintln!("Error compressing body: {}", e);
+                return response;
+            }
+        };
+        if encoded.len() >= data.len() {
+            return response;
+        }
+
+        response.headers.insert("Content-Encoding", coding.token());
+        response.headers.insert("Content-Length", encoded.len().to_string());
+        // Byte ranges of the encoded form are not offered, and the encoded
+        // bytes differ from the identity ones, so a strong ETag becomes weak
+        response.headers.remove("Accept-Ranges");
+        Self::weaken_etag(&mut response);
+        response.body = Body::Bytes(encoded);
+        response
+    }
+
+    /// `data` in a gzip member or zlib stream
+    fn encode(coding: ContentCoding, data: &[u8]) -> io::Result<Vec<u8>> {
+        let level = flate2::Compression::default();
+        match coding {
+            ContentCoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentCoding::Deflate => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentCoding::Brotli => Err(io::Error::other("brotli is only served from precompressed files")),
+        }
+    }
+
+    fn weaken_etag(response: &mut HttpResponse) {
+        if let Some(etag) = response.headers.get_mut("ETag") {
+            if !etag.starts_with("W/") {
+                etag.insert_str(0, "W/");
+            }
+        }
+    }
+
+    fn read_body(body: &Body) -> io::Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        match body {
+            Body::Bytes(bytes) => Ok(bytes.clone()),
+            Body::File { file, offset, len } => {
+                let mut data = vec![0; *len as usize];
+                file.read_exact_at(&mut data, *offset)?;
+                Ok(data)
+            }
+            _ => Err(io::Error::other("body cannot be buffered")),
+        }
+    }
+
+    /// Add a field name to the Vary header unless it is already listed
+    fn add_vary(response: &mut HttpResponse, field: &str) {
+        let listed = response
+            .headers
+            .get_all("Vary")
+            .flat_map(|value| value.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(field) || v.trim() == "*");
+        if !listed {
+            response.headers.append("Vary", field);
+        }
+    }
+}
+
+/// What `Compression::apply` does with a response
+struct CompressionPlan {
+    /// The response depends on Accept-Encoding
+    vary: bool,
+    /// Coding the client gets, if any
+    coding: Option<ContentCoding>,
+}
+
+/// Compression settings of a server block and its locations. Runs as the
+/// innermost response middleware, so preconditions and ranges are evaluated
+/// against the variant actually sent and a 304 carries its Vary and ETag.
+struct LocationCompression {
+    /// Compression outside any location
+    default: Compression,
+    /// Location prefix to its compression settings
+    locations: Vec<(String, Compression)>,
+}
+
+impl LocationCompression {
+    fn new(server: &ServerConfig, locations: &[LocationConfig]) -> Self {
+        let default = Compression::from_config(server);
+        let locations = locations
+            .iter()
+            .map(|location| {
+                let settings = Compression {
+                    enabled: location.compression.unwrap_or(default.enabled),
+                    min_length: location.compression_min_length.unwrap_or(default.min_length),
+                    max_length: default.max_length,
+                    types: default.types.clone(),
+                };
+                (location.path.trim_end_matches('/').to_string(), settings)
+            })
+            .collect();
+        LocationCompression { default, locations }
+    }
+
+    /// Settings of the longest location containing `path`
+    fn for_path(&self, path: &str) -> &Compression {
+        self.locations
+            .iter()
+            .filter(|(prefix, _)| {
+                path == prefix || (path.starts_with(prefix.as_str()) && path[prefix.len()..].starts_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, settings)| settings)
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Middleware for LocationCompression {
+    fn after(&self, request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        self.for_path(&request.path).apply(request, response)
+    }
+}
+
+/// One entry of an autoindex listing
+struct ListingEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Contents of a directory rendered for autoindex, as HTML or JSON
+struct DirectoryListing {
+    /// URL path of the directory, ending in "/"
+    url_path: String,
+    entries: Vec<ListingEntry>,
+}
+
+/// Column an autoindex listing is sorted by, from the "C" query parameter
+#[derive(Clone, Copy, PartialEq)]
+enum SortColumn {
+    Name,
+    Size,
+    Modified,
+}
+
+impl DirectoryListing {
+    fn read(dir: &std::path::Path, url_path: &str) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            // Skip entries that vanish or cannot be stat'ed (e.g. broken symlinks)
+            let Ok(metadata) = fs::metadata(entry.path()) else {
+                continue;
+            };
+            entries.push(ListingEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            });
+        }
+        Ok(DirectoryListing {
+            url_path: url_path.to_string(),
+            entries,
+        })
+    }
+
+    /// Sort by the column and order requested as `?C=N|S|M&O=A|D`
+    /// (directories always first) and render as JSON when the client asks
+    /// for it, HTML otherwise
+    fn into_response(mut self, req: &HttpRequest) -> HttpResponse {
+        let column = match req.query_params.get("C").map(|c| c.as_str()) {
+            Some("S") => SortColumn::Size,
+            Some("M") => SortColumn::Modified,
+            _ => SortColumn::Name,
+        };
+        let descending = req.query_params.get("O").map(|o| o == "D").unwrap_or(false);
+        self.entries.sort_by(|a, b| {
+            let order = match column {
+                SortColumn::Name => a.name.cmp(&b.name),
+                SortColumn::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+                SortColumn::Modified => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)),
+            };
+            b.is_dir.cmp(&a.is_dir).then(if descending { order.reverse() } else { order })
+        });
+
+        let wants_json = req
+            .header("Accept")
+            .map(|accept| accept.contains("application/json"))
+            .unwrap_or(false);
+        let (content_type, body) = if wants_json {
+            ("application/json", self.to_json())
+        } else {
+            ("text/html; charset=utf-8", self.to_html(column, descending))
+        };
+        ResponseBuilder::new()
+            .status(200, "OK")
+            .content_type(content_type)
+            .header("Vary", "Accept")
+            .body_text(&body)
+            .build()
+    }
+
+    fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let modified = entry
+                    .modified
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                format!(
+                    r#"{{"name": "{}", "type": "{}", "size": {}, "modified": "{}", "modified_unix": {}}}"#,
+                    json_escape(&entry.name),
+                    if entry.is_dir { "directory" } else { "file" },
+                    entry.size,
+                    format_iso8601(entry.modified),
+                    modified
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"path": "{}", "entries": [{}]}}"#,
+            json_escape(&self.url_path),
+            entries.join(", ")
+        )
+    }
+
+    fn to_html(&self, column: SortColumn, descending: bool) -> String {
+        let title = html_escape(&self.url_path);
+        // Clicking the active column flips its order, any other column sorts ascending
+        let header = |label: &str, key: &str, this: SortColumn| {
+            let order = if this == column && !descending { "D" } else { "A" };
+            let arrow = match (this == column, descending) {
+                (true, false) => " &#9650;",
+                (true, true) => " &#9660;",
+                _ => "",
+            };
+            format!(r#"<th><a href="?C={}&amp;O={}">{}{}</a></th>"#, key, order, label, arrow)
+        };
+
+        let mut rows = String::new();
+        if self.url_path != "/" {
+            rows.push_str(r#"<tr><td><a href="../">../</a></td><td>-</td><td>-</td></tr>"#);
+        }
+        for entry in &self.entries {
+            let suffix = if entry.is_dir { "/" } else { "" };
+            rows.push_str(&format!(
+                r#"<tr><td><a href="{}{}">{}{}</a></td><td>{}</td><td>{}</td></tr>"#,
+                html_escape(&url_encode_path_segment(&entry.name)),
+                suffix,
+                html_escape(&entry.name),
+                suffix,
+                if entry.is_dir { "-".to_string() } else { format_size(entry.size) },
+                format_iso8601(entry.modified).replace('T', " ").trim_end_matches('Z'),
+            ));
+        }
+
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Index of {title}</title>
+    <style>
+        body {{
+            font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif;
+            margin: 0;
+            padding: 20px;
+            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+            min-height: 100vh;
+        }}
+        .container {{
+            max-width: 900px;
+            margin: 0 auto;
+            background: white;
+            padding: 40px;
+            border-radius: 10px;
+            box-shadow: 0 10px 40px rgba(0, 0, 0, 0.2);
+        }}
+        h1 {{ color: #667eea; margin-top: 0; word-break: break-all; }}
+        table {{ width: 100%; border-collapse: collapse; }}
+        th, td {{ padding: 8px; border-bottom: 1px solid #ddd; text-align: left; }}
+        th a {{ color: #764ba2; }}
+        td:nth-child(2), th:nth-child(2) {{ text-align: right; white-space: nowrap; }}
+        td:nth-child(3) {{ white-space: nowrap; color: #666; }}
+        a {{ color: #667eea; text-decoration: none; }}
+        a:hover {{ text-decoration: underline; }}
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>Index of {title}</h1>
+        <table>
+            <tr>{}{}{}</tr>
+            {rows}
+        </table>
+    </div>
+</body>
+</html>"#,
+            header("Name", "N", SortColumn::Name),
+            header("Size", "S", SortColumn::Size),
+            header("Last Modified", "M", SortColumn::Modified),
+        )
+    }
+}
+
+/// Human-readable file size for listings
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// "." and ".." name the current and parent directory, so a path holding
+/// them can escape the prefix it was matched against
+fn is_dot_segment(segment: &str) -> bool {
+    segment == "." || segment == ".."
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode a file name for use as one URL path segment
+fn url_encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Split seconds since the Unix epoch into UTC calendar fields:
+/// (year, month, day, hour, minute, second, weekday with 0 = Sunday)
+fn civil_from_unix(secs: u64) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Howard Hinnant's days-from-civil inverse
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let weekday = (days + 4).rem_euclid(7) as u32;
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+        weekday,
+    )
+}
+
+/// Inverse of `civil_from_unix`: days since the Unix epoch for a UTC date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// IMF-fixdate as used in HTTP headers: "Sun, 06 Nov 1994 08:49:37 GMT"
+fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day, hour, minute, second, weekday) = civil_from_unix(secs);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[weekday as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Parse an HTTP-date in any of the three formats recipients must accept:
+/// IMF-fixdate, the obsolete RFC 850 form and asctime()
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = match fields.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, time, "GMT"] => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+            let year = year.parse::<i64>().ok()?;
+            // Two-digit years more than 50 years in the future are in the past
+            let year = if year < 70 { 2000 + year } else if year < 100 { 1900 + year } else { year };
+            (day, month, year, *time)
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    let mut clock = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// "2025-12-09T20:00:00Z"
+fn format_iso8601(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day, hour, minute, second, _) = civil_from_unix(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// A compiled `[[servers.location]]` block
+struct Location {
+    config: LocationConfig,
+    /// Files under the location's root or alias
+    files: StaticFiles,
+    /// Every configured upload directory, in any server block
+    upload_dirs: Vec<String>,
+}
+
+impl Location {
+    /// `alias` replaces the location's prefix, while `root` (or the server
+    /// root) keeps it, so a `root` location serves `root + prefix` and is
+    /// confined below that directory rather than the whole root
+    fn new(config: LocationConfig, server_root: &str, upload_dirs: &[String]) -> Self {
+        let base = match &config.alias {
+            Some(alias) => alias.clone(),
+            None => {
+                let root = std::path::Path::new(config.root.as_deref().unwrap_or(server_root));
+                root.join(config.path.trim_matches('/')).to_string_lossy().into_owned()
+            }
+        };
+        let files = StaticFiles::new(&base, &config.index).with_autoindex(config.autoindex);
+        Location {
+            config,
+            files,
+            upload_dirs: upload_dirs.to_vec(),
+        }
+    }
+
+    fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        if let Some(target) = &self.config.redirect {
+            return ResponseBuilder::new()
+                .status(self.config.redirect_code, Self::redirect_text(self.config.redirect_code))
+                .header("Location", target)
+                .content_type("text/html; charset=utf-8")
+                .body_text(&format!(r#"<a href="{}">Moved</a>"#, target))
+                .build();
+        }
+
+        if let Some(upload_dir) = &self.config.upload_dir {
+            if req.method == "POST" || req.method == "PUT" {
+                return handle_upload(req, upload_dir);
+            }
+        }
+
+        let url_path = self.url_path(&req.path);
+        if let Some(script) = self.cgi_script(url_path) {
+            return match script {
+                Ok(script) => handle_cgi(req, &script),
+                Err(response) => response,
+            };
+        }
+
+        if req.method != "GET" && req.method != "HEAD" {
+            return Router::method_not_allowed(&["GET", "HEAD", "OPTIONS"]);
+        }
+        self.files.serve(req, url_path)
+    }
+
+    /// The part of the request path looked up below the location's directory
+    fn url_path<'a>(&self, request_path: &'a str) -> &'a str {
+        let prefix = self.config.path.trim_end_matches('/');
+        request_path.strip_prefix(prefix).unwrap_or(request_path)
+    }
+
+    /// Find the first path segment carrying a CGI extension; whatever follows
+    /// it becomes PATH_INFO
+    fn cgi_script(&self, url_path: &str) -> Option<Result<CgiScript, HttpResponse>> {
+        if self.config.cgi.is_empty() {
+            return None;
+        }
+        let Some(segments) = StaticFiles::normalize(url_path) else {
+            return Some(Err(StaticFiles::forbidden()));
+        };
+        for (i, segment) in segments.iter().enumerate() {
+            let Some(interpreter) = self
+                .config
+                .cgi
+                .iter()
+                .find(|(extension, _)| segment.ends_with(extension.as_str()))
+                .map(|(_, interpreter)| interpreter)
+            else {
+                continue;
+            };
+            let script_url = format!("/{}", segments[..=i].join("/"));
+            let path = match self.files.resolve(&script_url) {
+                Ok(path) => path,
+                Err(response) => return Some(Err(response)),
+            };
+            if self.in_upload_dir(&path) {
+                return Some(Err(StaticFiles::forbidden()));
+            }
+            let script_name = format!("{}{}", self.config.path.trim_end_matches('/'), script_url);
+            let path_info = if i + 1 < segments.len() {
+                format!("/{}", segments[i + 1..].join("/"))
+            } else {
+                String::new()
+            };
+            return Some(Ok(CgiScript {
+                path: path.to_string_lossy().into_owned(),
+                interpreter: if interpreter.is_empty() { None } else { Some(interpreter.clone()) },
+                script_name,
+                path_info,
+            }));
+        }
+        None
+    }
+
+    /// Whether `path` lies in a directory clients can upload to. Scripts
+    /// there are never run, whichever location they are reached through.
+    fn in_upload_dir(&self, path: &std::path::Path) -> bool {
+        let Ok(real) = fs::canonicalize(path) else {
+            return true;
+        };
+        self.upload_dirs
+            .iter()
+            .filter_map(|dir| fs::canonicalize(dir).ok())
+            .any(|dir| real.starts_with(dir))
+    }
+
+    fn redirect_text(code: u16) -> &'static str {
+        match code {
+            300 => "Multiple Choices",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            _ => "Redirect",
+        }
+    }
+}
+
+/// A configured virtual host and the routes it serves
+struct VirtualServer {
+    config: VirtualServerConfig,
+    router: Router,
+    /// Also used for errors raised before a request reaches the router
+    error_pages: CustomErrorPages,
+}
+
+impl VirtualServer {
+    /// `upload_dirs` lists the upload directories of every server block, so
+    /// no CGI location runs an uploaded file
+    fn new(config: VirtualServerConfig, server: &ServerConfig, upload_dirs: &[String]) -> io::Result<Self> {
+        // Initialize router with routes. Response stages run innermost first:
+        // compression picks the representation, which preconditions and
+        // ranges then apply to, and the log sees the final status.
+        let error_pages = CustomErrorPages::new(config.error_pages.clone());
+        let mut router = Router::new();
+        router
+            .wrap(AccessLog)
+            .wrap(RequestId)
+            .wrap(error_pages.clone())
+            .wrap(ByteRanges)
+            .wrap(Preconditions)
+            .wrap(LocationCompression::new(server, &config.location));
+        if config.builtin_routes {
+            router.register("GET", "/", handle_root);
+            router.register("GET", "/health", handle_health);
+            router.register("GET", "/inspect", handle_inspect);
+            router.register("GET", "/form-test", handle_form_test);
+            router.register("POST", "/form-test", handle_form_test);
+            router.register("GET", "/download", handle_download);
+            router.register("GET", "/login", handle_login);
+            router
+                .register("POST", "/login", handle_login)
+                .wrap(RateLimit::new(10, Duration::from_secs(60)));
+            router
+                .register("GET", "/protected", handle_protected)
+                .wrap(RequireSession::new("user", "/login"));
+            router.register("POST", "/logout", handle_logout);
+            router.register("GET", "/logout", handle_logout);
+            let files = StaticFiles::new(&config.root, "example.html");
+            router
+                .register_prefix("GET", "/static", move |req| handle_static(req, &files))
+                .map_err(io::Error::other)?;
+            let mut api = router
+                .group("/api")
+                .wrap(Cors::new(&["*"]).methods(&["GET", "POST"]).headers(&["Content-Type"]));
+            api.register("GET", "/users", handle_users);
+            api.register("POST", "/users", handle_users);
+            api.register("GET", "/users/:id{u64}", handle_user);
+            api.register("GET", "/*path", handle_api_catch_all);
+            api.register("POST", "/*path", handle_api_catch_all);
+        }
+
+        // Compile the configured locations into prefix routes
+        for location in &config.location {
+            let location = Rc::new(Location::new(location.clone(), &config.root, upload_dirs));
+            for method in &location.config.methods {
+                let path = location.config.path.clone();
+                let location = Rc::clone(&location);
+                router
+                    .register_prefix(method, &path, move |req| location.handle(req))
+                    .map_err(|e| io::Error::other(format!("Invalid location: {}", e)))?;
+            }
+        }
+
+        Ok(VirtualServer {
+            config,
+            router,
+            error_pages,
+        })
+    }
+
+    /// Whether `host` (lowercase, without port) is one of this server's names
+    fn matches_host(&self, host: &str) -> bool {
+        self.config.server_name.iter().any(|name| {
+            let name = name.to_lowercase();
+            match name.strip_prefix("*.") {
+                Some(suffix) => host.len() > suffix.len() + 1
+                    && host.ends_with(suffix)
+                    && host.as_bytes()[host.len() - suffix.len() - 1] == b'.',
+                None => name == host,
+            }
+        })
+    }
+
+    /// Replace the body of an error response with the configured page, if any
+    fn apply_error_page(&self, response: &mut HttpResponse) {
+        self.error_pages.apply(response);
+    }
+}
+
+/// Configured `[servers.error_pages]`, swapped in for the built-in pages
+#[derive(Clone)]
+struct CustomErrorPages {
+    /// Status code to HTML file
+    pages: HashMap<String, String>,
+}
+
+impl CustomErrorPages {
+    fn new(pages: HashMap<String, String>) -> Self {
+        CustomErrorPages { pages }
+    }
+
+    fn apply(&self, response: &mut HttpResponse) {
+        if response.status < 400 {
+            return;
+        }
+        let Some(path) = self.pages.get(&response.status.to_string()) else {
+            return;
+        };
+        match fs::read(path) {
+            Ok(page) => {
+                response.headers.insert("Content-Type", ResponseBuilder::get_content_type(path));
+                response.headers.insert("Content-Length", page.len().to_string());
+                response.body = Body::Bytes(page);
+            }
+            Err(e) => eprintln!("Error reading error page {}: {}", path, e),
+        }
+    }
+}
+
+impl Middleware for CustomErrorPages {
+    fn after(&self, _request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+        self.apply(&mut response);
+        response
+    }
+}
+
+/// A listening socket and the virtual hosts bound to its address
+struct Listener {
+    listener: TcpListener,
+    /// Address from the configuration, which a reload must keep
+    addr: SocketAddr,
+    /// Indexes into `Server::vhosts`, in configuration order
+    servers: Vec<usize>,
+    /// Server answering when the Host header matches none of `servers`
+    default_server: usize,
+}
+
+struct Server {
+    listeners: HashMap<RawFd, Listener>,
+    vhosts: Vec<VirtualServer>,
+    config: Config,
+    cookie_keys: Rc<CookieKeys>,
+    sessions: Sessions,
+    epoll_fd: RawFd,
+    connections: HashMap<RawFd, Connection>,
+}
+
+impl Server {
+    pub fn new(config_path: &str) -> io::Result<Server> {
+        let config = Self::load_config(config_path)?;
+        let vhosts = Self::build_vhosts(&config)?;
+
+        // Create epoll instance
+        let epoll_fd = unsafe { epoll_create1(0) };
+        if epoll_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut listeners = HashMap::new();
+        for (addr, servers) in Self::group_addresses(&vhosts)? {
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+
+            // Add listener to epoll
+            let mut event = epoll_event {
+                events: EPOLLIN as u32,
+                u64: listener.as_raw_fd() as u64,
+            };
+
+            unsafe {
+                if epoll_ctl(
+                    epoll_fd,
+                    EPOLL_CTL_ADD,
+                    listener.as_raw_fd(),
+                    &mut event as *mut epoll_event,
+                ) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            let default_server = Self::default_server(&vhosts, &servers);
+            for &index in &servers {
+                let names = &vhosts[index].config.server_name;
+                println!(
+                    "Server started on http://{}/ ({}{})",
+                    addr,
+                    if names.is_empty() { "*".to_string() } else { names.join(", ") },
+                    if index == default_server { ", default" } else { "" }
+                );
+            }
+
+            listeners.insert(listener.as_raw_fd(), Listener {
+                listener,
+                addr,
+                servers,
+                default_server,
+            });
+        }
+        
+        let cookie_keys = Rc::new(CookieKeys::from_config(&config.cookies)?);
+        let sessions = Sessions::from_config(&config.sessions)?;
+
+        Ok(Server {
+            listeners,
+            vhosts,
+            config,
+            cookie_keys,
+            sessions,
+            epoll_fd,
+            connections: HashMap::new(),
+        })
+    }
+
+    /// Read, parse and validate a configuration file
+    fn load_config(config_path: &str) -> io::Result<Config> {
+        let config_content = fs::read_to_string(config_path)
+            .map_err(|e| io::Error::other(format!("Failed to read config: {}", e)))?;
+
+        let config: Config = toml::from_str(&config_content)
+            .map_err(|e| io::Error::other(format!("Failed to parse config: {}", e)))?;
+        config
+            .validate()
+            .map_err(|e| io::Error::other(format!("Invalid config: {:?}", e)))?;
+        Ok(config)
+    }
+
+    /// Compile every server block with its routes and locations
+    fn build_vhosts(config: &Config) -> io::Result<Vec<VirtualServer>> {
+        let upload_dirs = config.upload_dirs();
+        config
+            .virtual_servers()
+            .into_iter()
+            .map(|vhost| VirtualServer::new(vhost, &config.server, &upload_dirs))
+            .collect()
+    }
+
+    /// Group servers by resolved address so each address is bound once
+    fn group_addresses(vhosts: &[VirtualServer]) -> io::Result<Vec<(SocketAddr, Vec<usize>)>> {
+        let mut addresses: Vec<(SocketAddr, Vec<usize>)> = Vec::new();
+        for (index, vhost) in vhosts.iter().enumerate() {
+            for listen in &vhost.config.listen {
+                let addr = listen
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| io::Error::other(format!("Cannot resolve listen address: {}", listen)))?;
+                match addresses.iter_mut().find(|(existing, _)| *existing == addr) {
+                    Some((_, servers)) if !servers.contains(&index) => servers.push(index),
+                    Some(_) => {}
+                    None => addresses.push((addr, vec![index])),
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// The server marked `default` among `servers`, else the first
+    fn default_server(vhosts: &[VirtualServer], servers: &[usize]) -> usize {
+        servers
+            .iter()
+            .copied()
+            .find(|&index| vhosts[index].config.default)
+            .unwrap_or(servers[0])
+    }
+
+    /// Pick the virtual host for a request arriving on `listener_fd` by its
+    /// Host header, falling back to the listener's default server
+    fn select_vhost(listeners: &HashMap<RawFd, Listener>, vhosts: &[VirtualServer], listener_fd: RawFd, host: Option<&str>) -> usize {
+        let Some(listener) = listeners.get(&listener_fd) else {
+            return 0;
+        };
+        if let Some(host) = host {
+            // Strip the port, keeping IPv6 literals like "[::1]" intact
+            let name = match host.rfind(':') {
+                Some(colon) if !host[colon..].contains(']') => &host[..colon],
+                _ => host,
+            };
+            let name = name.trim_end_matches('.').to_lowercase();
+            if let Some(&index) = listener.servers.iter().find(|&&index| vhosts[index].matches_host(&name)) {
+                return index;
+            }
+        }
+        listener.default_server
+    }
+    
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = vec![epoll_event { events: 0, u64: 0 }; self.config.server.max_events];
+        
+        loop {
+            let num_events = unsafe {
+                epoll_wait(
+                    self.epoll_fd,
+                    events.as_mut_ptr(),
+                    self.config.server.max_events as i32,
+                    self.config.server.timeout_ms,
+                )
+            };
+
+            if num_events < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for event in events.iter().take(num_events as usize) {
+                let fd = event.u64 as RawFd;
+
+                if self.listeners.contains_key(&fd) {
+                    // Handle new connection
+                    self.accept_connection(fd)?;
+                } else {
+                    // Handle existing connection
+                    if event.events & (EPOLLERR as u32 | EPOLLHUP as u32) != 0 {
+                        self.remove_connection(fd)?;
+                        continue;
+                    }
+
+                    if event.events & EPOLLOUT as u32 != 0 {
+                        // Resume a partially written response, then answer any
+                        // pipelined requests that were waiting behind it
+                        let epoll_fd = self.epoll_fd;
+                        let flushed = match self.connections.get_mut(&fd) {
+                            Some(connection) => connection.flush(epoll_fd),
+                            None => continue,
+                        };
+                        if !flushed || !self.process_requests(fd) {
+                            self.remove_connection(fd)?;
+                            continue;
+                        }
+                    }
+
+                    if event.events & EPOLLIN as u32 != 0 {
+                        if self.handle_client_data(fd).is_err() {
+                            self.remove_connection(fd)?;
+                            continue;
+                        }
+                        if !self.process_requests(fd) {
+                            self.remove_connection(fd)?;
+                        }
+                    }
+                }
+            }
+
+            self.expire_connections()?;
+            self.sessions.sweep();
+        }
+    }
+
+    fn accept_connection(&mut self, listener_fd: RawFd) -> io::Result<()> {
+        let Some(listener) = self.listeners.get(&listener_fd) else {
+            return Ok(());
+        };
+        match listener.listener.accept() {
+            Ok((stream, addr)) => {
+                println!("New connection from: {}", addr);
+                stream.set_nonblocking(true)?;
+                
+                let fd = stream.as_raw_fd();
+                let mut event = epoll_event {
+                    events: EPOLLIN as u32,
+                    u64: fd as u64,
+                };
+
+                unsafe {
+                    if epoll_ctl(self.epoll_fd, EPOLL_CTL_ADD, fd, &mut event as *mut epoll_event) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+
+                self.connections.insert(fd, Connection {
+                    stream,
+                    listener_fd,
+                    buffer: Vec::with_capacity(4096),
+                    parser: HttpParser::new(ParserLimits::from_config(&self.config.server)),
+                    requests_served: 0,
+                    request_started: Instant::now(),
+                    last_read: Instant::now(),
+                    last_write: Instant::now(),
+                    output: VecDeque::new(),
+                    write_offset: 0,
+                    use_sendfile: true,
+                    close_after_write: false,
+                    interest: EPOLLIN as u32,
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => eprintln!("Error accepting connection: {}", e),
+        }
+        Ok(())
+    }
+
+    fn remove_connection(&mut self, fd: RawFd) -> io::Result<()> {
+        unsafe {
+            epoll_ctl(self.epoll_fd, EPOLL_CTL_DEL, fd, std::ptr::null_mut());
+        }
+        self.connections.remove(&fd);
+        Ok(())
+    }
+
+    fn handle_client_data(&mut self, fd: RawFd) -> io::Result<()> {
+        if let Some(connection) = self.connections.get_mut(&fd) {
+            let mut buffer = [0; 4096];
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => {
+                    // Connection closed by client
+                    println!("Connection closed by client");
+                    return Err(io::Error::other("Connection closed"));
+                }
+                Ok(n) => {
+                    // Append new data to the connection buffer; parsing happens
+                    // in process_requests so pipelined requests are all seen
+                    let now = Instant::now();
+                    if connection.parser.is_idle() && connection.buffer.is_empty() {
+                        connection.request_started = now;
+                    }
+                    connection.buffer.extend_from_slice(&buffer[..n]);
+                    connection.last_read = now;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("Error reading from client: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse and answer every complete request sitting in the connection
+    /// buffer, in order. Bytes of a following, partial request are kept for
+    /// the next read, and parsing pauses while a response is still being
+    /// written. Returns false once the connection should be closed.
+    fn process_requests(&mut self, fd: RawFd) -> bool {
+        let max_requests = self.config.server.max_keep_alive_requests;
+        let keep_alive_timeout = self.config.server.keep_alive_timeout_ms;
+        let epoll_fd = self.epoll_fd;
+        let Some(connection) = self.connections.get_mut(&fd) else {
+            return false;
+        };
+        let (listeners, vhosts) = (&self.listeners, &self.vhosts);
+        let cookie_keys = &self.cookie_keys;
+        let sessions = &mut self.sessions;
+
+        loop {
+            if connection.has_pending_output() || connection.close_after_write {
+                return true;
+            }
+
+            // The parser keeps its place, so a request split across reads resumes here
+            let mut request = match connection.parser.advance(&mut connection.buffer) {
+                Ok(Some(request)) => request,
+                Ok(None) => {
+                    // Headers passed the limits; let a waiting client send its body
+                    if connection.parser.take_continue() {
+                        connection.queue_segment(Body::Bytes(b"HTTP/1.1 100 Continue\r\n\r\n".to_vec()));
+                        return connection.flush(epoll_fd);
+                    }
+                    return true;
+                }
+                Err(e) => {
+                    eprintln!("Error parsing request: {}", e);
+                    let mut response = e.to_response();
+                    // The Host header may not have been read, so the listener's default server answers
+                    let vhost = Self::select_vhost(listeners, vhosts, connection.listener_fd, None);
+                    vhosts[vhost].apply_error_page(&mut response);
+                    response.headers.insert("Connection", "close");
+                    connection.queue_response(response);
+                    connection.close_after_write = true;
+                    return connection.flush(epoll_fd);
+                }
+            };
+
+            request.remote_addr = connection.stream.peer_addr().ok();
+            request.local_addr = connection.stream.local_addr().ok();
+            request.cookie_keys = Some(Rc::clone(cookie_keys));
+            request.session = Rc::new(RefCell::new(sessions.load(&request)));
+
+            connection.requests_served += 1;
+            // Whatever follows in the buffer is the start of the next request
+            connection.request_started = Instant::now();
+            let limit_reached = max_requests != 0 && connection.requests_served >= max_requests;
+            let mut keep_alive = request.wants_keep_alive() && !limit_reached;
+
+            // Route the request through the virtual host it is addressed to
+            let vhost = &vhosts[Self::select_vhost(listeners, vhosts, connection.listener_fd, request.header("Host"))];
+            let mut response = vhost.router.handle(&mut request);
+            sessions.save(request.session.take(), &mut response, cookie_keys);
+            // HTTP/1.0 clients do not understand chunked framing
+            if request.version == "HTTP/1.0" && response.unchunk() {
+                keep_alive = false;
+            }
+            // HEAD gets the headers GET would, Content-Length included, but no body
+            if request.method == "HEAD" {
+                response.body = Body::Bytes(Vec::new());
+            }
+            if keep_alive {
+                response.headers.insert("Connection", "keep-alive");
+                let remaining = if max_requests == 0 {
+                    String::new()
+                } else {
+                    format!(", max={}", max_requests - connection.requests_served)
+                };
+                response.headers.insert(
+                    "Keep-Alive",
+                    format!("timeout={}{}", keep_alive_timeout.div_ceil(1000), remaining),
+                );
+            } else {
+                response.headers.insert("Connection", "close");
+            }
+
+            // Send response; whatever the socket does not take now is
+            // written when it becomes writable again
+            connection.queue_response(response);
+            connection.close_after_write = !keep_alive;
+            if !connection.flush(epoll_fd) {
+                return false;
+            }
+        }
+    }
+
+    /// Enforce header, body, keep-alive and write deadlines. Checked on
+    /// every loop tick, so `timeout_ms` bounds how late a deadline is noticed.
+    fn expire_connections(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let expired: Vec<(RawFd, Expiry)> = self
+            .connections
+            .iter()
+            .filter_map(|(&fd, connection)| {
+                connection.expiry(&self.config.server, now).map(|expiry| (fd, expiry))
+            })
+            .collect();
+
+        let epoll_fd = self.epoll_fd;
+        for (fd, expiry) in expired {
+            let keep = match (expiry, self.connections.get_mut(&fd)) {
+                (Expiry::RequestTimeout, Some(connection)) => {
+                    println!("Request timed out");
+                    let mut response = HttpResponse::new(408, "Request Timeout", &ErrorPages::request_timeout());
+                    let vhost = Self::select_vhost(&self.listeners, &self.vhosts, connection.listener_fd, None);
+                    self.vhosts[vhost].apply_error_page(&mut response);
+                    response.headers.insert("Connection", "close");
+                    connection.queue_response(response);
+                    connection.close_after_write = true;
+                    connection.last_write = now;
+                    connection.flush(epoll_fd)
+                }
+                _ => {
+                    println!("Closing idle connection");
+                    false
+                }
+            };
+            if !keep {
+                self.remove_connection(fd)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-read the configuration and rebuild the virtual hosts and their
+    /// locations from it. Listening sockets are kept, so a reload that adds
+    /// or removes a listen address is rejected and the old configuration
+    /// stays in force. Cookie keys and the session store are kept as well.
+    #[allow(dead_code)]
+    pub fn reload_config(&mut self, config_path: &str) -> io::Result<()> {
+        let config = Self::load_config(config_path)?;
+        let vhosts = Self::build_vhosts(&config)?;
+        let addresses = Self::group_addresses(&vhosts)?;
+
+        let mut bound: Vec<SocketAddr> = self.listeners.values().map(|listener| listener.addr).collect();
+        let mut wanted: Vec<SocketAddr> = addresses.iter().map(|(addr, _)| *addr).collect();
+        bound.sort_unstable();
+        wanted.sort_unstable();
+        if bound != wanted {
+            return Err(io::Error::other("listen addresses changed; restart the server to apply them"));
+        }
+
+        for listener in self.listeners.values_mut() {
+            if let Some((_, servers)) = addresses.iter().find(|(addr, _)| *addr == listener.addr) {
+                listener.default_server = Self::default_server(&vhosts, servers);
+                listener.servers = servers.clone();
+            }
+        }
+        self.vhosts = vhosts;
+        self.config = config;
+        println!("Configuration reloaded successfully");
+        Ok(())
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.servers.is_empty() && self.server.port == 0 {
+            return Err(ServerError::InvalidConfig("Port cannot be 0".into()));
+        }
+        for key in self.cookies.signing_key.iter().chain(&self.cookies.verify_keys) {
+            if key.len() < CookieKeys::MIN_SECRET_LEN {
+                return Err(ServerError::InvalidConfig(format!(
+                    "cookie keys must be at least {} bytes",
+                    CookieKeys::MIN_SECRET_LEN
+                )));
+            }
+            if key == CookieKeys::PLACEHOLDER_SECRET {
+                return Err(ServerError::InvalidConfig(
+                    "cookie key is the example placeholder; set your own secret".into(),
+                ));
+            }
+        }
+        for (index, server) in self.servers.iter().enumerate() {
+            if server.listen.is_empty() {
+                return Err(ServerError::InvalidConfig(format!("servers[{}] has no listen address", index)));
+            }
+            for code in server.error_pages.keys() {
+                if code.parse::<u16>().is_err() {
+                    return Err(ServerError::InvalidConfig(format!("invalid error page status: {}", code)));
+                }
+            }
+            for location in &server.location {
+                if !location.path.starts_with('/') {
+                    return Err(ServerError::InvalidConfig(format!("location path must start with '/': {}", location.path)));
+                }
+                if location.root.is_some() && location.alias.is_some() {
+                    return Err(ServerError::InvalidConfig(format!("location {} sets both root and alias", location.path)));
+                }
+                if location.redirect.is_some() && !(300..=308).contains(&location.redirect_code) {
+                    return Err(ServerError::InvalidConfig(format!("location {} has invalid redirect_code", location.path)));
+                }
+            }
+        }
+        if self.server.max_events == 0 {
+            return Err(ServerError::InvalidConfig("max_events cannot be 0".into()));
+        }
+        if self.server.timeout_ms < 0 {
+            return Err(ServerError::InvalidConfig("timeout_ms cannot be negative".into()));
+        }
+        Ok(())
+    }
+
+    /// The configured virtual hosts, or one built from `[server]` when none are declared
+    fn virtual_servers(&self) -> Vec<VirtualServerConfig> {
+        if !self.servers.is_empty() {
+            return self.servers.clone();
+        }
+        vec![VirtualServerConfig {
+            listen: vec![format!("{}:{}", self.server.host, self.server.port)],
+            server_name: Vec::new(),
+            root: default_root(),
+            default: true,
+            error_pages: HashMap::new(),
+            builtin_routes: true,
+            location: Vec::new(),
+        }]
+    }
+
+    /// Upload directories across all server blocks
+    fn upload_dirs(&self) -> Vec<String> {
+        self.virtual_servers()
+            .iter()
+            .flat_map(|vhost| &vhost.location)
+            .filter_map(|location| location.upload_dir.clone())
+            .collect()
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut server = Server::new("config.toml")?;
+    server.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ParserLimits {
+        ParserLimits {
+            max_header_bytes: 8192,
+            max_headers: 100,
+            max_body_size: 1024 * 1024,
+        }
+    }
+
+    /// Feed `raw` to a fresh parser in one piece
+    fn parse(raw: &[u8]) -> Result<Option<HttpRequest>, ParseError> {
+        HttpParser::new(limits()).advance(&mut raw.to_vec())
+    }
+
+    /// A bodiless request for `target`
+    fn request(method: &str, target: &str) -> HttpRequest {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, target);
+        parse(raw.as_bytes()).unwrap().unwrap()
+    }
+
+    /// An empty directory unique to this test process and `name`
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("localhost-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn location(toml: &str, server_root: &std::path::Path) -> Location {
+        let config: LocationConfig = toml::from_str(toml).unwrap();
+        Location::new(config, &server_root.to_string_lossy(), &[])
+    }
+
+    fn body_text(response: &HttpResponse) -> String {
+        String::from_utf8_lossy(&Compression::read_body(&response.body).unwrap()).into_owned()
+    }
+
+    /// Config file text with `servers` blocks after the required sections
+    fn config_text(servers: &str) -> String {
+        format!(
+            "[server]\ntimeout_ms = 100\nmax_events = 16\ncompression = true\ncompression_min_length = 16\n\
+             [logging]\nlevel = \"info\"\nfile = \"-\"\n{}",
+            servers
+        )
+    }
+
+    fn config(servers: &str) -> Config {
+        toml::from_str(&config_text(servers)).unwrap()
+    }
+
+    fn vhost(config: &Config, index: usize) -> VirtualServer {
+        VirtualServer::new(config.virtual_servers()[index].clone(), &config.server, &[]).unwrap()
+    }
+
+    #[test]
+    fn response_stages_run_as_router_middleware() {
+        let root = temp_dir("response-stages");
+        fs::write(root.join("page.html"), "<p>hello</p>".repeat(100)).unwrap();
+        fs::write(root.join("404.html"), "custom missing page").unwrap();
+        let config = config(&format!(
+            "[[servers]]\nlisten = [\"127.0.0.1:0\"]\nroot = {:?}\n[servers.error_pages]\n404 = {:?}\n",
+            root.to_string_lossy(),
+            root.join("404.html").to_string_lossy()
+        ));
+        let vhost = vhost(&config, 0);
+        let get = |extra: &str| {
+            let raw = format!("GET /static/page.html HTTP/1.1\r\nAccept-Encoding: gzip\r\n{}\r\n", extra);
+            vhost.router.handle(&mut parse(raw.as_bytes()).unwrap().unwrap())
+        };
+
+        let compressed = get("");
+        assert_eq!(compressed.headers.get("Content-Encoding"), Some("gzip"));
+        let etag = compressed.headers.get("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/"));
+
+        let not_modified = get(&format!("If-None-Match: {}\r\n", etag));
+        assert_eq!(not_modified.status, 304);
+        assert_eq!(not_modified.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(not_modified.headers.get("Vary"), Some("Accept-Encoding"));
+
+        let partial = get("Range: bytes=0-2\r\n");
+        assert_eq!(partial.status, 206);
+        assert!(!partial.headers.contains("Content-Encoding"));
+        assert_eq!(body_text(&partial), "<p>");
+
+        let missing = vhost.router.handle(&mut request("GET", "/static/none.html"));
+        assert_eq!(missing.status, 404);
+        assert_eq!(body_text(&missing), "custom missing page");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reload_rebuilds_locations() {
+        let dir = temp_dir("reload");
+        for name in ["one", "two"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("file.txt"), name).unwrap();
+        }
+        let write_config = |listen: &str, location: &str| {
+            let servers = format!(
+                "[[servers]]\nlisten = [{:?}]\nbuiltin_routes = false\n\
+                 [[servers.location]]\npath = \"/{}\"\nalias = {:?}\n",
+                listen,
+                location,
+                dir.join(location).to_string_lossy()
+            );
+            fs::write(dir.join("config.toml"), config_text(&servers)).unwrap();
+        };
+        let path = dir.join("config.toml").to_string_lossy().into_owned();
+        let get = |server: &Server, target: &str| server.vhosts[0].router.handle(&mut request("GET", target)).status;
+
+        write_config("127.0.0.1:0", "one");
+        let mut server = Server::new(&path).unwrap();
+        assert_eq!(get(&server, "/one/file.txt"), 200);
+        assert_eq!(get(&server, "/two/file.txt"), 404);
+
+        write_config("127.0.0.1:0", "two");
+        server.reload_config(&path).unwrap();
+        assert_eq!(get(&server, "/one/file.txt"), 404);
+        assert_eq!(get(&server, "/two/file.txt"), 200);
+
+        // Listen addresses cannot change without rebinding; the old setup stays
+        write_config("127.0.0.2:0", "one");
+        assert!(server.reload_config(&path).is_err());
+        assert_eq!(get(&server, "/two/file.txt"), 200);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parser_rejects_dot_segments() {
+        for target in ["/cgi-bin/../config.toml", "/a/./b", "/..", "/a/%2e%2e/b", "/a/%2E./b", "/x/.%2e"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            assert!(matches!(parse(raw.as_bytes()), Err(ParseError::BadRequest(_))), "{}", target);
+        }
+        for target in ["/a..b/c.", "/.well-known/x", "/search?q=../..", "/files/...", "/"] {
+            assert_eq!(request("GET", target).method, "GET", "{}", target);
+        }
+    }
+
+    /// A document root holding a location directory and a file beside it
+    /// that must stay out of reach
+    fn traversal_root(name: &str) -> std::path::PathBuf {
+        let root = temp_dir(name);
+        fs::create_dir_all(root.join("public/sub")).unwrap();
+        fs::write(root.join("public/sub/page.txt"), "page").unwrap();
+        fs::write(root.join("secret.txt"), "secret").unwrap();
+        fs::create_dir_all(root.join("other")).unwrap();
+        fs::write(root.join("other/file.txt"), "other").unwrap();
+        root
+    }
+
+    /// Requests that slipped past the parser's check must still not leave
+    /// the location's directory
+    fn assert_confined(location: &Location, prefix: &str) {
+        let ok = location.handle(&request("GET", &format!("{}/sub/page.txt", prefix)));
+        assert_eq!(ok.status, 200);
+        for escape in ["/../secret.txt", "/../other/file.txt", "/sub/../../secret.txt", "/%2e%2e/secret.txt"] {
+            let mut req = request("GET", "/");
+            req.path = format!("{}{}", prefix, escape);
+            let response = location.handle(&req);
+            assert!(response.status == 403 || response.status == 404, "{} gave {}", escape, response.status);
+        }
+    }
+
+    #[test]
+    fn root_location_stays_below_its_prefix() {
+        let root = traversal_root("root-location");
+        let location = location(&format!("path = \"/public\"\nroot = {:?}", root.to_string_lossy()), &root);
+        assert_confined(&location, "/public");
+        // The server root is the default root and gets the same treatment
+        let location = self::location("path = \"/public\"", &root);
+        assert_confined(&location, "/public");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn alias_location_stays_below_its_directory() {
+        let root = traversal_root("alias-location");
+        let alias = root.join("public");
+        let location = location(&format!("path = \"/assets\"\nalias = {:?}", alias.to_string_lossy()), &root);
+        assert_confined(&location, "/assets");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn cgi_never_runs_uploaded_files() {
+        let root = temp_dir("cgi-uploads");
+        for dir in ["cgi-bin", "uploads"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("script.py"), "print()").unwrap();
+        }
+        let uploads = root.join("uploads").to_string_lossy().into_owned();
+        let cgi = |alias: &std::path::Path| {
+            let config = format!("path = \"/run\"\nalias = {:?}\ncgi = {{ \".py\" = \"/usr/bin/python3\" }}", alias.to_string_lossy());
+            Location::new(toml::from_str(&config).unwrap(), &root.to_string_lossy(), std::slice::from_ref(&uploads))
+        };
+
+        let script = cgi(&root.join("cgi-bin")).cgi_script("/script.py/extra");
+        let script = script.unwrap().map_err(|response| response.status).unwrap();
+        assert_eq!(script.script_name, "/run/script.py");
+        assert_eq!(script.path_info, "/extra");
+
+        let refused = cgi(&root.join("uploads")).cgi_script("/script.py");
+        assert_eq!(refused.unwrap().err().map(|response| response.status), Some(403));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn wildcard_captures_refuse_dot_segments() {
+        let mut router = Router::new();
+        router.register("GET", "/files/*rest", |req| HttpResponse::new(200, "OK", req.param("rest").unwrap_or("")));
+        let mut params = Vec::new();
+        assert!(router.find("GET", "/files/a/b.txt", &mut params).is_some());
+        assert_eq!(params, [("rest".to_string(), "a/b.txt".to_string())]);
+        for path in ["/files/../secret", "/files/a/./b", "/files/a/%2e%2e/b", "/files/a%2F..%2Fb"] {
+            assert!(router.find("GET", path, &mut Vec::new()).is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn directory_redirect_escapes_the_path() {
+        let root = temp_dir("redirect-escape");
+        fs::create_dir_all(root.join("<b>")).unwrap();
+        let files = StaticFiles::new(&root.to_string_lossy(), "index.html");
+        let mut req = request("GET", "/");
+        req.path = "/<b>".to_string();
+        let response = files.serve(&req, &req.path);
+        assert_eq!(response.status, 301);
+        assert!(body_text(&response).contains("/&lt;b&gt;/"));
+        assert!(!body_text(&response).contains("<b>"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Deterministic bytes for tests (xorshift64)
+    fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    const LENGTH_BASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+    ];
+    const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+    const DIST_BASE: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+        8193, 12289, 16385, 24577,
+    ];
+    const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+    /// Order code length code lengths are transmitted in
+    const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+    /// Bitwise CRC-32 (IEEE), kept apart from flate2's table-driven one
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = 0xFFFFFFFFu32;
+        for &byte in data {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    fn adler32(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        (b << 16) | a
+    }
+
+    /// Minimal DEFLATE decoder (stored, fixed and dynamic blocks), written
+    /// independently of flate2 to check what it produces
+    struct Inflater<'a> {
+        data: &'a [u8],
+        pos: usize,
+        bit: u32,
+        out: Vec<u8>,
+    }
+
+    impl Inflater<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let byte = self.data[self.pos];
+                value |= (((byte >> self.bit) & 1) as u32) << i;
+                self.bit += 1;
+                if self.bit == 8 {
+                    self.bit = 0;
+                    self.pos += 1;
+                }
+            }
+            value
+        }
+
+        /// Canonical Huffman table as (codes per length, symbols by code)
+        fn table(lengths: &[u8]) -> ([u16; 16], Vec<u16>) {
+            let mut counts = [0u16; 16];
+            for &len in lengths {
+                counts[len as usize] += 1;
+            }
+            counts[0] = 0;
+            let mut offsets = [0u16; 16];
+            for len in 1..15 {
+                offsets[len + 1] = offsets[len] + counts[len];
+            }
+            let mut symbols = vec![0; lengths.len()];
+            for (symbol, &len) in lengths.iter().enumerate() {
+                if len != 0 {
+                    symbols[offsets[len as usize] as usize] = symbol as u16;
+                    offsets[len as usize] += 1;
+                }
+            }
+            (counts, symbols)
+        }
+
+        fn decode(&mut self, (counts, symbols): &([u16; 16], Vec<u16>)) -> usize {
+            let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+            for &count in &counts[1..] {
+                code |= self.bits(1) as i32;
+                let count = count as i32;
+                if code - count < first {
+                    return symbols[(index + code - first) as usize] as usize;
+                }
+                index += count;
+                first = (first + count) << 1;
+                code <<= 1;
+            }
+            panic!("invalid Huffman code");
+        }
+
+        fn inflate(data: &[u8]) -> Vec<u8> {
+            let mut inflater = Inflater { data, pos: 0, bit: 0, out: Vec::new() };
+            loop {
+                let last = inflater.bits(1) == 1;
+                match inflater.bits(2) {
+                    0 => {
+                        if inflater.bit != 0 {
+                            inflater.bit = 0;
+                            inflater.pos += 1;
+                        }
+                        let at = inflater.pos;
+                        let len = u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+                        inflater.out.extend_from_slice(&data[at + 4..at + 4 + len]);
+                        inflater.pos = at + 4 + len;
+                    }
+                    1 => {
+                        let mut lengths = [8u8; 288];
+                        lengths[144..256].fill(9);
+                        lengths[256..280].fill(7);
+                        inflater.codes(&Self::table(&lengths), &Self::table(&[5; 30]));
+                    }
+                    2 => {
+                        let literals = inflater.bits(5) as usize + 257;
+                        let distances = inflater.bits(5) as usize + 1;
+                        let code_lengths = inflater.bits(4) as usize + 4;
+                        let mut order = [0u8; 19];
+                        for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+                            order[symbol] = inflater.bits(3) as u8;
+                        }
+                        let order = Self::table(&order);
+                        let mut lengths = Vec::new();
+                        while lengths.len() < literals + distances {
+                            match inflater.decode(&order) {
+                                symbol @ 0..=15 => lengths.push(symbol as u8),
+                                16 => {
+                                    let previous = *lengths.last().unwrap();
+                                    let repeat = 3 + inflater.bits(2) as usize;
+                                    lengths.extend(std::iter::repeat_n(previous, repeat));
+                                }
+                                17 => lengths.extend(std::iter::repeat_n(0, 3 + inflater.bits(3) as usize)),
+                                _ => lengths.extend(std::iter::repeat_n(0, 11 + inflater.bits(7) as usize)),
+                            }
+                        }
+                        inflater.codes(&Self::table(&lengths[..literals]), &Self::table(&lengths[literals..]));
+                    }
+                    _ => panic!("reserved block type"),
+                }
+                if last {
+                    return inflater.out;
+                }
+            }
+        }
+
+        fn codes(&mut self, literals: &([u16; 16], Vec<u16>), distances: &([u16; 16], Vec<u16>)) {
+            loop {
+                let symbol = self.decode(literals);
+                match symbol {
+                    0..=255 => self.out.push(symbol as u8),
+                    256 => return,
+                    _ => {
+                        let index = symbol - 257;
+                        let len = LENGTH_BASE[index] as usize + self.bits(LENGTH_EXTRA[index] as u32) as usize;
+                        let index = self.decode(distances);
+                        let dist = DIST_BASE[index] as usize + self.bits(DIST_EXTRA[index] as u32) as usize;
+                        let start = self.out.len() - dist;
+                        for i in 0..len {
+                            let byte = self.out[start + i];
+                            self.out.push(byte);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn cookie_keys(signing_key: &str, verify_keys: &[&str]) -> CookieKeys {
+        let config = CookieConfig {
+            signing_key: Some(signing_key.to_string()),
+            verify_keys: verify_keys.iter().map(|key| key.to_string()).collect(),
+        };
+        CookieKeys::from_config(&config).unwrap()
+    }
+
+    const OLD_SECRET: &str = "an-older-secret-that-is-long-enough-to-use";
+    const NEW_SECRET: &str = "the-current-secret-that-is-long-enough-too";
+
+    /// Test cases 1, 2, 6 and 7 from RFC 4231
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        assert_eq!(
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. \
+                  The key needs to be hashed before being used by the HMAC algorithm."
+            )),
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2"
+        );
+    }
+
+    #[test]
+    fn base64url_round_trips() {
+        for len in 0..70 {
+            let data = pseudo_random(len, len as u64 + 1);
+            let encoded = base64url_encode(&data);
+            assert!(!encoded.contains(['=', '+', '/']));
+            assert_eq!(base64url_decode(&encoded), Some(data));
+        }
+        assert_eq!(base64url_encode(b"\xfb\xff"), "-_8");
+    }
+
+    #[test]
+    fn signed_cookie_verifies() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let cookie = keys.sign(Cookie::new("user", "alice; admin=1"));
+        assert_eq!(keys.verify("user", &cookie.value).as_deref(), Some("alice; admin=1"));
+        // The signature is bound to the cookie name
+        assert_eq!(keys.verify("other", &cookie.value), None);
+    }
+
+    #[test]
+    fn tampered_signed_cookie_is_rejected() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let cookie = keys.sign(Cookie::new("user", "alice"));
+        let (_, signature) = cookie.value.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", base64url_encode(b"admin"), signature);
+        assert_eq!(keys.verify("user", &forged), None);
+        assert_eq!(keys.verify("user", "alice"), None);
+
+        let mut flipped = cookie.value.into_bytes();
+        flipped[0] ^= 1;
+        assert_eq!(keys.verify("user", &String::from_utf8(flipped).unwrap()), None);
+    }
+
+    #[test]
+    fn rotated_keys_still_verify() {
+        let old = cookie_keys(OLD_SECRET, &[]);
+        let signed = old.sign(Cookie::new("user", "alice"));
+        let sealed = old.encrypt(Cookie::new("token", "secret")).unwrap();
+
+        let rotated = cookie_keys(NEW_SECRET, &[OLD_SECRET]);
+        assert_eq!(rotated.verify("user", &signed.value).as_deref(), Some("alice"));
+        assert_eq!(rotated.decrypt("token", &sealed.value).as_deref(), Some("secret"));
+        // New cookies use the new key only
+        let fresh = rotated.sign(Cookie::new("user", "bob"));
+        assert_eq!(old.verify("user", &fresh.value), None);
+
+        let dropped = cookie_keys(NEW_SECRET, &[]);
+        assert_eq!(dropped.verify("user", &signed.value), None);
+        assert_eq!(dropped.decrypt("token", &sealed.value), None);
+    }
+
+    #[test]
+    fn private_cookie_round_trips() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let sealed = keys.encrypt(Cookie::new("token", "top secret")).unwrap();
+        assert!(!sealed.value.contains("secret"));
+        assert_eq!(keys.decrypt("token", &sealed.value).as_deref(), Some("top secret"));
+        assert_eq!(keys.decrypt("other", &sealed.value), None);
+
+        assert_eq!(keys.decrypt("token", "c2hvcnQ"), None);
+        assert_eq!(keys.decrypt("token", ""), None);
+        assert_eq!(keys.decrypt("token", "not base64!"), None);
+    }
+
+    #[test]
+    fn private_cookie_rejects_any_altered_byte() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let sealed = keys.encrypt(Cookie::new("token", "top secret")).unwrap();
+        let raw = base64url_decode(&sealed.value).unwrap();
+        assert_eq!(raw.len(), CookieKeys::NONCE_LEN + "top secret".len() + CookieKeys::TAG_LEN);
+        // Nonce, ciphertext and tag are all covered
+        for i in 0..raw.len() {
+            let mut altered = raw.clone();
+            altered[i] ^= 0x80;
+            assert_eq!(keys.decrypt("token", &base64url_encode(&altered)), None, "byte {}", i);
+        }
+        for len in 0..raw.len() {
+            assert_eq!(keys.decrypt("token", &base64url_encode(&raw[..len])), None, "length {}", len);
+        }
+        // A fresh nonce each time, so equal values do not encrypt alike
+        let again = keys.encrypt(Cookie::new("token", "top secret")).unwrap();
+        assert_ne!(again.value, sealed.value);
+    }
+
+    #[test]
+    fn signed_cookie_rejects_altered_signatures() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let cookie = keys.sign(Cookie::new("user", "alice"));
+        let (value, signature) = cookie.value.rsplit_once('.').unwrap();
+        let raw = base64url_decode(signature).unwrap();
+        for i in 0..raw.len() {
+            let mut altered = raw.clone();
+            altered[i] ^= 1;
+            let forged = format!("{}.{}", value, base64url_encode(&altered));
+            assert_eq!(keys.verify("user", &forged), None, "byte {}", i);
+        }
+        // Truncated signatures never verify, not even a prefix of the right one
+        for len in 0..raw.len() {
+            let forged = format!("{}.{}", value, base64url_encode(&raw[..len]));
+            assert_eq!(keys.verify("user", &forged), None, "length {}", len);
+        }
+    }
+
+    fn sessions() -> Sessions {
+        Sessions::from_config(&SessionConfig::default()).unwrap()
+    }
+
+    fn stored_session(sessions: &mut Sessions, last_access: u64) -> Session {
+        let record = SessionRecord {
+            data: HashMap::from([("user".to_string(), "alice".to_string())]),
+            created: last_access,
+            last_access,
+        };
+        sessions.store.save("abc", &record).unwrap();
+        Session {
+            id: Some("abc".to_string()),
+            record,
+            ..Session::default()
+        }
+    }
+
+    #[test]
+    fn unchanged_session_is_not_rewritten() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let mut sessions = sessions();
+        let recent = Sessions::now() - 10;
+        let session = stored_session(&mut sessions, recent);
+        sessions.save(session, &mut HttpResponse::new(200, "OK", ""), &keys);
+        assert_eq!(sessions.store.load("abc").unwrap().unwrap().last_access, recent);
+    }
+
+    #[test]
+    fn session_is_touched_when_changed_or_stale() {
+        let keys = cookie_keys(NEW_SECRET, &[]);
+        let mut sessions = sessions();
+        let recent = Sessions::now() - 10;
+        let mut session = stored_session(&mut sessions, recent);
+        session.insert("theme", "dark");
+        sessions.save(session, &mut HttpResponse::new(200, "OK", ""), &keys);
+        let record = sessions.store.load("abc").unwrap().unwrap();
+        assert!(record.last_access > recent);
+        assert_eq!(record.data.get("theme").map(String::as_str), Some("dark"));
+
+        let idle = sessions.config.idle_timeout_secs;
+        let stale = Sessions::now() - idle / 2;
+        let session = stored_session(&mut sessions, stale);
+        sessions.save(session, &mut HttpResponse::new(200, "OK", ""), &keys);
+        assert!(sessions.store.load("abc").unwrap().unwrap().last_access > stale);
+    }
+
+    #[test]
+    fn sweep_drops_expired_sessions() {
+        let mut sessions = sessions();
+        let now = Sessions::now();
+        let idle = sessions.config.idle_timeout_secs;
+        let absolute = sessions.config.absolute_timeout_secs;
+        let record = |created, last_access| SessionRecord { data: HashMap::new(), created, last_access };
+        sessions.store.save("live", &record(now - 60, now - 60)).unwrap();
+        sessions.store.save("idle", &record(now - idle - 60, now - idle - 1)).unwrap();
+        sessions.store.save("old", &record(now - absolute, now)).unwrap();
+        sessions.last_sweep = Instant::now() - Duration::from_secs(sessions.config.sweep_interval_secs);
+        sessions.sweep();
+        assert!(sessions.store.load("live").unwrap().is_some());
+        assert!(sessions.store.load("idle").unwrap().is_none());
+        assert!(sessions.store.load("old").unwrap().is_none());
+    }
+
+    fn deflate_samples() -> Vec<Vec<u8>> {
+        let mut text = Vec::new();
+        for i in 0..5000 {
+            text.extend_from_slice(format!("<tr><td>row {}</td><td>{}</td></tr>\n", i, i * 7 % 13).as_bytes());
+        }
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            vec![b'x'; 100_000],
+            b"abcabcabcabcabcabcabcabcabc".repeat(1000),
+            text,
+            pseudo_random(200_000, 0x9E3779B97F4A7C15),
+        ]
+    }
+
+    #[test]
+    fn gzip_header_and_trailer() {
+        for sample in deflate_samples() {
+            let gzip = Compression::encode(ContentCoding::Gzip, &sample).unwrap();
+            assert_eq!(&gzip[..4], &[0x1f, 0x8b, 8, 0]);
+            let trailer = &gzip[gzip.len() - 8..];
+            assert_eq!(trailer[..4], crc32(&sample).to_le_bytes());
+            assert_eq!(trailer[4..], (sample.len() as u32).to_le_bytes());
+            assert_eq!(Inflater::inflate(&gzip[10..gzip.len() - 8]), sample, "length {}", sample.len());
+        }
+        // Check value from the CRC-32 catalogue
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn zlib_header_and_trailer() {
+        for sample in deflate_samples() {
+            let zlib = Compression::encode(ContentCoding::Deflate, &sample).unwrap();
+            assert_eq!(zlib[0] & 0x0F, 8);
+            assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+            assert_eq!(zlib[zlib.len() - 4..], adler32(&sample).to_be_bytes());
+            assert_eq!(Inflater::inflate(&zlib[2..zlib.len() - 4]), sample, "length {}", sample.len());
+        }
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn compression_skips_bodies_over_the_cap() {
+        let config = config("");
+        let compression = Compression::from_config(&config.server);
+        assert_eq!(compression.max_length, 256 * 1024);
+        let mut req = request("GET", "/");
+        req.headers.insert("Accept-Encoding", "gzip");
+        for (len, compressed) in [(compression.max_length as usize, true), (compression.max_length as usize + 1, false)] {
+            let mut response = HttpResponse::new(200, "OK", &"x".repeat(len));
+            response.headers.insert("Content-Type", "text/plain");
+            let response = compression.apply(&req, response);
+            assert_eq!(response.headers.get("Content-Encoding").is_some(), compressed, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn parser_moves_through_each_state() {
+        let mut parser = HttpParser::new(limits());
+        let mut buffer = b"POST /form HT".to_vec();
+        assert!(parser.advance(&mut buffer).unwrap().is_none());
+        assert_eq!(parser.state, ParseState::RequestLine);
+        assert!(parser.is_idle());
+
+        buffer.extend_from_slice(b"TP/1.1\r\nHost: localhost\r\nContent-Le");
+        assert!(parser.advance(&mut buffer).unwrap().is_none());
+        assert_eq!(parser.state, ParseState::Headers);
+
+        buffer.extend_from_slice(b"ngth: 11\r\n\r\nhello");
+        assert!(parser.advance(&mut buffer).unwrap().is_none());
+        assert_eq!(parser.state, ParseState::Body { remaining: 6 });
+        assert!(parser.in_body());
+
+        buffer.extend_from_slice(b" world");
+        let request = parser.advance(&mut buffer).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/form");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("content-length"), Some("11"));
+        assert_eq!(request.body, b"hello world");
+        assert!(buffer.is_empty());
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn parser_accepts_a_request_one_byte_at_a_time() {
+        let raw = b"\r\nPUT /a?x=1&y=%C3%A9 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc";
+        let mut parser = HttpParser::new(limits());
+        let mut buffer = Vec::new();
+        for (i, &byte) in raw.iter().enumerate() {
+            buffer.push(byte);
+            let result = parser.advance(&mut buffer).unwrap();
+            if i + 1 < raw.len() {
+                assert!(result.is_none(), "complete after {} bytes", i + 1);
+                continue;
+            }
+            let request = result.unwrap();
+            assert_eq!(request.method, "PUT");
+            assert_eq!(request.path, "/a");
+            assert_eq!(request.query_string.as_deref(), Some("x=1&y=%C3%A9"));
+            assert_eq!(request.query_params["y"], "é");
+            assert_eq!(request.body, b"abc");
+        }
+    }
+
+    #[test]
+    fn parser_completes_bodiless_requests_at_the_blank_line() {
+        let mut buffer = b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let mut parser = HttpParser::new(limits());
+        let request = parser.advance(&mut buffer).unwrap().unwrap();
+        assert!(request.body.is_empty());
+        // Bare LF line endings are accepted as well
+        let request = parse(b"GET / HTTP/1.1\nHost: localhost\n\n").unwrap().unwrap();
+        assert_eq!(request.header("Host"), Some("localhost"));
+    }
+
+    #[test]
+    fn parser_rejects_malformed_request_lines() {
+        for raw in [
+            "GET /\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1 extra\r\n\r\n",
+            "G(T / HTTP/1.1\r\n\r\n",
+            "GET /a\x01b HTTP/1.1\r\n\r\n",
+            "GET / FTP/1.0\r\n\r\n",
+        ] {
+            assert!(matches!(parse(raw.as_bytes()), Err(ParseError::BadRequest(_))), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn parser_rejects_malformed_headers() {
+        for header in ["NoColon", ": empty-name", "Bad Name: x", " folded: x", "Nul: a\0b"] {
+            let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n\r\n", header);
+            assert!(matches!(parse(raw.as_bytes()), Err(ParseError::BadRequest(_))), "{:?}", header);
+        }
+        // Optional whitespace around the value is trimmed
+        let request = parse(b"GET / HTTP/1.1\r\nX-Test: \t value \t\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.header("x-test"), Some("value"));
+    }
+
+    #[test]
+    fn parser_checks_content_length() {
+        for length in ["abc", "-1", "1, 2", "+5", ""] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+            assert!(matches!(parse(raw.as_bytes()), Err(ParseError::BadRequest(_))), "{:?}", length);
+        }
+        // Repeats of the same value are allowed
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 2, 2\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(parse(raw).unwrap().unwrap().body, b"ok");
+        // An unsupported transfer coding is refused rather than guessed at
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::BadRequest(_))));
+    }
+
+    #[test]
+    fn parse_errors_map_to_statuses() {
+        let statuses: Vec<u16> = [
+            ParseError::BadRequest(String::new()),
+            ParseError::UriTooLong,
+            ParseError::HeaderFieldsTooLarge,
+            ParseError::PayloadTooLarge,
+        ]
+        .iter()
+        .map(|error| error.to_response().status)
+        .collect();
+        assert_eq!(statuses, [400, 414, 431, 413]);
+    }
+
+    #[test]
+    fn request_bodies_keep_every_byte() {
+        let body: Vec<u8> = (0..=255u8).chain([0, b'\r', b'\n', 0xFF]).collect();
+        let mut raw = format!("POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend_from_slice(&body);
+        assert_eq!(parse(&raw).unwrap().unwrap().body, body);
+    }
+
+    #[test]
+    fn multipart_file_parts_are_binary_safe() {
+        let file: Vec<u8> = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', 0, 0xFF, b'-', b'-'];
+        let mut body = Vec::new();
+        body.extend_from_slice(b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nh\xC3\xA9llo\r\n");
+        body.extend_from_slice(
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+              Content-Type: image/png\r\n\r\n",
+        );
+        body.extend_from_slice(&file);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+        let mut raw = format!(
+            "POST /form HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=\"XyZ\"\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&body);
+
+        let request = parse(&raw).unwrap().unwrap();
+        assert_eq!(request.form_fields["title"], "héllo");
+        let upload = &request.form_files["file"];
+        assert_eq!(upload.filename, "a.png");
+        assert_eq!(upload.content_type, "image/png");
+        assert_eq!(upload.data, file);
+    }
+
+    #[test]
+    fn urlencoded_forms_are_decoded() {
+        let body = "name=J%C3%BCrgen+M&empty=&plus=%2B";
+        let raw = format!(
+            "POST /form HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let request = parse(raw.as_bytes()).unwrap().unwrap();
+        assert_eq!(request.form_fields["name"], "Jürgen M");
+        assert_eq!(request.form_fields["empty"], "");
+        assert_eq!(request.form_fields["plus"], "+");
+    }
+
+    #[test]
+    fn cgi_output_keeps_binary_bodies() {
+        let mut output = b"Status: 201 Created\r\nContent-Type: application/octet-stream\r\nX-Extra: 1\r\n\r\n".to_vec();
+        output.extend_from_slice(&[0, 0xFF, b'\r', b'\n', b'\r', b'\n', 7]);
+        let response = CGIExecutor::parse_cgi_response(&output).unwrap();
+        assert_eq!((response.status, response.status_text.as_str()), (201, "Created"));
+        assert_eq!(response.headers.get("Content-Type"), Some("application/octet-stream"));
+        assert_eq!(response.headers.get("X-Extra"), Some("1"));
+        assert_eq!(response.headers.get("Content-Length"), Some("7"));
+        assert!(matches!(&response.body, Body::Bytes(bytes) if bytes == &[0, 0xFF, b'\r', b'\n', b'\r', b'\n', 7]));
+
+        // LF-only header blocks and a missing Content-Type are tolerated
+        let response = CGIExecutor::parse_cgi_response(b"X-A: b\n\nbody").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/html"));
+        assert_eq!(body_text(&response), "body");
+    }
+
+    fn chunked(body: &str) -> Result<Option<HttpRequest>, ParseError> {
+        let raw = format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{}", body);
+        parse(raw.as_bytes())
+    }
+
+    #[test]
+    fn chunked_body_with_extensions_and_trailers() {
+        let request = chunked(
+            "5;name=value\r\nhello\r\n\
+             6 ; quoted=\"a;b\\\"c\" ; flag\r\n world\r\n\
+             0\r\nX-Checksum: abc\r\nX-Checksum: def\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.trailers.get_all("x-checksum").collect::<Vec<_>>(), ["abc", "def"]);
+        // Trailers stay out of the header section
+        assert!(request.header("X-Checksum").is_none());
+    }
+
+    #[test]
+    fn chunked_parser_moves_through_each_state() {
+        let mut parser = HttpParser::new(limits());
+        let mut buffer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 99\r\n\r\n".to_vec();
+        assert!(parser.advance(&mut buffer).unwrap().is_none());
+        assert_eq!(parser.state, ParseState::ChunkSize);
+        assert!(parser.headers.get("Content-Length").is_none());
+
+        let steps: [(&[u8], ParseState); 5] = [
+            (b"A\r\n0123", ParseState::ChunkData { remaining: 6 }),
+            (b"456789", ParseState::ChunkDataEnd),
+            (b"\r\n", ParseState::ChunkSize),
+            (b"0\r\n", ParseState::ChunkTrailer),
+            (b"Expires: never\r\n", ParseState::ChunkTrailer),
+        ];
+        for (bytes, state) in steps {
+            buffer.extend_from_slice(bytes);
+            assert!(parser.advance(&mut buffer).unwrap().is_none());
+            assert_eq!(parser.state, state);
+        }
+        buffer.extend_from_slice(b"\r\nGET /next");
+        let request = parser.advance(&mut buffer).unwrap().unwrap();
+        assert_eq!(request.body, b"0123456789");
+        assert_eq!(request.trailers.get("Expires"), Some("never"));
+        assert_eq!(buffer, b"GET /next");
+    }
+
+    #[test]
+    fn chunked_parser_rejects_bad_framing() {
+        for body in [
+            "5\r\nhelloX\r\n0\r\n\r\n",
+            "g\r\n",
+            ";ext\r\n",
+            "5;\r\n",
+            "5;a=\r\n",
+            "5;a=\"open\r\n",
+            "5 junk\r\n",
+            "ffffffffffffffffff\r\n",
+            "0\r\nno colon\r\n\r\n",
+        ] {
+            assert!(matches!(chunked(body), Err(ParseError::BadRequest(_))), "{:?}", body);
+        }
+        let long_line = format!("5;{}", "a".repeat(MAX_CHUNK_LINE + 1));
+        assert!(matches!(chunked(&long_line), Err(ParseError::BadRequest(_))));
+    }
+
+    #[test]
+    fn chunked_bodies_count_against_the_body_limit() {
+        let mut parser = HttpParser::new(ParserLimits { max_body_size: 8, ..limits() });
+        let mut buffer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n".to_vec();
+        assert!(parser.advance(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(b"4\r\n");
+        assert!(matches!(parser.advance(&mut buffer), Err(ParseError::PayloadTooLarge)));
+    }
+
+    /// A server on an ephemeral loopback port, with `settings` added to its
+    /// [server] section
+    fn test_server(name: &str, settings: &str) -> Server {
+        let servers = "[[servers]]\nlisten = [\"127.0.0.1:0\"]\n";
+        let text = config_text(servers).replacen("[server]\n", &format!("[server]\n{}\n", settings), 1);
+        start_server(name, &text)
+    }
+
+    /// A server set up from the config file text `text`
+    fn start_server(name: &str, text: &str) -> Server {
+        let dir = temp_dir(name);
+        let path = dir.join("config.toml");
+        fs::write(&path, text).unwrap();
+        let server = Server::new(&path.to_string_lossy()).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        server
+    }
+
+    /// Open a client connection and have the server accept it. Returns the
+    /// client side and the server's descriptor for the connection.
+    fn connect(server: &mut Server) -> (TcpStream, RawFd) {
+        let (&listener_fd, listener) = server.listeners.iter().next().unwrap();
+        let client = TcpStream::connect(listener.listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        server.accept_connection(listener_fd).unwrap();
+        let fd = *server.connections.keys().next().unwrap();
+        (client, fd)
+    }
+
+    /// Write `raw` from the client, let the server read all of it and answer
+    /// what it can. Returns whether the server keeps the connection.
+    fn send(server: &mut Server, fd: RawFd, client: &mut TcpStream, raw: &[u8]) -> bool {
+        client.write_all(raw).unwrap();
+        let expected = server.connections[&fd].buffer.len() + raw.len();
+        while server.connections[&fd].buffer.len() < expected {
+            server.handle_client_data(fd).unwrap();
+        }
+        server.process_requests(fd)
+    }
+
+    /// Everything the client can read before the server goes quiet
+    fn received(client: &mut TcpStream) -> String {
+        let mut data = Vec::new();
+        let mut chunk = [0; 64 * 1024];
+        while let Ok(n @ 1..) = client.read(&mut chunk) {
+            data.extend_from_slice(&chunk[..n]);
+        }
+        String::from_utf8_lossy(&data).into_owned()
+    }
+
+    /// Status lines of the responses in `responses`. Bodies need not end in
+    /// a newline, so a status line may follow one directly.
+    fn status_lines(responses: &str) -> Vec<&str> {
+        responses
+            .match_indices("HTTP/1.1 ")
+            .map(|(start, _)| &responses[start..start + responses[start..].find("\r\n").unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut server = test_server("pipelining", "");
+        let (mut client, fd) = connect(&mut server);
+        let raw = b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                    GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                    GET /hea";
+        assert!(send(&mut server, fd, &mut client, raw));
+        let responses = received(&mut client);
+        assert_eq!(status_lines(&responses), ["HTTP/1.1 200 OK", "HTTP/1.1 404 Not Found"]);
+        assert_eq!(responses.matches("Connection: keep-alive\r\n").count(), 2);
+        // The start of the third request waits for the rest of it
+        assert_eq!(server.connections[&fd].buffer, b"GET /hea");
+
+        assert!(!send(&mut server, fd, &mut client, b"lth HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        let responses = received(&mut client);
+        assert_eq!(status_lines(&responses), ["HTTP/1.1 200 OK"]);
+        assert!(responses.contains("Connection: close\r\n"));
+    }
 
-fn handle_users(req: &HttpRequest) -> HttpResponse {
-    let body = format!(
-        r#"{{"path": "{}", "method": "{}"}}"#,
-        req.path, req.method
-    );
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("application/json")
-        .body_text(&body)
-        .cookie_with_options("user_session", "session_12345", Some(3600), "/api", true)
-        .build()
-}
+    #[test]
+    fn keep_alive_stops_after_the_request_limit() {
+        let mut server = test_server("keep-alive-limit", "max_keep_alive_requests = 2\nkeep_alive_timeout_ms = 1500");
+        let (mut client, fd) = connect(&mut server);
+        assert!(send(&mut server, fd, &mut client, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert!(received(&mut client).contains("Keep-Alive: timeout=2, max=1\r\n"));
+        assert!(!send(&mut server, fd, &mut client, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        let response = received(&mut client);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("Keep-Alive"));
+    }
 
-fn handle_api_catch_all(req: &HttpRequest) -> HttpResponse {
-    let body = format!(
-        r#"{{"message": "API endpoint", "path": "{}", "method": "{}", "timestamp": "2025-12-09T20:00:00Z"}}"#,
-        req.path, req.method
-    );
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("application/json")
-        .body_text(&body)
-        .header("X-API-Version", "1.0")
-        .build()
-}
+    #[test]
+    fn http_1_0_closes_unless_asked_to_keep_alive() {
+        let mut server = test_server("http-1-0", "");
+        let (mut client, fd) = connect(&mut server);
+        assert!(send(&mut server, fd, &mut client, b"GET /health HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"));
+        assert!(received(&mut client).contains("Connection: keep-alive\r\n"));
+        assert!(!send(&mut server, fd, &mut client, b"GET /health HTTP/1.0\r\n\r\n"));
+        assert!(received(&mut client).contains("Connection: close\r\n"));
+    }
 
-fn handle_inspect(req: &HttpRequest) -> HttpResponse {
-    let mut body = String::from(r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Request Inspector</title>
-    <style>
-        body { font-family: monospace; margin: 20px; }
-        .section { margin: 20px 0; padding: 10px; background: #f5f5f5; border-left: 4px solid #667eea; }
-        h2 { color: #667eea; margin-top: 0; }
-        table { width: 100%; border-collapse: collapse; }
-        td { padding: 8px; border-bottom: 1px solid #ddd; }
-        td:first-child { font-weight: bold; color: #333; width: 20%; }
-        a { color: #667eea; text-decoration: none; }
-        a:hover { text-decoration: underline; }
-    </style>
-</head>
-<body>
-    <h1>Request Inspector</h1>
-"#);
-    
-    // Request line info
-    body.push_str(&format!(
-        r#"<div class="section">
-        <h2>Request Line</h2>
-        <table>
-            <tr><td>Method:</td><td>{}</td></tr>
-            <tr><td>Path:</td><td>{}</td></tr>
-            <tr><td>Query String:</td><td>{}</td></tr>
-            <tr><td>HTTP Version:</td><td>{}</td></tr>
-        </table>
-    </div>"#,
-        req.method,
-        req.path,
-        req.query_string.as_ref().unwrap_or(&"(none)".to_string()),
-        req.version
-    ));
-    
-    // Headers
-    if !req.headers.is_empty() {
-        body.push_str(r#"<div class="section">
-        <h2>Headers</h2>
-        <table>"#);
-        for (key, value) in &req.headers {
-            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", key, value));
+    #[test]
+    fn keep_alive_follows_version_and_connection_tokens() {
+        let wants = |version: &str, connection: &[&str]| {
+            let mut raw = format!("GET / {}\r\n", version);
+            for value in connection {
+                raw.push_str(&format!("Connection: {}\r\n", value));
+            }
+            raw.push_str("\r\n");
+            parse(raw.as_bytes()).unwrap().unwrap().wants_keep_alive()
+        };
+        assert!(wants("HTTP/1.1", &[]));
+        assert!(wants("HTTP/1.1", &["keep-alive"]));
+        assert!(!wants("HTTP/1.1", &["upgrade, Close"]));
+        assert!(!wants("HTTP/1.1", &["te", "close"]));
+        assert!(!wants("HTTP/1.0", &[]));
+        assert!(wants("HTTP/1.0", &["KEEP-ALIVE"]));
+    }
+
+    #[test]
+    fn output_resumes_on_epollout_before_pipelined_requests() {
+        let mut server = test_server("output-queue", "");
+        let (mut client, fd) = connect(&mut server);
+        let epoll_fd = server.epoll_fd;
+        let large = "x".repeat(8 * 1024 * 1024);
+        let connection = server.connections.get_mut(&fd).unwrap();
+        connection.queue_response(HttpResponse::new(200, "OK", &large));
+        connection.queue_response(HttpResponse::new(200, "OK", "second"));
+        // Consecutive in-memory responses share one buffer
+        assert_eq!(connection.output.len(), 1);
+        assert!(connection.flush(epoll_fd));
+        assert!(connection.has_pending_output());
+        assert_eq!(connection.interest, EPOLLOUT as u32);
+
+        // A pipelined request is read but not answered while output is pending
+        let pipelined = b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(send(&mut server, fd, &mut client, pipelined));
+        assert_eq!(server.connections[&fd].buffer, pipelined);
+
+        // Each time the client catches up, the next flush picks up where the last stopped
+        let mut data = Vec::new();
+        let mut chunk = vec![0; 256 * 1024];
+        while server.connections[&fd].has_pending_output() {
+            let n = client.read(&mut chunk).unwrap();
+            data.extend_from_slice(&chunk[..n]);
+            assert!(server.connections.get_mut(&fd).unwrap().flush(epoll_fd));
         }
-        body.push_str("</table></div>");
+        assert_eq!(server.connections[&fd].interest, EPOLLIN as u32);
+        assert!(server.process_requests(fd));
+        assert!(server.connections[&fd].buffer.is_empty());
+
+        let responses = String::from_utf8(data).unwrap() + &received(&mut client);
+        assert_eq!(status_lines(&responses), ["HTTP/1.1 200 OK"; 3]);
+        let body_start = responses.find("\r\n\r\n").unwrap() + 4;
+        assert_eq!(&responses[body_start..body_start + large.len()], large);
+        let rest = &responses[body_start + large.len()..];
+        assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rest.contains("\r\n\r\nsecondHTTP/1.1 200 OK\r\n"));
     }
-    
-    // Cookies
-    if !req.cookies.is_empty() {
-        body.push_str(r#"<div class="section">
-        <h2>Cookies</h2>
-        <table>"#);
-        for (name, value) in &req.cookies {
-            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", name, value));
+
+    const TIMEOUTS: &str = "header_timeout_ms = 1000\nbody_timeout_ms = 2000\n\
+                            keep_alive_timeout_ms = 3000\nwrite_timeout_ms = 4000";
+
+    /// What the connection's deadline check says `ms` after `since`
+    fn expiry_after(server: &Server, fd: RawFd, since: fn(&Connection) -> Instant, ms: u64) -> Option<Expiry> {
+        let connection = &server.connections[&fd];
+        connection.expiry(&server.config.server, since(connection) + Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn silent_connections_close_after_the_header_timeout() {
+        let mut server = test_server("timeout-silent", TIMEOUTS);
+        let (_client, fd) = connect(&mut server);
+        let since = |c: &Connection| c.request_started;
+        assert!(expiry_after(&server, fd, since, 999).is_none());
+        assert!(matches!(expiry_after(&server, fd, since, 1000), Some(Expiry::Close)));
+    }
+
+    #[test]
+    fn slow_headers_and_bodies_time_out_with_408() {
+        let mut server = test_server("timeout-slow", TIMEOUTS);
+        let (mut client, fd) = connect(&mut server);
+        assert!(send(&mut server, fd, &mut client, b"POST /form-test HTTP/1.1\r\nHost: loc"));
+        let since = |c: &Connection| c.request_started;
+        assert!(expiry_after(&server, fd, since, 999).is_none());
+        assert!(matches!(expiry_after(&server, fd, since, 1000), Some(Expiry::RequestTimeout)));
+
+        // Once in the body, the deadline moves with every read
+        assert!(send(&mut server, fd, &mut client, b"alhost\r\nContent-Length: 10\r\n\r\nabc"));
+        let since = |c: &Connection| c.last_read;
+        assert!(expiry_after(&server, fd, since, 1999).is_none());
+        assert!(matches!(expiry_after(&server, fd, since, 2000), Some(Expiry::RequestTimeout)));
+    }
+
+    #[test]
+    fn idle_keep_alive_connections_close_quietly() {
+        let mut server = test_server("timeout-idle", TIMEOUTS);
+        let (mut client, fd) = connect(&mut server);
+        assert!(send(&mut server, fd, &mut client, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        let since = |c: &Connection| std::cmp::max(c.last_read, c.last_write);
+        assert!(expiry_after(&server, fd, since, 2999).is_none());
+        assert!(matches!(expiry_after(&server, fd, since, 3000), Some(Expiry::Close)));
+    }
+
+    #[test]
+    fn stalled_readers_close_after_the_write_timeout() {
+        let mut server = test_server("timeout-write", TIMEOUTS);
+        let (_client, fd) = connect(&mut server);
+        let epoll_fd = server.epoll_fd;
+        let connection = server.connections.get_mut(&fd).unwrap();
+        connection.queue_response(HttpResponse::new(200, "OK", &"x".repeat(8 * 1024 * 1024)));
+        assert!(connection.flush(epoll_fd));
+        assert!(connection.has_pending_output());
+        let since = |c: &Connection| c.last_write;
+        assert!(expiry_after(&server, fd, since, 3999).is_none());
+        assert!(matches!(expiry_after(&server, fd, since, 4000), Some(Expiry::Close)));
+    }
+
+    #[test]
+    fn expired_requests_are_answered_and_closed() {
+        let mut server = test_server("timeout-expire", TIMEOUTS);
+        let (mut client, fd) = connect(&mut server);
+        assert!(send(&mut server, fd, &mut client, b"GET / HTTP/1.1\r\n"));
+        server.expire_connections().unwrap();
+        assert!(server.connections.contains_key(&fd));
+
+        let connection = server.connections.get_mut(&fd).unwrap();
+        connection.request_started -= Duration::from_millis(1000);
+        server.expire_connections().unwrap();
+        assert!(!server.connections.contains_key(&fd));
+        let response = received(&mut client);
+        assert_eq!(status_lines(&response), ["HTTP/1.1 408 Request Timeout"]);
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    fn parse_with(limits: ParserLimits, raw: &[u8]) -> Result<Option<HttpRequest>, ParseError> {
+        HttpParser::new(limits).advance(&mut raw.to_vec())
+    }
+
+    #[test]
+    fn long_request_lines_get_414() {
+        let small = ParserLimits { max_header_bytes: 64, ..limits() };
+        let target = format!("/{}", "a".repeat(64));
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        assert!(matches!(parse_with(small, raw.as_bytes()), Err(ParseError::UriTooLong)));
+        // Refused before the line is complete, so a client cannot stream one forever
+        let partial = &raw.as_bytes()[..65];
+        assert!(matches!(parse_with(small, partial), Err(ParseError::UriTooLong)));
+        assert!(parse_with(small, &raw.as_bytes()[..64]).unwrap().is_none());
+    }
+
+    #[test]
+    fn large_header_sections_get_431() {
+        let small = ParserLimits { max_header_bytes: 64, max_headers: 3, ..limits() };
+        let raw = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "v".repeat(40));
+        assert!(matches!(parse_with(small, raw.as_bytes()), Err(ParseError::HeaderFieldsTooLarge)));
+        let partial = format!("GET / HTTP/1.1\r\nX-Long: {}", "v".repeat(60));
+        assert!(matches!(parse_with(small, partial.as_bytes()), Err(ParseError::HeaderFieldsTooLarge)));
+
+        let fields = |count: usize| format!("GET / HTTP/1.1\r\n{}\r\n", "A: 1\r\n".repeat(count));
+        assert!(parse_with(small, fields(3).as_bytes()).unwrap().is_some());
+        assert!(matches!(parse_with(small, fields(4).as_bytes()), Err(ParseError::HeaderFieldsTooLarge)));
+
+        // Trailers count against the same limits
+        let trailers = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{}\r\n",
+            "T: 1\r\n".repeat(4)
+        );
+        assert!(matches!(parse_with(small, trailers.as_bytes()), Err(ParseError::HeaderFieldsTooLarge)));
+    }
+
+    #[test]
+    fn large_bodies_get_413_before_they_are_read() {
+        let small = ParserLimits { max_body_size: 10, ..limits() };
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789";
+        assert_eq!(parse_with(small, raw).unwrap().unwrap().body, b"0123456789");
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n";
+        assert!(matches!(parse_with(small, raw), Err(ParseError::PayloadTooLarge)));
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nb\r\n";
+        assert!(matches!(parse_with(small, raw), Err(ParseError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn continue_is_offered_once_and_only_when_a_body_follows() {
+        let mut parser = HttpParser::new(limits());
+        let mut buffer = b"POST / HTTP/1.1\r\nExpect: 100-Continue\r\nContent-Length: 2\r\n\r\n".to_vec();
+        assert!(parser.advance(&mut buffer).unwrap().is_none());
+        assert!(parser.take_continue());
+        assert!(!parser.take_continue());
+
+        let mut parser = HttpParser::new(limits());
+        let mut buffer = b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 0\r\n\r\n".to_vec();
+        assert!(parser.advance(&mut buffer).unwrap().is_some());
+        assert!(!parser.take_continue());
+    }
+
+    #[test]
+    fn server_sends_100_continue_then_the_response() {
+        let mut server = test_server("continue", "client_max_body_size = 16");
+        let (mut client, fd) = connect(&mut server);
+        let head = b"POST /form-test HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+                     Content-Type: application/x-www-form-urlencoded\r\nContent-Length: 7\r\n\r\n";
+        assert!(send(&mut server, fd, &mut client, head));
+        assert_eq!(received(&mut client), "HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(send(&mut server, fd, &mut client, b"name=ab"));
+        assert_eq!(status_lines(&received(&mut client)), ["HTTP/1.1 200 OK"]);
+
+        // A body over the limit is refused without inviting the client to send it
+        let head = b"POST /form-test HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 17\r\n\r\n";
+        assert!(!send(&mut server, fd, &mut client, head));
+        let response = received(&mut client);
+        assert_eq!(status_lines(&response), ["HTTP/1.1 413 Payload Too Large"]);
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn virtual_hosts_are_picked_by_host_header() {
+        let servers = "[[servers]]\nlisten = [\"127.0.0.1:0\"]\nserver_name = [\"example.test\", \"WWW.Example.Test\"]\n\
+                       [[servers]]\nlisten = [\"127.0.0.1:0\"]\nserver_name = [\"*.tools.test\"]\ndefault = true\n\
+                       [[servers]]\nlisten = [\"127.0.0.1:0\"]\nserver_name = [\"[::1]\"]\n\
+                       [[servers]]\nlisten = [\"127.0.0.2:0\"]\nserver_name = [\"other.test\"]\n";
+        let server = start_server("vhosts", &config_text(servers));
+        let listener_fd = server
+            .listeners
+            .iter()
+            .find(|(_, listener)| listener.addr.ip() == std::net::Ipv4Addr::LOCALHOST)
+            .map(|(&fd, _)| fd)
+            .unwrap();
+        assert_eq!(server.listeners[&listener_fd].servers, [0, 1, 2]);
+        let select = |host: Option<&str>| Server::select_vhost(&server.listeners, &server.vhosts, listener_fd, host);
+
+        assert_eq!(select(Some("example.test")), 0);
+        assert_eq!(select(Some("Example.TEST:8080")), 0);
+        assert_eq!(select(Some("www.example.test.")), 0);
+        assert_eq!(select(Some("api.tools.test")), 1);
+        assert_eq!(select(Some("a.b.tools.test:80")), 1);
+        assert_eq!(select(Some("[::1]:8080")), 2);
+        assert_eq!(select(Some("[::1]")), 2);
+        // No match, or a name served on another address only, goes to the default server
+        assert_eq!(select(Some("tools.test")), 1);
+        assert_eq!(select(Some("xtools.test")), 1);
+        assert_eq!(select(Some("other.test")), 1);
+        assert_eq!(select(None), 1);
+    }
+
+    #[test]
+    fn first_server_is_the_default_unless_one_is_marked() {
+        let servers = "[[servers]]\nlisten = [\"127.0.0.1:0\"]\nserver_name = [\"a.test\"]\n\
+                       [[servers]]\nlisten = [\"127.0.0.1:0\"]\nserver_name = [\"b.test\"]\n";
+        let server = start_server("vhosts-default", &config_text(servers));
+        let (&listener_fd, _) = server.listeners.iter().next().unwrap();
+        assert_eq!(Server::select_vhost(&server.listeners, &server.vhosts, listener_fd, Some("b.test")), 1);
+        assert_eq!(Server::select_vhost(&server.listeners, &server.vhosts, listener_fd, Some("c.test")), 0);
+    }
+
+    fn listing_dir(name: &str) -> std::path::PathBuf {
+        let dir = temp_dir(name);
+        fs::write(dir.join("<img src=x onerror=alert(1)>.txt"), "x").unwrap();
+        fs::write(dir.join("a&b \"q\".txt"), "0123456789").unwrap();
+        fs::create_dir_all(dir.join("sub dir")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn autoindex_escapes_file_names() {
+        let dir = listing_dir("autoindex-html");
+        let location = location(&format!("path = \"/files\"\nalias = {:?}\nautoindex = true", dir.to_string_lossy()), &dir);
+        let response = location.handle(&request("GET", "/files/"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Vary"), Some("Accept"));
+        let html = body_text(&response);
+        assert!(!html.contains("<img"));
+        assert!(html.contains(r#"<a href="%3Cimg%20src=x%20onerror=alert(1)%3E.txt">&lt;img src=x onerror=alert(1)&gt;.txt</a>"#));
+        assert!(html.contains(r#"<a href="a&amp;b%20%22q%22.txt">a&amp;b &quot;q&quot;.txt</a>"#));
+        assert!(html.contains("<title>Index of /files/</title>"));
+        // Directories come first whatever the order
+        let position = |name: &str| html.find(name).unwrap();
+        assert!(position("sub%20dir/") < position("%3Cimg") && position("%3Cimg") < position("a&amp;b"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn autoindex_sorts_and_renders_json() {
+        let dir = listing_dir("autoindex-json");
+        let location = location(&format!("path = \"/files\"\nalias = {:?}\nautoindex = true", dir.to_string_lossy()), &dir);
+        let html = body_text(&location.handle(&request("GET", "/files/?C=S&O=D")));
+        assert!(html.find("a&amp;b").unwrap() < html.find("&lt;img").unwrap());
+        assert!(html.contains(r#"<a href="?C=S&amp;O=A">Size &#9660;</a>"#));
+
+        let mut req = request("GET", "/files/");
+        req.headers.insert("Accept", "application/json");
+        let response = location.handle(&req);
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json"));
+        let json = body_text(&response);
+        assert!(json.starts_with(r#"{"path": "/files/", "entries": [{"name": "sub dir", "type": "directory", "size": 0,"#));
+        assert!(json.contains(r#"{"name": "a&b \"q\".txt", "type": "file", "size": 10,"#));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A 200 for "0123456789" that allows ranges
+    fn rangeable() -> HttpResponse {
+        let mut response = HttpResponse::new(200, "OK", "0123456789");
+        response.headers.insert("Content-Type", "text/plain");
+        response.headers.insert("Accept-Ranges", "bytes");
+        response.headers.insert("ETag", "\"v1\"");
+        response.headers.insert("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT");
+        response
+    }
+
+    fn ranged(range: &str, extra: &[(&str, &str)]) -> HttpResponse {
+        let mut req = request("GET", "/");
+        req.headers.insert("Range", range);
+        for &(name, value) in extra {
+            req.headers.insert(name, value);
         }
-        body.push_str("</table></div>");
-    } else {
-        body.push_str(r#"<div class="section">
-        <h2>Cookies</h2>
-        <p>(no cookies)</p>
-    </div>"#);
+        ByteRanges::apply(&req, rangeable())
     }
-    
-    // Query Parameters
-    if !req.query_params.is_empty() {
-        body.push_str(r#"<div class="section">
-        <h2>Query Parameters</h2>
-        <table>"#);
-        for (key, value) in &req.query_params {
-            body.push_str(&format!("<tr><td>{}:</td><td>{}</td></tr>", key, value));
+
+    #[test]
+    fn range_headers_are_parsed_and_coalesced() {
+        assert_eq!(ByteRanges::parse("bytes=0-0", 10), Some(vec![(0, 0)]));
+        assert_eq!(ByteRanges::parse("Bytes = 2-4 , -3", 10), Some(vec![(2, 4), (7, 9)]));
+        assert_eq!(ByteRanges::parse("bytes=8-,0-99999999999999999999999", 10), Some(vec![(0, 9)]));
+        assert_eq!(ByteRanges::parse("bytes=0-3,2-5,6-7", 10), Some(vec![(0, 7)]));
+        assert_eq!(ByteRanges::parse("bytes=-20", 10), Some(vec![(0, 9)]));
+        assert_eq!(ByteRanges::parse("bytes=10-,-0", 10), Some(vec![]));
+        for invalid in ["bytes=5-2", "items=0-1", "bytes=a-b", "bytes=1", "bytes=-", "0-1"] {
+            assert_eq!(ByteRanges::parse(invalid, 10), None, "{}", invalid);
         }
-        body.push_str("</table></div>");
-    } else {
-        body.push_str(r#"<div class="section">
-        <h2>Query Parameters</h2>
-        <p>(no query parameters)</p>
-    </div>"#);
     }
-    
-    body.push_str(r#"<div class="section">
-        <h2>Test Links</h2>
-        <p><a href="/inspect?name=John&age=30&city=NYC">With Query Params</a></p>
-    </div>
-    
-    <div class="section">
-        <h2>cURL Examples</h2>
-        <p>Test with cookies:<br>
-        <code>curl -b "session_id=abc123; user_id=42" http://localhost:8080/inspect</code></p>
-        <p>Test with query params:<br>
-        <code>curl "http://localhost:8080/inspect?key=value&name=test"</code></p>
-    </div>
-    
-    </body>
-</html>"#);
-    
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("text/html; charset=utf-8")
-        .body_text(&body)
-        .header("X-Inspector", "true")
-        .build()
-}
 
-fn handle_form_test(req: &HttpRequest) -> HttpResponse {
-    let mut body = String::from(r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Form Parser Test</title>
-    <style>
-        body { font-family: monospace; margin: 20px; background: #f5f5f5; }
-        .container { max-width: 800px; margin: 0 auto; background: white; padding: 20px; border-radius: 8px; }
-        h1 { color: #667eea; }
-        .section { margin: 20px 0; padding: 15px; background: #f9f9f9; border-left: 4px solid #667eea; }
-        h2 { color: #333; margin: 0 0 10px 0; }
-        table { width: 100%; border-collapse: collapse; }
-        td { padding: 8px; border-bottom: 1px solid #ddd; }
-        td:first-child { font-weight: bold; width: 25%; color: #667eea; }
-        code { background: #f0f0f0; padding: 2px 6px; border-radius: 3px; }
-        .form-section { background: white; padding: 20px; margin: 20px 0; border: 1px solid #ddd; border-radius: 8px; }
-        input, textarea { width: 100%; padding: 8px; margin: 5px 0; border: 1px solid #ddd; border-radius: 4px; font-family: monospace; }
-        button { background: #667eea; color: white; padding: 10px 20px; border: none; border-radius: 4px; cursor: pointer; margin-top: 10px; }
-        button:hover { background: #764ba2; }
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>HTTP Parser Features Demo</h1>"#);
-    
-    body.push_str(r#"
-        <div class="section">
-            <h2>✅ Implemented Features</h2>
-            <ul>
-                <li><strong>Parse Request Line:</strong> Method, Path, Query String, HTTP Version</li>
-                <li><strong>Extract Cookies:</strong> Automatic parsing from Cookie header</li>
-                <li><strong>Query Parameters:</strong> URL decoding support</li>
-                <li><strong>Chunked Encoding:</strong> Automatic decoding of chunked transfer encoding</li>
-                <li><strong>Form Data:</strong> URL-encoded and multipart/form-data parsing</li>
-                <li><strong>Headers:</strong> All HTTP headers parsed and accessible</li>
-            </ul>
-        </div>"#);
-    
-    // Show current request details
-    body.push_str(r#"
-        <div class="section">
-            <h2>Current Request Info</h2>
-            <table>
-                <tr><td>Method:</td><td>"#);
-    body.push_str(&req.method);
-    body.push_str(r#"</td></tr>
-                <tr><td>Path:</td><td>"#);
-    body.push_str(&req.path);
-    body.push_str(r#"</td></tr>
-                <tr><td>HTTP Version:</td><td>"#);
-    body.push_str(&req.version);
-    body.push_str(r#"</td></tr>
-            </table>
-        </div>"#);
-    
-    // Form fields
-    if !req.form_fields.is_empty() {
-        body.push_str(r#"
-        <div class="section">
-            <h2>Form Fields Parsed</h2>
-            <table>"#);
-        for (name, value) in &req.form_fields {
-            body.push_str(&format!(
-                r#"<tr><td>{}:</td><td><code>{}</code></td></tr>"#,
-                name, value
-            ));
+    #[test]
+    fn single_ranges_get_206() {
+        for (range, body, content_range) in [
+            ("bytes=2-4", "234", "bytes 2-4/10"),
+            ("bytes=-3", "789", "bytes 7-9/10"),
+            ("bytes=7-", "789", "bytes 7-9/10"),
+            ("bytes=0-3,2-5", "012345", "bytes 0-5/10"),
+        ] {
+            let response = ranged(range, &[]);
+            assert_eq!(response.status, 206, "{}", range);
+            assert_eq!(body_text(&response), body);
+            assert_eq!(response.headers.get("Content-Range"), Some(content_range));
+            assert_eq!(response.headers.get("Content-Length"), Some(body.len().to_string().as_str()));
+        }
+    }
+
+    #[test]
+    fn multiple_ranges_get_multipart_byteranges() {
+        let response = ranged("bytes=7-8,0-1", &[]);
+        assert_eq!(response.status, 206);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let Body::Parts(parts) = &response.body else {
+            panic!("expected a multipart body");
+        };
+        let body: Vec<u8> = parts.iter().flat_map(|part| Compression::read_body(part).unwrap()).collect();
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-8/10\r\n\r\n78\r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+        assert_eq!(response.headers.get("Content-Length"), Some(expected.len().to_string().as_str()));
+    }
+
+    #[test]
+    fn unsatisfiable_and_invalid_ranges() {
+        let response = ranged("bytes=10-", &[]);
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */10"));
+
+        // Bad syntax, unknown units and range floods are ignored: the full body is sent
+        let flood: Vec<String> = (0..33).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        let flood = format!("bytes={}", flood.join(","));
+        for range in ["bytes=5-2", "lines=0-1", "bytes=x-", flood.as_str()] {
+            let mut req = request("GET", "/");
+            req.headers.insert("Range", range);
+            let long = HttpResponse { body: Body::Bytes(vec![0; 100]), ..rangeable() };
+            assert_eq!(ByteRanges::apply(&req, long).status, 200, "{}", range);
+        }
+
+        // Only GET and HEAD are ranged
+        let mut req = request("POST", "/");
+        req.headers.insert("Range", "bytes=0-1");
+        assert_eq!(ByteRanges::apply(&req, rangeable()).status, 200);
+    }
+
+    #[test]
+    fn if_range_needs_a_current_validator() {
+        assert_eq!(ranged("bytes=0-1", &[("If-Range", "\"v1\"")]).status, 206);
+        assert_eq!(ranged("bytes=0-1", &[("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT")]).status, 206);
+        for stale in ["\"v0\"", "W/\"v1\"", "Mon, 07 Nov 1994 08:49:37 GMT", "garbage"] {
+            let response = ranged("bytes=0-1", &[("If-Range", stale)]);
+            assert_eq!((response.status, body_text(&response).as_str()), (200, "0123456789"), "{}", stale);
         }
-        body.push_str("</table></div>");
     }
-    
-    // Form files
-    if !req.form_files.is_empty() {
-        body.push_str(r#"
-        <div class="section">
-            <h2>Files Uploaded</h2>
-            <table>"#);
-        for (field_name, file) in &req.form_files {
-            body.push_str(&format!(
-                r#"<tr><td>{}:</td><td><code>{}</code> ({} bytes, type: {})</td></tr>"#,
-                field_name, file.filename, file.data.len(), file.content_type
-            ));
+
+    #[test]
+    fn static_files_are_ranged_without_copying() {
+        let dir = temp_dir("ranges-static");
+        fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+        let servers = format!(
+            "[[servers]]\nlisten = [\"127.0.0.1:0\"]\nbuiltin_routes = false\n\
+             [[servers.location]]\npath = \"/files\"\nalias = {:?}\n",
+            dir.to_string_lossy()
+        );
+        let vhost = vhost(&config(&servers), 0);
+        let mut req = request("GET", "/files/digits.txt");
+        req.headers.insert("Range", "bytes=3-5");
+        let response = vhost.router.handle(&mut req);
+        assert_eq!(response.status, 206);
+        assert!(matches!(response.body, Body::File { offset: 3, len: 3, .. }));
+        assert_eq!(body_text(&response), "345");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    const MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const EARLIER: &str = "Sat, 05 Nov 1994 08:49:37 GMT";
+    const LATER: &str = "Mon, 07 Nov 1994 08:49:37 GMT";
+
+    fn conditional(method: &str, headers: &[(&str, &str)]) -> HttpResponse {
+        let mut req = request(method, "/");
+        for &(name, value) in headers {
+            req.headers.insert(name, value);
         }
-        body.push_str("</table></div>");
+        let mut response = rangeable();
+        response.headers.insert("Cache-Control", "max-age=60");
+        Preconditions::evaluate(&req, response)
     }
-    
-    // Test forms
-    body.push_str(r#"
-        <div class="form-section">
-            <h2>📝 Test URL-Encoded Form</h2>
-            <form method="POST" action="/form-test" enctype="application/x-www-form-urlencoded">
-                <label>Username:</label><input type="text" name="username" value="john_doe">
-                <label>Email:</label><input type="email" name="email" value="john@example.com">
-                <label>Message:</label><textarea name="message">Hello from form!</textarea>
-                <button type="submit">Submit Form (URL-Encoded)</button>
-            </form>
-        </div>
-        
-        <div class="form-section">
-            <h2>📤 Test Multipart Form</h2>
-            <form method="POST" action="/form-test" enctype="multipart/form-data">
-                <label>Name:</label><input type="text" name="name" value="John Doe">
-                <label>File:</label><input type="file" name="upload">
-                <label>Description:</label><textarea name="description">File upload test</textarea>
-                <button type="submit">Submit Form (Multipart)</button>
-            </form>
-        </div>
-        
-        <div class="section">
-            <h2>cURL Examples</h2>
-            <p><strong>URL-Encoded POST:</strong><br>
-            <code>curl -X POST -d "username=john&email=john@example.com&message=hello" http://localhost:8080/form-test</code></p>
-            <p><strong>With Chunked Encoding:</strong><br>
-            <code>curl -X POST -H "Transfer-Encoding: chunked" -d "data=value" http://localhost:8080/form-test</code></p>
-        </div>
-    </div>
-</body>
-</html>"#);
-    
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("text/html; charset=utf-8")
-        .body_text(&body)
-        .header("X-Form-Parser", "enabled")
-        .build()
-}
 
-fn handle_download(_req: &HttpRequest) -> HttpResponse {
-    // Demonstrate chunked transfer encoding for streaming responses
-    let large_content = r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Chunked Response Demo</title>
-    <style>
-        body { font-family: Arial, sans-serif; margin: 20px; padding: 20px; background: #f5f5f5; }
-        .container { max-width: 800px; margin: 0 auto; background: white; padding: 20px; border-radius: 8px; }
-        h1 { color: #667eea; }
-        p { line-height: 1.6; color: #333; }
-        .highlight { background: #fff3cd; padding: 10px; border-left: 4px solid #ffc107; margin: 10px 0; }
-        code { background: #f0f0f0; padding: 2px 6px; border-radius: 3px; }
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>📥 Chunked Transfer Encoding Demo</h1>
-        <p>This response was sent using <strong>Transfer-Encoding: chunked</strong>, allowing the server to stream large content without knowing the total size in advance.</p>
-        
-        <div class="highlight">
-            <strong>✨ Benefits of Chunked Encoding:</strong>
-            <ul>
-                <li>Stream responses without pre-calculating Content-Length</li>
-                <li>Ideal for dynamic or generated content</li>
-                <li>Enable HTTP/1.1 trailers</li>
-                <li>Support for gzip-like compression</li>
-                <li>Better for real-time data (SSE, WebSocket upgrades)</li>
-            </ul>
-        </div>
-        
-        <h2>How It Works</h2>
-        <p>With chunked encoding enabled, the response body is sent as a series of chunks:</p>
-        <code>[chunk size in hex]\r\n[chunk data]\r\n[next chunk size]\r\n[chunk data]\r\n0\r\n</code>
-        
-        <p>The ResponseBuilder automatically handles this when you call <code>.chunked(true)</code>:</p>
-        <code>ResponseBuilder::new().body_text("...").chunked(true).build()</code>
-        
-        <h2>Use Cases</h2>
-        <ul>
-            <li><strong>Server-Sent Events (SSE):</strong> Send real-time updates to clients</li>
-            <li><strong>Large File Downloads:</strong> Stream without buffering entire file</li>
-            <li><strong>API Responses:</strong> Generate large JSON responses on-the-fly</li>
-            <li><strong>Web Sockets Upgrade:</strong> Handshake mechanism uses chunked encoding</li>
-            <li><strong>Streaming Analytics:</strong> Send metrics as they're collected</li>
-        </ul>
-        
-        <p><strong>View the HTTP headers:</strong> Open Developer Tools (F12) → Network tab and check the response headers for <code>Transfer-Encoding: chunked</code></p>
-    </div>
-</body>
-</html>"#;
-    
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("text/html; charset=utf-8")
-        .body_text(large_content)
-        .chunked(true)
-        .header("Cache-Control", "no-store")
-        .build()
-}
+    #[test]
+    fn matching_validators_get_304() {
+        for headers in [
+            &[("If-None-Match", "\"v1\"")][..],
+            &[("If-None-Match", "\"x\", W/\"v1\"")],
+            &[("If-None-Match", "*")],
+            &[("If-Modified-Since", MODIFIED)],
+            &[("If-Modified-Since", LATER)],
+        ] {
+            let response = conditional("GET", headers);
+            assert_eq!(response.status, 304, "{:?}", headers);
+            assert_eq!(body_text(&response), "");
+            assert_eq!(response.headers.get("ETag"), Some("\"v1\""));
+            assert_eq!(response.headers.get("Cache-Control"), Some("max-age=60"));
+            assert!(response.headers.get("Content-Type").is_none());
+            assert!(response.headers.get("Content-Length").is_none());
+        }
+        assert_eq!(conditional("HEAD", &[("If-None-Match", "\"v1\"")]).status, 304);
+        assert_eq!(conditional("GET", &[("If-None-Match", "\"v2\"")]).status, 200);
+        assert_eq!(conditional("GET", &[("If-Modified-Since", EARLIER)]).status, 200);
+        assert_eq!(conditional("GET", &[("If-Modified-Since", "not a date")]).status, 200);
+    }
 
-fn handle_login(_req: &HttpRequest) -> HttpResponse {
-    // Demonstrate advanced cookie management for sessions
-    let html = r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>Session Management Demo</title>
-    <style>
-        body { font-family: Arial, sans-serif; margin: 20px; padding: 20px; background: linear-gradient(135deg, #667eea 0%, #764ba2 100%); min-height: 100vh; }
-        .container { max-width: 600px; margin: 0 auto; background: white; padding: 30px; border-radius: 10px; box-shadow: 0 10px 40px rgba(0,0,0,0.2); }
-        h1 { color: #667eea; margin-top: 0; }
-        .session-box { background: #f0f7ff; padding: 15px; border: 2px solid #667eea; border-radius: 8px; margin: 20px 0; }
-        .cookie-item { background: white; padding: 10px; margin: 10px 0; border-left: 4px solid #764ba2; }
-        code { background: #f5f5f5; padding: 2px 6px; border-radius: 3px; font-weight: bold; }
-        ul { line-height: 1.8; }
-        .note { background: #fff3cd; padding: 10px; border-radius: 4px; margin: 10px 0; }
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>🔐 Session Management Demo</h1>
-        
-        <div class="session-box">
-            <h2>Cookies Set by This Response:</h2>
-            <div class="cookie-item">
-                <strong>user_session:</strong> <code>session_12345</code><br>
-                <small>HttpOnly, Max-Age: 3600 seconds (1 hour), Path: /</small>
-            </div>
-            <div class="cookie-item">
-                <strong>preferences:</strong> <code>theme=dark&lang=en</code><br>
-                <small>HttpOnly, Max-Age: 2592000 seconds (30 days), Path: /</small>
-            </div>
-        </div>
-        
-        <h2>💡 How SessionManagement Works</h2>
-        <ul>
-            <li><strong>HttpOnly Flag:</strong> Prevents JavaScript access, protects against XSS attacks</li>
-            <li><strong>Max-Age:</strong> Session lifetime in seconds (3600 = 1 hour)</li>
-            <li><strong>Path:</strong> Cookie scope (/ = entire domain)</li>
-            <li><strong>Secure Flag:</strong> Should be set in production (HTTPS only)</li>
-            <li><strong>SameSite:</strong> CSRF protection (not shown, but recommended)</li>
-        </ul>
-        
-        <div class="note">
-            <strong>✅ Recommended Practices:</strong>
-            <ul>
-                <li>Store sensitive data server-side, use session ID in cookie</li>
-                <li>Always use HttpOnly flag for session cookies</li>
-                <li>Use Secure flag in production (HTTPS only)</li>
-                <li>Implement server-side session validation</li>
-                <li>Set reasonable Max-Age values</li>
-                <li>Implement logout to clear session cookies</li>
-            </ul>
-        </div>
-        
-        <h2>Implementation Example</h2>
-        <p>Using <code>ResponseBuilder::cookie_with_options()</code>:</p>
-        <code style="display: block; background: #f5f5f5; padding: 10px; border-radius: 4px; margin: 10px 0; overflow-x: auto;">
-ResponseBuilder::new()<br>
-&nbsp;&nbsp;.status(200, "OK")<br>
-&nbsp;&nbsp;.cookie_with_options("user_session", "session_12345", Some(3600), "/", true)<br>
-&nbsp;&nbsp;.body_text("...")<br>
-&nbsp;&nbsp;.build()
-        </code>
-        
-        <p>Next step: <a href="/protected" style="color: #667eea; font-weight: bold;">Visit /protected</a> to see session validation</p>
-    </div>
-</body>
-</html>"#;
-    
-    ResponseBuilder::new()
-        .status(200, "OK")
-        .content_type("text/html; charset=utf-8")
-        .body_text(html)
-        .cookie_with_options("user_session", "session_12345", Some(3600), "/", true)
-        .cookie_with_options("preferences", "theme=dark&lang=en", Some(2592000), "/", true)
-        .header("X-Session-Demo", "true")
-        .build()
-}
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        // A changed ETag wins over a date that would have matched
+        let response = conditional("GET", &[("If-None-Match", "\"v0\""), ("If-Modified-Since", LATER)]);
+        assert_eq!(response.status, 200);
+        // And a matching ETag wins over a date that would not
+        let response = conditional("GET", &[("If-None-Match", "\"v1\""), ("If-Modified-Since", EARLIER)]);
+        assert_eq!(response.status, 304);
+        // If-Modified-Since only applies to GET and HEAD
+        assert_eq!(conditional("DELETE", &[("If-Modified-Since", LATER)]).status, 200);
+    }
 
-fn handle_static(_req: &HttpRequest) -> HttpResponse {
-    // Demonstrate static file serving with ResponseBuilder
-    match ResponseBuilder::new().file("static/example.html") {
-        Ok(builder) => {
-            builder
-                .status(200, "OK")
-                .header("Cache-Control", "public, max-age=3600")
-                .build()
+    #[test]
+    fn failed_preconditions_get_412() {
+        for (method, headers) in [
+            ("PUT", &[("If-Match", "\"v0\"")][..]),
+            ("PUT", &[("If-Match", "W/\"v1\"")]),
+            ("GET", &[("If-Unmodified-Since", EARLIER)]),
+            ("DELETE", &[("If-None-Match", "\"v1\"")]),
+            ("POST", &[("If-None-Match", "*")]),
+            // If-Match is checked before If-None-Match
+            ("GET", &[("If-Match", "\"v0\""), ("If-None-Match", "\"v1\"")]),
+        ] {
+            assert_eq!(conditional(method, headers).status, 412, "{} {:?}", method, headers);
         }
-        Err(_) => {
-            // If file not found, return 404 error page
-            ResponseBuilder::new()
-                .status(404, "Not Found")
-                .content_type("text/html; charset=utf-8")
-                .body_text(&ErrorPages::not_found())
-                .build()
+        // If-Match takes precedence over If-Unmodified-Since
+        let response = conditional("PUT", &[("If-Match", "\"v1\""), ("If-Unmodified-Since", EARLIER)]);
+        assert_eq!(response.status, 200);
+        assert_eq!(conditional("PUT", &[("If-Match", "*")]).status, 200);
+        assert_eq!(conditional("PUT", &[("If-Unmodified-Since", MODIFIED)]).status, 200);
+    }
+
+    #[test]
+    fn preconditions_skip_errors_and_unvalidated_responses() {
+        let mut req = request("GET", "/");
+        req.headers.insert("If-Match", "\"v0\"");
+        let not_found = HttpResponse::new(404, "Not Found", "");
+        assert_eq!(Preconditions::evaluate(&req, not_found).status, 404);
+        let plain = HttpResponse::new(200, "OK", "no validators");
+        assert_eq!(Preconditions::evaluate(&req, plain).status, 200);
+    }
+
+    #[test]
+    fn body_etags_follow_the_content() {
+        assert_eq!(Preconditions::body_etag(b"abc"), Preconditions::body_etag(b"abc"));
+        assert_ne!(Preconditions::body_etag(b"abc"), Preconditions::body_etag(b"abd"));
+        assert!(Preconditions::body_etag(b"").starts_with("\"0-"));
+    }
+
+    fn file_response(path: &std::path::Path, offset: u64, len: u64) -> HttpResponse {
+        let mut response = HttpResponse::new(200, "OK", "");
+        response.headers.insert("Content-Length", len.to_string());
+        response.body = Body::File { file: Rc::new(fs::File::open(path).unwrap()), offset, len };
+        response
+    }
+
+    #[test]
+    fn file_regions_are_sent_with_and_without_sendfile() {
+        let dir = temp_dir("sendfile");
+        let path = dir.join("data.bin");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let mut server = test_server("sendfile-server", "");
+        let (mut client, fd) = connect(&mut server);
+        let epoll_fd = server.epoll_fd;
+
+        for use_sendfile in [true, false] {
+            let connection = server.connections.get_mut(&fd).unwrap();
+            connection.use_sendfile = use_sendfile;
+            connection.queue_response(file_response(&path, 1000, 150_000));
+            let mut received = Vec::new();
+            let mut chunk = vec![0; 64 * 1024];
+            loop {
+                let connection = server.connections.get_mut(&fd).unwrap();
+                assert!(connection.flush(epoll_fd));
+                if !connection.has_pending_output() {
+                    break;
+                }
+                let n = client.read(&mut chunk).unwrap();
+                received.extend_from_slice(&chunk[..n]);
+            }
+            while let Ok(n @ 1..) = client.read(&mut chunk) {
+                received.extend_from_slice(&chunk[..n]);
+            }
+            let body_start = HttpParser::find_bytes(&received, b"\r\n\r\n", 0).unwrap() + 4;
+            assert_eq!(&received[body_start..], &data[1000..151_000], "sendfile {}", use_sendfile);
         }
+        fs::remove_dir_all(dir).unwrap();
     }
-}
 
-fn handle_cgi(req: &HttpRequest, client_ip: &str) -> HttpResponse {
-    // Extract script name from path (e.g., /cgi-bin/script.cgi)
-    let cgi_path = format!("cgi-bin/{}", req.path.trim_start_matches("/cgi-bin/"));
-    
-    match CGIExecutor::execute(&cgi_path, req, client_ip) {
-        Ok(response) => response,
-        Err(e) => {
-            eprintln!("CGI execution error: {}", e);
-            ResponseBuilder::new()
-                .status(500, "Internal Server Error")
-                .content_type("text/html; charset=utf-8")
-                .body_text(&format!(
-                    r#"<!DOCTYPE html>
-<html>
-<head>
-    <title>CGI Error</title>
-    <style>
-        body {{ font-family: Arial, sans-serif; margin: 20px; background: #f5f5f5; }}
-        .error {{ background: #ffebee; padding: 20px; border-left: 4px solid #f44336; border-radius: 4px; }}
-        code {{ background: #f0f0f0; padding: 2px 6px; border-radius: 3px; }}
-    </style>
-</head>
-<body>
-    <div class="error">
-        <h1>CGI Execution Error</h1>
-        <p><strong>Error:</strong> <code>{}</code></p>
-        <p>Failed to execute CGI script at <code>{}</code></p>
-    </div>
-</body>
-</html>"#,
-                    e, cgi_path
-                ))
-                .build()
+    #[test]
+    fn truncated_files_end_the_connection() {
+        let dir = temp_dir("sendfile-truncated");
+        let path = dir.join("short.txt");
+        fs::write(&path, "0123456789").unwrap();
+        let mut server = test_server("sendfile-truncated-server", "");
+        let (_client, fd) = connect(&mut server);
+        let epoll_fd = server.epoll_fd;
+        for use_sendfile in [true, false] {
+            let connection = server.connections.get_mut(&fd).unwrap();
+            connection.output.clear();
+            connection.use_sendfile = use_sendfile;
+            // Headers promised more than the file now holds
+            connection.queue_response(file_response(&path, 5, 10));
+            assert!(!connection.flush(epoll_fd), "sendfile {}", use_sendfile);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn segments_merge_memory_and_keep_file_regions() {
+        let dir = temp_dir("segments");
+        let path = dir.join("file.txt");
+        fs::write(&path, "0123456789").unwrap();
+        let file = Rc::new(fs::File::open(&path).unwrap());
+        let region = |offset, len| Body::File { file: Rc::clone(&file), offset, len };
+        let mut response = HttpResponse::new(200, "OK", "");
+        response.headers = HeaderMap::new();
+        response.body = Body::Parts(vec![
+            Body::Bytes(b"a".to_vec()),
+            region(0, 0),
+            Body::Parts(vec![Body::Bytes(b"b".to_vec()), region(2, 3)]),
+            Body::Bytes(b"c".to_vec()),
+        ]);
+        assert_eq!(response.body.len(), Some(6));
+        let segments = response.into_segments();
+        assert_eq!(segments.len(), 3);
+        assert!(matches!(&segments[0], Body::Bytes(bytes) if bytes == b"HTTP/1.1 200 OK\r\n\r\nab"));
+        assert!(matches!(&segments[1], Body::File { offset: 2, len: 3, .. }));
+        assert!(matches!(&segments[2], Body::Bytes(bytes) if bytes == b"c"));
+        assert!(matches!(region(2, 6).slice(1, 2), Some(Body::File { offset: 3, len: 2, .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Let the server write everything it has queued for the client, `keep`
+    /// being what the last call into the server returned. Returns what the
+    /// client read and whether the server keeps the connection.
+    fn drain(server: &mut Server, fd: RawFd, client: &mut TcpStream, mut keep: bool) -> (String, bool) {
+        let epoll_fd = server.epoll_fd;
+        let mut data = Vec::new();
+        let mut chunk = vec![0; 64 * 1024];
+        while keep && server.connections[&fd].has_pending_output() {
+            let n = client.read(&mut chunk).unwrap();
+            data.extend_from_slice(&chunk[..n]);
+            keep = server.connections.get_mut(&fd).unwrap().flush(epoll_fd);
+        }
+        (String::from_utf8(data).unwrap() + &received(client), keep)
+    }
+
+    #[test]
+    fn streams_are_pulled_lazily_and_sent_chunked() {
+        let pulled = Rc::new(std::cell::Cell::new(0));
+        let counter = Rc::clone(&pulled);
+        let chunks = ["hello", "", " world"].into_iter().map(move |chunk| {
+            counter.set(counter.get() + 1);
+            chunk.as_bytes().to_vec()
+        });
+        let response = ResponseBuilder::new().content_type("text/plain").stream(chunks).build();
+        assert_eq!(response.headers.get("Transfer-Encoding"), Some("chunked"));
+        assert!(response.headers.get("Content-Length").is_none());
+        assert_eq!(response.body.len(), None);
+
+        let mut server = test_server("stream-chunked", "");
+        let (mut client, fd) = connect(&mut server);
+        let epoll_fd = server.epoll_fd;
+        let connection = server.connections.get_mut(&fd).unwrap();
+        connection.queue_response(response);
+        assert_eq!(pulled.get(), 0);
+        assert!(connection.flush(epoll_fd));
+        assert_eq!(pulled.get(), 3);
+        let sent = received(&mut client);
+        // The empty chunk is skipped rather than ending the body early
+        assert!(sent.ends_with("\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"), "{:?}", sent);
+    }
+
+    #[test]
+    fn http_1_0_streams_are_delimited_by_closing() {
+        let mut server = test_server("stream-http-1-0", "");
+        let (mut client, fd) = connect(&mut server);
+        let keep = send(&mut server, fd, &mut client, b"GET /download HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (chunked, keep) = drain(&mut server, fd, &mut client, keep);
+        assert!(keep);
+        assert!(chunked.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(chunked.ends_with("</html>\r\n0\r\n\r\n"));
+
+        let keep = send(&mut server, fd, &mut client, b"GET /download HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        let (plain, keep) = drain(&mut server, fd, &mut client, keep);
+        assert!(!keep);
+        let head = &plain[..plain.find("\r\n\r\n").unwrap()];
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(head.contains("Connection: close"));
+        assert!(plain.ends_with("</html>"));
+        // The same document, minus the chunk framing
+        let body = |response: &str| response[response.find("\r\n\r\n").unwrap() + 4..].to_string();
+        let unframed: String = body(&chunked)
+            .split("\r\n")
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .filter_map(|frame| frame.get(1).copied())
+            .collect();
+        assert_eq!(unframed, body(&plain));
+    }
+
+    fn sidecar_root(name: &str) -> std::path::PathBuf {
+        let root = temp_dir(name);
+        fs::create_dir_all(root.join("public")).unwrap();
+        for (file, contents) in [("app.js", "plain"), ("app.js.gz", "gzipped"), ("app.js.br", "brotli"), ("style.css", "css")] {
+            fs::write(root.join("public").join(file), contents).unwrap();
+        }
+        fs::write(root.join("outside.gz"), "outside").unwrap();
+        std::os::unix::fs::symlink(root.join("outside.gz"), root.join("public").join("style.css.gz")).unwrap();
+        root
+    }
+
+    fn fetch(location: &Location, path: &str, accept_encoding: Option<&str>) -> HttpResponse {
+        let mut req = request("GET", path);
+        if let Some(accept) = accept_encoding {
+            req.headers.insert("Accept-Encoding", accept);
+        }
+        location.handle(&req)
+    }
+
+    #[test]
+    fn sidecars_are_served_by_accepted_coding() {
+        let root = sidecar_root("sidecars");
+        let location = location(&format!("path = \"/s\"\nalias = {:?}", root.join("public").to_string_lossy()), &root);
+        for (accept, encoding, body) in [
+            (Some("gzip, deflate, br"), Some("br"), "brotli"),
+            (Some("gzip"), Some("gzip"), "gzipped"),
+            (Some("br;q=0, x-gzip"), Some("gzip"), "gzipped"),
+            (Some("*;q=0.5, br;q=0.1"), Some("gzip"), "gzipped"),
+            (Some("deflate"), None, "plain"),
+            (Some("identity, *;q=0"), None, "plain"),
+            (None, None, "plain"),
+        ] {
+            let response = fetch(&location, "/s/app.js", accept);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.headers.get("Content-Encoding"), encoding, "{:?}", accept);
+            assert_eq!(body_text(&response), body, "{:?}", accept);
+            assert_eq!(response.headers.get("Content-Type"), Some("application/javascript"));
+            assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
         }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sidecars_outside_the_root_are_ignored() {
+        let root = sidecar_root("sidecars-outside");
+        let location = location(&format!("path = \"/s\"\nalias = {:?}", root.join("public").to_string_lossy()), &root);
+        let response = fetch(&location, "/s/style.css", Some("gzip"));
+        assert!(response.headers.get("Content-Encoding").is_none());
+        assert_eq!(body_text(&response), "css");
+        // Files without sidecars do not vary
+        assert!(response.headers.get("Vary").is_none());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sidecars_are_not_compressed_again() {
+        let root = sidecar_root("sidecars-vhost");
+        let servers = format!(
+            "[[servers]]\nlisten = [\"127.0.0.1:0\"]\nbuiltin_routes = false\n\
+             [[servers.location]]\npath = \"/s\"\nalias = {:?}\n",
+            root.join("public").to_string_lossy()
+        );
+        let vhost = vhost(&config(&servers), 0);
+        let mut req = request("GET", "/s/app.js");
+        req.headers.insert("Accept-Encoding", "gzip");
+        let response = vhost.router.handle(&mut req);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(body_text(&response), "gzipped");
+        assert!(matches!(response.body, Body::File { .. }));
+        fs::remove_dir_all(root).unwrap();
     }
-}
 
-#[derive(Deserialize)]
-struct Config {
-    server: ServerConfig,
-    #[allow(dead_code)]
-    logging: LoggingConfig,
-}
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "text/plain");
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+        assert!(headers.contains("Content-type"));
+        *headers.get_mut("content-TYPE").unwrap() = "text/html".into();
+        assert_eq!(headers.get("Content-Type"), Some("text/html"));
+        headers.remove("CONTENT-type");
+        assert!(headers.is_empty());
+        assert!(headers.get("Content-Type").is_none());
+    }
 
-#[derive(Deserialize)]
-struct ServerConfig {
-    host: String,
-    port: u16,
-    timeout_ms: i32,
-    max_events: usize,
-}
+    #[test]
+    fn headers_keep_every_value_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Vary", "Accept");
+        headers.append("set-cookie", "b=2");
+        headers.append("Vary", "Accept-Encoding");
+        assert_eq!(headers.get("Set-Cookie"), Some("a=1"));
+        assert_eq!(headers.get_all("SET-COOKIE").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(headers.get_joined("vary").as_deref(), Some("Accept, Accept-Encoding"));
+        assert_eq!(headers.get_joined("Missing"), None);
 
-#[derive(Deserialize)]
-struct LoggingConfig {
-    #[allow(dead_code)]
-    level: String,
-    #[allow(dead_code)]
-    file: String,
-}
+        // Insert replaces all values, keeping the first one's position and the new casing
+        headers.insert("SET-COOKIE", "c=3");
+        let entries: Vec<_> = headers.iter().collect();
+        assert_eq!(entries, [("SET-COOKIE", "c=3"), ("Vary", "Accept"), ("Vary", "Accept-Encoding")]);
 
-#[allow(dead_code)]
-#[derive(Debug)]
-enum ServerError {
-    Io(io::Error),
-    Config(toml::de::Error),
-    InvalidConfig(String),
-}
+        headers.retain(|_, value| value != "Accept");
+        assert_eq!(headers.get_all("Vary").collect::<Vec<_>>(), ["Accept-Encoding"]);
+    }
 
-impl From<io::Error> for ServerError {
-    fn from(err: io::Error) -> ServerError {
-        ServerError::Io(err)
+    #[test]
+    fn responses_write_each_header_value_on_its_own_line() {
+        let response = ResponseBuilder::new()
+            .set_cookie(&Cookie::new("a", "1"))
+            .set_cookie(&Cookie::new("b", "2"))
+            .body_text("ok")
+            .build();
+        let segments = response.into_segments();
+        let [Body::Bytes(head), ..] = segments.as_slice() else {
+            panic!("expected the head in memory");
+        };
+        let head = String::from_utf8_lossy(head).into_owned();
+        assert!(head.contains("\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n"), "{}", head);
+
+        // Repeated request fields are kept too
+        let request = parse(b"GET / HTTP/1.1\r\nAccept: text/html\r\naccept: */*\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.headers.get_joined("Accept").as_deref(), Some("text/html, */*"));
     }
-}
 
-impl From<toml::de::Error> for ServerError {
-    fn from(err: toml::de::Error) -> ServerError {
-        ServerError::Config(err)
+    #[test]
+    fn cookie_builder_serializes_every_attribute() {
+        let cookie = Cookie::new("id", "a b;c\"d%é")
+            .domain(".example.test")
+            .path("/app")
+            .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .max_age(3600)
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict);
+        assert_eq!(
+            cookie.to_header_value(),
+            "id=a%20b%3Bc%22d%25%C3%A9; Domain=example.test; Path=/app; Expires=Sun, 06 Nov 1994 08:49:37 GMT; \
+             Max-Age=3600; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(Cookie::new("a", "1").to_header_value(), "a=1");
+        assert_eq!(Cookie::new("a", "1").max_age(-5).to_header_value(), "a=1; Max-Age=0");
+        // Attribute values cannot smuggle in further attributes
+        assert_eq!(Cookie::new("a", "1").path("/x; Secure\n").to_header_value(), "a=1; Path=/x Secure");
     }
-}
 
-struct Connection {
-    stream: TcpStream,
-    buffer: Vec<u8>,
-    request: Option<HttpRequest>,
-}
+    #[test]
+    fn cross_site_and_partitioned_cookies_are_always_secure() {
+        let none = Cookie::new("a", "1").same_site(SameSite::None);
+        assert_eq!(none.to_header_value(), "a=1; Secure; SameSite=None");
+        let partitioned = Cookie::new("a", "1").partitioned(true);
+        assert_eq!(partitioned.to_header_value(), "a=1; Secure; Partitioned");
+        let lax = Cookie::new("a", "1").same_site(SameSite::Lax);
+        assert_eq!(lax.to_header_value(), "a=1; SameSite=Lax");
+    }
 
-struct Server {
-    listener: TcpListener,
-    config: Config,
-    epoll_fd: RawFd,
-    connections: HashMap<RawFd, Connection>,
-    router: Router,
-}
+    #[test]
+    fn expired_cookies_clear_the_value() {
+        let cookie = Cookie::new("id", "secret").path("/").expired();
+        assert_eq!(cookie.to_header_value(), "id=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0");
+        let response = ResponseBuilder::new().delete_cookie("id", "/").build();
+        assert_eq!(response.headers.get("Set-Cookie"), Some(cookie.to_header_value().as_str()));
+    }
 
-impl Server {
-    pub fn new(config_path: &str) -> io::Result<Server> {
-        // Read and parse configuration
-        let config_content = fs::read_to_string(config_path)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to read config: {}", e)))?;
-        
-        let config: Config = toml::from_str(&config_content)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to parse config: {}", e)))?;
+    #[test]
+    fn cookie_jar_parses_and_decodes_request_cookies() {
+        let raw = b"GET / HTTP/1.1\r\nCookie: a=1; b=hello%20world;c=\"quoted\"\r\n\
+                    Cookie: d=%zz%4; e=; f=x=y; noequals\r\n\r\n";
+        let request = parse(raw).unwrap().unwrap();
+        let jar = request.cookie_jar();
+        assert_eq!(jar.get("a"), Some("1"));
+        assert_eq!(jar.get("b"), Some("hello world"));
+        assert_eq!(jar.get("c"), Some("quoted"));
+        assert_eq!(jar.get("d"), Some("%zz%4"));
+        assert_eq!(jar.get("e"), Some(""));
+        assert_eq!(jar.get("f"), Some("x=y"));
+        assert_eq!(jar.get("noequals"), None);
+        // Without keys, signed and private cookies cannot be read or made
+        assert_eq!(jar.get_signed("a"), None);
+        assert!(jar.signed(Cookie::new("a", "1")).is_none());
 
-        let address = format!("{}:{}", config.server.host, config.server.port);
+        for value in ["plain", "with space; and \"quotes\"", "100%", "ünïcødé", ""] {
+            let encoded = Cookie::new("v", value).to_header_value();
+            assert_eq!(Cookie::decode_value(encoded.strip_prefix("v=").unwrap()), value);
+        }
+    }
 
-        let listener = TcpListener::bind(&address)?;
-        listener.set_nonblocking(true)?;
-        
-        // Create epoll instance
-        let epoll_fd = unsafe { epoll_create1(0) };
-        if epoll_fd < 0 {
-            return Err(io::Error::last_os_error());
+    /// A request to a server using `keys`, carrying `sid` as its session
+    /// cookie signed by `signer`, if any
+    fn session_request(keys: &Rc<CookieKeys>, sid: &str, signer: Option<&CookieKeys>) -> HttpRequest {
+        let name = SessionConfig::default().cookie_name;
+        let mut cookie = Cookie::new(&name, sid);
+        if let Some(signer) = signer {
+            cookie = signer.sign(cookie);
         }
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", cookie.to_header_value());
+        let mut request = parse(raw.as_bytes()).unwrap().unwrap();
+        request.cookie_keys = Some(Rc::clone(keys));
+        request
+    }
 
-        // Add listener to epoll
-        let mut event = epoll_event {
-            events: EPOLLIN as u32,
-            u64: listener.as_raw_fd() as u64,
-        };
+    /// The session id set by a response, after checking its signature
+    fn issued_id(response: &HttpResponse, keys: &Rc<CookieKeys>) -> Option<String> {
+        let name = SessionConfig::default().cookie_name;
+        let header = response.headers.get("Set-Cookie")?;
+        assert!(header.ends_with("; Path=/; HttpOnly; SameSite=Lax"), "{}", header);
+        let value = header.strip_prefix(&format!("{}=", name))?.split(';').next()?;
+        keys.verify(&name, value)
+    }
 
-        unsafe {
-            if epoll_ctl(
-                epoll_fd,
-                EPOLL_CTL_ADD,
-                listener.as_raw_fd(),
-                &mut event as *mut epoll_event,
-            ) < 0 {
-                return Err(io::Error::last_os_error());
-            }
+    #[test]
+    fn new_sessions_get_random_signed_ids() {
+        let keys = Rc::new(cookie_keys(NEW_SECRET, &[]));
+        let mut sessions = sessions();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mut session = sessions.load(&request("GET", "/"));
+            assert!(session.is_new());
+            session.insert("user", "alice");
+            let mut response = HttpResponse::new(200, "OK", "");
+            sessions.save(session, &mut response, &keys);
+            let id = issued_id(&response, &keys).unwrap();
+            assert_eq!(id.len(), 32);
+            assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+            assert_eq!(sessions.store.load(&id).unwrap().unwrap().data["user"], "alice");
+            ids.push(id);
         }
-        
-        println!("Server started on http://{}:{}/", config.server.host, config.server.port);
-        
-        // Initialize router with routes
-        let mut router = Router::new();
-        router.register("GET", "/", handle_root);
-        router.register("GET", "/health", handle_health);
-        router.register("GET", "/inspect", handle_inspect);
-        router.register("GET", "/form-test", handle_form_test);
-        router.register("POST", "/form-test", handle_form_test);
-        router.register("GET", "/api/users", handle_users);
-        router.register("POST", "/api/users", handle_users);
-        router.register("GET", "/download", handle_download);
-        router.register("GET", "/login", handle_login);
-        router.register("GET", "/static", handle_static);
-        router.register("GET", "/api/", handle_api_catch_all);
-        router.register("POST", "/api/", handle_api_catch_all);
-        
-        Ok(Server {
-            listener,
-            config,
-            epoll_fd,
-            connections: HashMap::new(),
-            router,
-        })
+        assert_ne!(ids[0], ids[1]);
     }
-    
-    pub fn run(&mut self) -> io::Result<()> {
-        let mut events = vec![epoll_event { events: 0, u64: 0 }; self.config.server.max_events];
-        
-        loop {
-            let num_events = unsafe {
-                epoll_wait(
-                    self.epoll_fd,
-                    events.as_mut_ptr(),
-                    self.config.server.max_events as i32,
-                    self.config.server.timeout_ms,
-                )
-            };
 
-            if num_events < 0 {
-                return Err(io::Error::last_os_error());
-            }
+    #[test]
+    fn planted_and_forged_ids_are_not_adopted() {
+        let keys = Rc::new(cookie_keys(NEW_SECRET, &[]));
+        let mut sessions = sessions();
+        stored_session(&mut sessions, Sessions::now());
+        // An id the server never issued, even correctly signed, starts a new session
+        let planted = "0123456789abcdef0123456789abcdef";
+        let mut session = sessions.load(&session_request(&keys, planted, Some(&keys)));
+        assert!(session.is_new());
+        session.insert("cart", "1");
+        let mut response = HttpResponse::new(200, "OK", "");
+        sessions.save(session, &mut response, &keys);
+        assert_ne!(issued_id(&response, &keys).unwrap(), planted);
+        assert!(sessions.store.load(planted).unwrap().is_none());
+        // A real id without a valid signature is ignored
+        assert!(sessions.load(&session_request(&keys, "abc", None)).is_new());
+        let other_keys = cookie_keys(OLD_SECRET, &[]);
+        assert!(sessions.load(&session_request(&keys, "abc", Some(&other_keys))).is_new());
+        assert_eq!(sessions.load(&session_request(&keys, "abc", Some(&keys))).get("user"), Some("alice"));
+    }
 
-            for i in 0..num_events as usize {
-                let fd = events[i].u64 as RawFd;
+    #[test]
+    fn renewing_moves_the_data_to_a_new_id() {
+        let keys = Rc::new(cookie_keys(NEW_SECRET, &[]));
+        let mut sessions = sessions();
+        stored_session(&mut sessions, Sessions::now());
+        let mut session = sessions.load(&session_request(&keys, "abc", Some(&keys)));
+        session.renew();
+        let mut response = HttpResponse::new(200, "OK", "");
+        sessions.save(session, &mut response, &keys);
+        let id = issued_id(&response, &keys).unwrap();
+        assert_ne!(id, "abc");
+        assert!(sessions.store.load("abc").unwrap().is_none());
+        assert_eq!(sessions.store.load(&id).unwrap().unwrap().data["user"], "alice");
+    }
 
-                if fd == self.listener.as_raw_fd() {
-                    // Handle new connection
-                    self.accept_connection()?;
-                } else {
-                    // Handle existing connection
-                    if events[i].events & (EPOLLERR as u32 | EPOLLHUP as u32) != 0 {
-                        self.remove_connection(fd)?;
-                        continue;
-                    }
+    #[test]
+    fn destroyed_and_expired_sessions_are_removed() {
+        let keys = Rc::new(cookie_keys(NEW_SECRET, &[]));
+        let mut sessions = sessions();
+        stored_session(&mut sessions, Sessions::now());
+        let mut session = sessions.load(&session_request(&keys, "abc", Some(&keys)));
+        session.destroy();
+        let mut response = HttpResponse::new(200, "OK", "");
+        sessions.save(session, &mut response, &keys);
+        assert!(sessions.store.load("abc").unwrap().is_none());
+        assert!(response.headers.get("Set-Cookie").unwrap().contains("Max-Age=0"));
 
-                    if events[i].events & EPOLLIN as u32 != 0 {
-                        if let Err(_) = self.handle_client_data(fd) {
-                            self.remove_connection(fd)?;
-                            continue;
-                        }
-                        
-                        // Check if we have a complete request to respond to
-                        if let Some(connection) = self.connections.get_mut(&fd) {
-                            if let Some(request) = &connection.request {
-                                // Route the request
-                                let response = self.router.handle(request);
-                                
-                                // Send response
-                                if let Err(_) = connection.stream.write_all(&response.to_bytes()) {
-                                    self.remove_connection(fd)?;
-                                } else {
-                                    if let Err(_) = connection.stream.flush() {
-                                        self.remove_connection(fd)?;
-                                    } else {
-                                        // Reset for potential next request
-                                        connection.request = None;
-                                        connection.buffer.clear();
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        let idle = SessionConfig::default().idle_timeout_secs;
+        stored_session(&mut sessions, Sessions::now() - idle);
+        assert!(sessions.load(&session_request(&keys, "abc", Some(&keys))).is_new());
+        assert!(sessions.store.load("abc").unwrap().is_none());
+    }
+
+    /// Register a route that answers with its own pattern and the
+    /// parameters it captured
+    fn labelled<'a>(router: &'a mut Router, method: &str, pattern: &str) -> &'a mut Route {
+        let label = pattern.to_string();
+        router.register(method, pattern, move |req| {
+            let mut params: Vec<String> = req.params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+            params.sort();
+            HttpResponse::new(200, "OK", format!("{} {}", label, params.join(" ")).trim_end())
+        })
+    }
+
+    fn route(router: &Router, method: &str, target: &str) -> String {
+        let response = router.handle(&mut request(method, target));
+        match response.status {
+            200 => body_text(&response),
+            status => status.to_string(),
         }
     }
 
-    fn accept_connection(&mut self) -> io::Result<()> {
-        match self.listener.accept() {
-            Ok((stream, addr)) => {
-                println!("New connection from: {}", addr);
-                stream.set_nonblocking(true)?;
-                
-                let fd = stream.as_raw_fd();
-                let mut event = epoll_event {
-                    events: EPOLLIN as u32,
-                    u64: fd as u64,
-                };
+    #[test]
+    fn literals_beat_typed_params_beat_params_beat_wildcards() {
+        let mut router = Router::new();
+        for pattern in ["/files/new", "/files/:id{u64}", "/files/:name", "/files/*rest", "/files/:name/raw"] {
+            labelled(&mut router, "GET", pattern);
+        }
+        assert_eq!(route(&router, "GET", "/files/new"), "/files/new");
+        assert_eq!(route(&router, "GET", "/files/42"), "/files/:id{u64} id=42");
+        assert_eq!(route(&router, "GET", "/files/report"), "/files/:name name=report");
+        assert_eq!(route(&router, "GET", "/files/report/raw"), "/files/:name/raw name=report");
+        assert_eq!(route(&router, "GET", "/files/a/b/c"), "/files/*rest rest=a/b/c");
+        // An empty segment is not a parameter value
+        assert_eq!(route(&router, "GET", "/files/"), "/files/*rest rest=");
+        assert_eq!(route(&router, "GET", "/other"), "404");
+    }
 
-                unsafe {
-                    if epoll_ctl(self.epoll_fd, EPOLL_CTL_ADD, fd, &mut event as *mut epoll_event) < 0 {
-                        return Err(io::Error::last_os_error());
-                    }
-                }
+    #[test]
+    fn lookup_backtracks_out_of_dead_ends() {
+        let mut router = Router::new();
+        labelled(&mut router, "GET", "/a/b/d");
+        labelled(&mut router, "GET", "/a/:x/c");
+        labelled(&mut router, "GET", "/a/:x/:y/*rest");
+        assert_eq!(route(&router, "GET", "/a/b/d"), "/a/b/d");
+        assert_eq!(route(&router, "GET", "/a/b/c"), "/a/:x/c x=b");
+        assert_eq!(route(&router, "GET", "/a/b/e/f"), "/a/:x/:y/*rest rest=f x=b y=e");
+    }
 
-                self.connections.insert(fd, Connection {
-                    stream,
-                    buffer: Vec::with_capacity(4096),
-                    request: None,
-                });
-            }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => eprintln!("Error accepting connection: {}", e),
+    #[test]
+    fn typed_params_reject_other_values() {
+        let mut router = Router::new();
+        labelled(&mut router, "GET", "/u/:id{u64}");
+        labelled(&mut router, "GET", "/i/:id{i64}");
+        labelled(&mut router, "GET", "/id/:id{uuid}");
+        assert_eq!(route(&router, "GET", "/u/18446744073709551615"), "/u/:id{u64} id=18446744073709551615");
+        for rejected in ["/u/abc", "/u/-1", "/u/18446744073709551616", "/u/1.5", "/i/1e3", "/id/not-a-uuid",
+                         "/id/123e4567-e89b-12d3-a456-42661417400g", "/id/123e4567e89b12d3a456426614174000"] {
+            assert_eq!(route(&router, "GET", rejected), "404", "{}", rejected);
         }
-        Ok(())
+        assert_eq!(route(&router, "GET", "/i/-7"), "/i/:id{i64} id=-7");
+        assert_eq!(
+            route(&router, "GET", "/id/123E4567-e89b-12d3-a456-426614174000"),
+            "/id/:id{uuid} id=123E4567-e89b-12d3-a456-426614174000"
+        );
     }
 
-    fn remove_connection(&mut self, fd: RawFd) -> io::Result<()> {
-        unsafe {
-            epoll_ctl(self.epoll_fd, EPOLL_CTL_DEL, fd, std::ptr::null_mut());
+    #[test]
+    fn captures_are_percent_decoded() {
+        let mut router = Router::new();
+        labelled(&mut router, "GET", "/users/:name");
+        labelled(&mut router, "GET", "/static/*path");
+        assert_eq!(route(&router, "GET", "/users/J%C3%BCrgen%20M"), "/users/:name name=Jürgen M");
+        // An encoded slash stays inside the one segment
+        assert_eq!(route(&router, "GET", "/users/a%2Fb"), "/users/:name name=a/b");
+        assert_eq!(route(&router, "GET", "/static/css/a%20b.css"), "/static/*path path=css/a b.css");
+        assert_eq!(route(&router, "GET", "/users/%ZZ"), "404");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown parameter type")]
+    fn unknown_param_types_panic() {
+        Router::new().register("GET", "/a/:id{float}", |_| HttpResponse::new(200, "OK", ""));
+    }
+
+    #[test]
+    #[should_panic(expected = "a wildcard must be the last segment")]
+    fn wildcards_must_come_last() {
+        Router::new().register("GET", "/a/*rest/b", |_| HttpResponse::new(200, "OK", ""));
+    }
+
+    #[test]
+    fn shared_literal_runs_split_where_routes_branch() {
+        let mut router = Router::new();
+        labelled(&mut router, "GET", "/api/v1/internal/status");
+        assert_eq!(router.trees["GET"].statics["api"].label, ["api", "v1", "internal", "status"]);
+        labelled(&mut router, "GET", "/api/v1/users");
+        labelled(&mut router, "GET", "/api/v2");
+        assert_eq!(router.trees["GET"].statics["api"].label, ["api"]);
+        for path in ["/api/v1/internal/status", "/api/v1/users", "/api/v2"] {
+            assert_eq!(route(&router, "GET", path), path);
+        }
+        for path in ["/api", "/api/v1", "/api/v1/internal", "/api/v3", "/api/v1/users/x"] {
+            assert_eq!(route(&router, "GET", path), "404", "{}", path);
         }
-        self.connections.remove(&fd);
-        Ok(())
     }
 
-    fn handle_client_data(&mut self, fd: RawFd) -> io::Result<()> {
-        if let Some(connection) = self.connections.get_mut(&fd) {
-            let mut buffer = [0; 4096];
-            match connection.stream.read(&mut buffer) {
-                Ok(0) => {
-                    // Connection closed by client
-                    println!("Connection closed by client");
-                    return Err(io::Error::new(io::ErrorKind::Other, "Connection closed"));
-                }
-                Ok(n) => {
-                    // Append new data to the connection buffer
-                    connection.buffer.extend_from_slice(&buffer[..n]);
-                    
-                    // Try to parse the HTTP request
-                    if connection.request.is_none() {
-                        if let Some(request) = HttpParser::parse(&connection.buffer) {
-                            connection.request = Some(request.clone());
-                            println!("Parsed HTTP Request:");
-                            println!("  Method: {}", request.method);
-                            println!("  Path: {}", request.path);
-                            println!("  Version: {}", request.version);
-                            println!("  Headers:");
-                            for (key, value) in &request.headers {
-                                println!("    {}: {}", key, value);
-                            }
-                            return Ok(());
-                        }
-                    }
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    return Ok(());
-                }
-                Err(e) => {
-                    eprintln!("Error reading from client: {}", e);
-                    return Err(e);
-                }
+    #[test]
+    fn conflicting_routes_are_refused() {
+        let mut router = Router::new();
+        let respond = |_: &HttpRequest| HttpResponse::new(200, "OK", "");
+        router.register("GET", "/users/:id", respond);
+        router.register("GET", "/files/*rest", respond);
+        let mut add = |pattern: &str| {
+            let segments = Segment::parse_pattern(pattern);
+            router.add("GET", pattern, &segments, Box::new(respond))
+        };
+        assert_eq!(add("/users/:id").unwrap_err(), "route GET /users/:id conflicts with /users/:id");
+        // Same position, different name: the two could never both be read
+        assert_eq!(add("/users/:name/posts").unwrap_err(), "route GET /users/:name/posts conflicts with /users/:id");
+        assert_eq!(add("/files/*path").unwrap_err(), "route GET /files/*path conflicts with /files/*rest");
+        // A different constraint or method is a separate route
+        assert!(add("/users/:id{u64}").is_ok());
+        assert!(router.register_prefix("POST", "/files", respond).is_ok());
+        assert!(router.register_prefix("GET", "/files/", respond).is_err());
+    }
+
+    #[test]
+    fn prefixes_match_on_segment_boundaries() {
+        let mut router = Router::new();
+        router
+            .register_prefix("GET", "/static/", |req| HttpResponse::new(200, "OK", req.param("path").unwrap_or("-")))
+            .unwrap();
+        assert_eq!(route(&router, "GET", "/static"), "");
+        assert_eq!(route(&router, "GET", "/static/"), "");
+        assert_eq!(route(&router, "GET", "/static/css/site.css"), "css/site.css");
+        assert_eq!(route(&router, "GET", "/statics"), "404");
+    }
+
+    /// Time route lookups as the route count grows. Each resource adds a
+    /// literal, a typed parameter and a wildcard route. Run with
+    /// `cargo test --release bench_router -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_router() {
+        const LOOKUPS: u32 = 200_000;
+        println!("{:>8} {:>10} {:>10} {:>10} {:>10}   (ns per lookup)", "routes", "static", "param", "wildcard", "miss");
+        for resources in [10, 100, 1_000, 10_000] {
+            let mut router = Router::new();
+            for i in 0..resources {
+                let respond = |_: &HttpRequest| HttpResponse::new(200, "OK", "");
+                router.register("GET", &format!("/api/v1/resource{}", i), respond);
+                router.register("GET", &format!("/api/v1/resource{}/:id{{u64}}", i), respond);
+                router.register("GET", &format!("/api/v1/resource{}/:id/files/*rest", i), respond);
             }
+            // Ask for the most recently registered resource, the worst case for a scan
+            let last = resources - 1;
+            let paths = [
+                format!("/api/v1/resource{}", last),
+                format!("/api/v1/resource{}/12345", last),
+                format!("/api/v1/resource{}/abc/files/a/b/c.txt", last),
+                "/api/v2/missing".to_string(),
+            ];
+            let timings: Vec<f64> = paths
+                .iter()
+                .map(|path| {
+                    let mut params = Vec::new();
+                    let start = Instant::now();
+                    for _ in 0..LOOKUPS {
+                        params.clear();
+                        std::hint::black_box(router.find("GET", std::hint::black_box(path), &mut params));
+                    }
+                    start.elapsed().as_nanos() as f64 / LOOKUPS as f64
+                })
+                .collect();
+            println!(
+                "{:>8} {:>10.0} {:>10.0} {:>10.0} {:>10.0}",
+                router.routes.len(),
+                timings[0],
+                timings[1],
+                timings[2],
+                timings[3]
+            );
         }
-        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn reload_config(&mut self, config_path: &str) -> io::Result<()> {
-        let config_content = fs::read_to_string(config_path)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to read config: {}", e)))?;
-        
-        self.config = toml::from_str(&config_content)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to parse config: {}", e)))?;
-        
-        println!("Configuration reloaded successfully");
-        Ok(())
+    fn method_router() -> Router {
+        let mut router = Router::new();
+        labelled(&mut router, "GET", "/items");
+        labelled(&mut router, "POST", "/items");
+        labelled(&mut router, "DELETE", "/items/:id");
+        labelled(&mut router, "HEAD", "/reports");
+        labelled(&mut router, "GET", "/reports");
+        router
     }
-}
 
-impl Config {
-    #[allow(dead_code)]
-    fn validate(&self) -> Result<(), ServerError> {
-        if self.server.port == 0 {
-            return Err(ServerError::InvalidConfig("Port cannot be 0".into()));
+    #[test]
+    fn wrong_methods_get_405_with_allow() {
+        let router = method_router();
+        for (method, path, allow) in [
+            ("PUT", "/items", "GET, HEAD, OPTIONS, POST"),
+            ("POST", "/items/7", "DELETE, OPTIONS"),
+            ("DELETE", "/reports", "GET, HEAD, OPTIONS"),
+        ] {
+            let response = router.handle(&mut request(method, path));
+            assert_eq!(response.status, 405, "{} {}", method, path);
+            assert_eq!(response.headers.get("Allow"), Some(allow), "{} {}", method, path);
         }
-        if self.server.max_events == 0 {
-            return Err(ServerError::InvalidConfig("max_events cannot be 0".into()));
+        // Paths no method is routed for stay 404
+        let response = router.handle(&mut request("PUT", "/nothing"));
+        assert_eq!(response.status, 404);
+        assert!(response.headers.get("Allow").is_none());
+    }
+
+    #[test]
+    fn options_lists_the_allowed_methods() {
+        let router = method_router();
+        let response = router.handle(&mut request("OPTIONS", "/items"));
+        assert_eq!(response.status, 204);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS, POST"));
+        assert!(response.headers.get("Content-Length").is_none());
+        assert_eq!(body_text(&response), "");
+
+        let response = router.handle(&mut request("OPTIONS", "*"));
+        assert_eq!(response.headers.get("Allow"), Some("DELETE, GET, HEAD, OPTIONS, POST"));
+        assert_eq!(router.handle(&mut request("OPTIONS", "/nothing")).status, 404);
+        // A route registered for OPTIONS itself takes over
+        let mut router = method_router();
+        labelled(&mut router, "OPTIONS", "/items");
+        assert_eq!(route(&router, "OPTIONS", "/items"), "/items");
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = method_router();
+        assert_eq!(route(&router, "HEAD", "/items"), "/items");
+        // An explicit HEAD route wins; either way the two answer the same path
+        let mut router = Router::new();
+        router.register("GET", "/reports", |_| HttpResponse::new(200, "OK", "get"));
+        router.register("HEAD", "/reports", |_| HttpResponse::new(200, "OK", "head"));
+        assert_eq!(route(&router, "HEAD", "/reports"), "head");
+        assert_eq!(route(&router, "HEAD", "/missing"), "404");
+    }
+
+    #[test]
+    fn head_responses_keep_their_headers_but_no_body() {
+        let mut server = test_server("head", "");
+        let (mut client, fd) = connect(&mut server);
+        assert!(send(&mut server, fd, &mut client, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        let get = received(&mut client);
+        assert!(send(&mut server, fd, &mut client, b"HEAD /health HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        let head = received(&mut client);
+        let (get_head, get_body) = get.split_once("\r\n\r\n").unwrap();
+        assert!(!get_body.is_empty());
+        assert!(head.ends_with("\r\n\r\n"));
+        let content_length = |head: &str| head.lines().find(|line| line.starts_with("Content-Length:")).map(str::to_string);
+        assert_eq!(content_length(&head), content_length(get_head));
+        assert!(content_length(&head).is_some());
+    }
+
+    /// Records when its hooks run; answers in `before` if `stop` is set
+    struct Trace {
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+        stop: bool,
+    }
+
+    impl Middleware for Trace {
+        fn before(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+            self.log.borrow_mut().push(format!("{} before", self.name));
+            self.stop.then(|| HttpResponse::new(403, "Forbidden", ""))
         }
-        if self.server.timeout_ms < 0 {
-            return Err(ServerError::InvalidConfig("timeout_ms cannot be negative".into()));
+
+        fn after(&self, _request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+            self.log.borrow_mut().push(format!("{} after", self.name));
+            response
         }
-        Ok(())
     }
-}
 
-fn main() -> io::Result<()> {
-    let mut server = Server::new("config.toml")?;
-    server.run()
-}
\ No newline at end of file
+    /// A router wrapped in `outer` then `inner`, with a group wrapped in
+    /// `group` holding a route wrapped in `route`
+    fn traced_router(log: &Rc<RefCell<Vec<String>>>, stop: &str) -> Router {
+        let trace = |name: &'static str| Trace { name, log: Rc::clone(log), stop: name == stop };
+        let mut router = Router::new();
+        router.wrap(trace("outer")).wrap(trace("inner"));
+        let handler_log = Rc::clone(log);
+        router
+            .group("/g")
+            .wrap(trace("group"))
+            .register("GET", "/route", move |_| {
+                handler_log.borrow_mut().push("handler".to_string());
+                HttpResponse::new(200, "OK", "")
+            })
+            .wrap(trace("route"));
+        router
+    }
+
+    #[test]
+    fn middleware_runs_outermost_first_in_and_last_out() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let router = traced_router(&log, "");
+        assert_eq!(router.handle(&mut request("GET", "/g/route")).status, 200);
+        assert_eq!(
+            *log.borrow(),
+            [
+                "outer before", "inner before", "group before", "route before", "handler",
+                "route after", "group after", "inner after", "outer after",
+            ]
+        );
+
+        // Requests no route matches still pass the router's layers, but not the group's
+        log.borrow_mut().clear();
+        assert_eq!(router.handle(&mut request("GET", "/g/other")).status, 404);
+        assert_eq!(*log.borrow(), ["outer before", "inner before", "inner after", "outer after"]);
+    }
+
+    #[test]
+    fn middleware_answering_early_skips_what_is_inside_it() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let router = traced_router(&log, "group");
+        assert_eq!(router.handle(&mut request("GET", "/g/route")).status, 403);
+        assert_eq!(*log.borrow(), ["outer before", "inner before", "group before", "inner after", "outer after"]);
+    }
+
+    fn cors_router() -> Router {
+        let mut router = Router::new();
+        let mut api = router
+            .group("/api")
+            .wrap(Cors::new(&["https://app.test"]).methods(&["GET", "PUT"]).headers(&["Content-Type", "X-Token"]));
+        api.register("GET", "/items", |_| HttpResponse::new(200, "OK", "items"));
+        api.register("PUT", "/items", |_| HttpResponse::new(200, "OK", "stored"));
+        router
+    }
+
+    fn preflight(router: &Router, origin: &str, method: &str, headers: Option<&str>) -> HttpResponse {
+        let mut req = request("OPTIONS", "/api/items");
+        req.headers.insert("Origin", origin);
+        req.headers.insert("Access-Control-Request-Method", method);
+        if let Some(headers) = headers {
+            req.headers.insert("Access-Control-Request-Headers", headers);
+        }
+        router.handle(&mut req)
+    }
+
+    #[test]
+    fn cors_preflights_grant_only_the_configured_lists() {
+        let router = cors_router();
+        let response = preflight(&router, "https://app.test", "PUT", Some("x-token, content-type"));
+        assert_eq!(response.status, 204);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("https://app.test"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some("GET, PUT"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Headers"), Some("Content-Type, X-Token"));
+        assert_eq!(response.headers.get("Access-Control-Max-Age"), Some("600"));
+
+        // Anything off the lists is not echoed back
+        for (method, headers) in [("DELETE", None), ("put", None), ("PUT", Some("X-Token, X-Admin"))] {
+            let response = preflight(&router, "https://app.test", method, headers);
+            assert_eq!(response.status, 204);
+            for name in ["Access-Control-Allow-Origin", "Access-Control-Allow-Methods", "Access-Control-Allow-Headers"] {
+                assert!(response.headers.get(name).is_none(), "{} {:?}: {}", method, headers, name);
+            }
+        }
+
+        // Other origins get the router's plain OPTIONS answer
+        let response = preflight(&router, "https://evil.test", "PUT", None);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS, PUT"));
+        assert!(response.headers.get("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn cors_marks_responses_for_allowed_origins() {
+        let router = cors_router();
+        let mut req = request("GET", "/api/items");
+        req.headers.insert("Origin", "https://app.test");
+        let response = router.handle(&mut req);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("https://app.test"));
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+
+        req.headers.insert("Origin", "https://evil.test");
+        assert!(router.handle(&mut req).headers.get("Access-Control-Allow-Origin").is_none());
+
+        // The default lists cover only the safelisted methods and headers
+        let cors = Cors::new(&["*"]);
+        assert!(cors.allows("POST", None));
+        assert!(!cors.allows("DELETE", None));
+        assert!(!cors.allows("GET", Some("Authorization")));
+    }
+}